@@ -0,0 +1,60 @@
+use sentry_tunnel::envelope::SentryEnvelope;
+
+use std::hint::black_box;
+use std::time::Instant;
+
+/**
+ * A replay envelope whose recording has this size, like the ones sent by the replay integration
+ */
+fn replay_envelope(recording_size: usize) -> Vec<u8> {
+    let mut envelope = format!(
+        "{}\n{}\n{}\n{}\n",
+        r#"{"event_id":"9ec79c33ec9942ab8353589fcb2e04dc","dsn":"https://public@sentry.example.com/5","sdk":{"name":"sentry.javascript.browser","version":"7.99.0"}}"#,
+        r#"{"type":"replay_event"}"#,
+        r#"{"type":"replay_event","replay_id":"9ec79c33ec9942ab8353589fcb2e04dc","segment_id":0}"#,
+        format_args!(r#"{{"type":"replay_recording","length":{}}}"#, recording_size),
+    )
+    .into_bytes();
+    envelope.extend((0..recording_size).map(|i| b'a' + (i % 26) as u8));
+    envelope.push(b'\n');
+    envelope
+}
+
+/**
+ * Run the function enough times to take about a second, and print the time per iteration
+ */
+fn bench<F: FnMut() -> usize>(name: &str, bytes: usize, mut f: F) {
+    let start = Instant::now();
+    let mut iterations = 0u64;
+    while start.elapsed().as_millis() < 1000 {
+        black_box(f());
+        iterations += 1;
+    }
+    let per_iteration = start.elapsed() / iterations as u32;
+    println!(
+        "{:<40} {:>12.2?}/iter {:>10.1} MB/s",
+        name,
+        per_iteration,
+        bytes as f64 / per_iteration.as_secs_f64() / 1_000_000.0
+    );
+}
+
+fn main() {
+    for size in [10_000, 1_000_000, 10_000_000] {
+        let mut body = replay_envelope(size);
+        let len = body.len();
+        // The body is moved in and out of the envelope, so that it is never copied
+        bench(&format!("parse header ({} bytes)", len), len, || {
+            let envelope = SentryEnvelope::try_new_from_body(std::mem::take(&mut body)).unwrap();
+            body = envelope.raw_body;
+            body.len()
+        });
+        let envelope = SentryEnvelope::try_new_from_body(body).unwrap();
+        bench(&format!("event id ({} bytes)", len), len, || {
+            envelope.event_id().map_or(0, |event_id| event_id.len())
+        });
+        bench(&format!("split items ({} bytes)", len), len, || {
+            envelope.items().unwrap().len()
+        });
+    }
+}
@@ -0,0 +1,121 @@
+use crate::redact;
+use futures_util::future::FutureExt;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::body::HttpBody;
+use gotham::hyper::{header, HeaderMap, Method, StatusCode, Uri};
+use gotham::middleware::Middleware;
+use gotham::state::{client_addr, FromState, State};
+use gotham_derive::NewMiddleware;
+
+use std::cell::Cell;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    // Time spent waiting for sentry by the request being handled
+    static UPSTREAM: Cell<Duration>;
+}
+
+/**
+ * Run a request to sentry, counting its duration in the upstream latency of the access log line
+ * of the request. Outside of a logged request, the future is only run.
+ */
+pub async fn upstream<F: Future>(future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    let elapsed = started.elapsed();
+    let _ = UPSTREAM.try_with(|upstream| upstream.set(upstream.get() + elapsed));
+    output
+}
+
+/**
+ * Writes a line to stdout for every request, whatever the log level : client address, method,
+ * path, status, request and response body sizes, total latency and the part of it spent waiting
+ * for sentry
+ */
+#[derive(Clone, Debug, NewMiddleware)]
+pub struct AccessLog {
+    enabled: bool,
+    // Header holding the client address, the peer address is logged without it
+    client_ip_header: Option<String>,
+}
+
+impl AccessLog {
+    pub fn new(enabled: bool, client_ip_header: Option<String>) -> AccessLog {
+        AccessLog {
+            enabled,
+            client_ip_header,
+        }
+    }
+
+    fn client_ip(&self, state: &State) -> String {
+        let forwarded = self.client_ip_header.as_ref().and_then(|client_ip_header| {
+            HeaderMap::borrow_from(state)
+                .get(client_ip_header.as_str())?
+                .to_str()
+                .ok()?
+                .split(',')
+                .next()
+                .map(|ip| ip.trim().to_string())
+        });
+        forwarded
+            .or_else(|| client_addr(state).map(|addr| addr.ip().to_string()))
+            .unwrap_or_else(|| "-".to_string())
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn size(size: Option<u64>) -> String {
+    size.map(|size| size.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+impl Middleware for AccessLog {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        if !self.enabled {
+            return chain(state);
+        }
+        let started = Instant::now();
+        let request = format!(
+            "{} \"{} {}\" in={}",
+            self.client_ip(&state),
+            Method::borrow_from(&state),
+            Uri::borrow_from(&state).path(),
+            size(
+                HeaderMap::borrow_from(&state)
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|length| length.to_str().ok()?.parse().ok())
+            )
+        );
+        UPSTREAM
+            .scope(Cell::new(Duration::ZERO), async move {
+                let handled = chain(state).await;
+                let (status, response_size) = match &handled {
+                    Ok((_, response)) => (response.status(), response.body().size_hint().exact()),
+                    Err((_, e)) => (e.status(), None),
+                };
+                log(&request, status, response_size, started.elapsed());
+                handled
+            })
+            .boxed()
+    }
+}
+
+fn log(request: &str, status: StatusCode, response_size: Option<u64>, total: Duration) {
+    let line = format!(
+        "{} {} out={} total={} upstream={}",
+        request,
+        status.as_u16(),
+        size(response_size),
+        millis(total),
+        millis(UPSTREAM.with(Cell::get))
+    );
+    let _ = writeln!(std::io::stdout().lock(), "{}", redact::redact(&line));
+}
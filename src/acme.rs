@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/**
+ * Url path prefix of the HTTP-01 challenges, followed by their token
+ */
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/**
+ * Directory of the Let's Encrypt production environment
+ */
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/**
+ * Key authorizations of the pending HTTP-01 challenges, by token
+ */
+#[derive(Debug, Default)]
+pub struct Challenges(RwLock<HashMap<String, String>>);
+
+impl Challenges {
+    pub fn insert(&self, token: &str, key_authorization: &str) {
+        self.0
+            .write()
+            .unwrap()
+            .insert(token.to_string(), key_authorization.to_string());
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.0.write().unwrap().remove(token);
+    }
+
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.0.read().unwrap().get(token).cloned()
+    }
+}
+
+#[cfg(feature = "acme")]
+pub use self::client::*;
+
+#[cfg(feature = "acme")]
+mod client {
+    use crate::config::Config;
+    use anyhow::{anyhow, Error as AError};
+    use instant_acme::{
+        Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+        NewOrder, OrderStatus,
+    };
+    use log::*;
+
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    // Certificates are renewed when they expire in less than this
+    const RENEWAL_MARGIN: Duration = Duration::from_secs(30 * 24 * 3600);
+    const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+    const RETRY_DELAY: Duration = Duration::from_secs(600);
+    const ACCOUNT_FILE: &str = "account.json";
+
+    /**
+     * The account saved in the ACME directory, created when there is none yet
+     */
+    async fn account(config: &Config) -> Result<Account, AError> {
+        let path = Path::new(&config.acme_dir).join(ACCOUNT_FILE);
+        if let Ok(saved) = fs::read(&path) {
+            let credentials: AccountCredentials = serde_json::from_slice(&saved)?;
+            return Ok(Account::from_credentials(credentials).await?);
+        }
+        let contact: Vec<String> = config
+            .acme_email
+            .iter()
+            .map(|email| format!("mailto:{}", email))
+            .collect();
+        let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+        let (account, credentials) = Account::create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            &config.acme_directory,
+            None,
+        )
+        .await?;
+        fs::create_dir_all(&config.acme_dir)?;
+        fs::write(&path, serde_json::to_vec(&credentials)?)?;
+        info!("Created an ACME account on {}", config.acme_directory);
+        Ok(account)
+    }
+
+    /**
+     * Order a certificate for the domains, answering the HTTP-01 challenges through the tunnel
+     * listener. Returns the PEM encoded certificate chain and private key.
+     */
+    async fn order_certificate(config: &Config) -> Result<(String, String), AError> {
+        let account = account(config).await?;
+        let identifiers: Vec<Identifier> = config
+            .acme_domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await?;
+        let mut tokens = vec![];
+        for authorization in order.authorizations().await? {
+            if authorization.status == AuthorizationStatus::Valid {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.r#type == ChallengeType::Http01)
+                .ok_or_else(|| {
+                    anyhow!("No HTTP-01 challenge offered for {:?}", authorization.identifier)
+                })?;
+            let key_authorization = order.key_authorization(challenge);
+            config
+                .acme_challenges
+                .insert(&challenge.token, key_authorization.as_str());
+            tokens.push(challenge.token.clone());
+            order.set_challenge_ready(&challenge.url).await?;
+        }
+        let mut status = order.state().status;
+        let mut delay = Duration::from_millis(250);
+        while status == OrderStatus::Pending && delay < Duration::from_secs(60) {
+            tokio::time::sleep(delay).await;
+            status = order.refresh().await?.status;
+            delay *= 2;
+        }
+        for token in tokens {
+            config.acme_challenges.remove(&token);
+        }
+        if status != OrderStatus::Ready {
+            return Err(anyhow!("The ACME order is {:?} instead of ready", status));
+        }
+        let mut params = rcgen::CertificateParams::new(config.acme_domains.clone());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let key = rcgen::Certificate::from_params(params)?;
+        order.finalize(&key.serialize_request_der()?).await?;
+        loop {
+            match order.certificate().await? {
+                Some(chain) => return Ok((chain, key.serialize_private_key_pem())),
+                None => tokio::time::sleep(Duration::from_secs(1)).await,
+            }
+        }
+    }
+
+    /**
+     * Whether the certificate is missing, unreadable, or expires within the renewal margin
+     */
+    fn needs_renewal(path: &str) -> bool {
+        let pem = match fs::read(path) {
+            Ok(pem) => pem,
+            Err(_) => return true,
+        };
+        let expires_in = x509_parser::pem::parse_x509_pem(&pem)
+            .ok()
+            .and_then(|(_, pem)| {
+                let cert = pem.parse_x509().ok()?;
+                cert.validity().time_to_expiration()
+            });
+        match expires_in {
+            Some(expires_in) => expires_in.whole_seconds() < RENEWAL_MARGIN.as_secs() as i64,
+            None => true,
+        }
+    }
+
+    /**
+     * Replace the file at once, so that the certificate watcher never reads half of it
+     */
+    fn write_atomically(path: &str, content: &str) -> Result<(), AError> {
+        let temporary = format!("{}.tmp", path);
+        fs::write(&temporary, content)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /**
+     * Obtain a new certificate when the current one is missing or about to expire. The key is
+     * written before the certificate, which triggers the reload of the TLS listeners.
+     */
+    pub async fn renew_if_needed(config: &Config) -> Result<(), AError> {
+        let (cert_path, key_path) = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            _ => return Err(anyhow!("No path to write the ACME certificate to")),
+        };
+        if !needs_renewal(cert_path) {
+            return Ok(());
+        }
+        info!("Requesting a TLS certificate for {:?}", config.acme_domains);
+        let (chain, key) = order_certificate(config).await?;
+        write_atomically(key_path, &key)?;
+        write_atomically(cert_path, &chain)?;
+        info!("Obtained a TLS certificate for {:?}", config.acme_domains);
+        Ok(())
+    }
+
+    /**
+     * Retry until a valid certificate is available
+     */
+    pub async fn wait_for_certificate(config: &Config) {
+        while let Err(e) = renew_if_needed(config).await {
+            error!("Could not obtain a TLS certificate from ACME : {}", e);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    /**
+     * Check the certificate twice a day, and renew it before it expires
+     */
+    pub async fn keep_renewed(config: Config) {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            wait_for_certificate(&config).await;
+        }
+    }
+}
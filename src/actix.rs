@@ -0,0 +1,64 @@
+use crate::config::Config;
+use crate::server::{dispatch, router, MAX_STREAMED_CONTENT_SIZE};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes, Data, PayloadConfig};
+use actix_web::{HttpRequest, HttpResponse, Scope};
+use gotham::hyper::{body, Body, Request};
+use gotham::router::Router;
+use log::*;
+
+use std::net::SocketAddr;
+
+/**
+ * The routes of the tunnel as an actix-web scope mounted at `path`,
+ * `App::new().service(sentry_tunnel::actix::scope("/sentry", config))` for instance
+ */
+pub fn scope(path: &str, config: Config) -> Scope {
+    let tunnel_router = router(&config.tunnel_path.clone(), config);
+    web::scope(path)
+        .app_data(Data::new(tunnel_router))
+        .app_data(PayloadConfig::new(MAX_STREAMED_CONTENT_SIZE as usize))
+        .default_service(web::to(handle))
+}
+
+/**
+ * Run the request through the tunnel router. actix-web payloads can not be moved to other
+ * threads, so bodies are read before being handed to the tunnel.
+ */
+async fn handle(router: Data<Router>, request: HttpRequest, payload: Bytes) -> HttpResponse {
+    let path = request.match_info().unprocessed();
+    let uri = match request.query_string() {
+        "" => format!("/{}", path.trim_start_matches('/')),
+        query => format!("/{}?{}", path.trim_start_matches('/'), query),
+    };
+    let mut builder = Request::builder().method(request.method().as_str()).uri(uri);
+    for (name, value) in request.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    let tunnel_request = match builder.body(Body::from(payload)) {
+        Ok(tunnel_request) => tunnel_request,
+        Err(e) => {
+            warn!("Could not convert an actix-web request : {}", e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    let client_addr = request
+        .peer_addr()
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let (parts, response_body) = dispatch(&router, tunnel_request, client_addr, None)
+        .await
+        .into_parts();
+    let status =
+        StatusCode::from_u16(parts.status.as_u16()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = HttpResponse::build(status);
+    for (name, value) in &parts.headers {
+        response.append_header((name.as_str(), value.as_bytes()));
+    }
+    match body::to_bytes(response_body).await {
+        Ok(bytes) => response.body(bytes),
+        Err(e) => {
+            error!("Failed to read the response body : {}", e);
+            HttpResponse::BadGateway().finish()
+        }
+    }
+}
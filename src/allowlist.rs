@@ -0,0 +1,160 @@
+use crate::envelope::{Host, ProjectId};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/**
+ * The allowlist could not be changed
+ */
+#[derive(Debug)]
+pub enum AllowlistError {
+    InvalidHost(String),
+    Persist(io::Error),
+}
+
+impl Error for AllowlistError {}
+
+impl Display for AllowlistError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllowlistError::InvalidHost(e) => f.write_str(e),
+            AllowlistError::Persist(e) => {
+                f.write_fmt(format_args!("Could not persist the allowlist : {}", e))
+            }
+        }
+    }
+}
+
+/**
+ * Projects and hosts added or removed at runtime, on top of the configured ones. Hosts are kept
+ * as their url.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Changes {
+    added_projects: BTreeSet<u64>,
+    removed_projects: BTreeSet<u64>,
+    added_hosts: BTreeSet<String>,
+    removed_hosts: BTreeSet<String>,
+}
+
+/**
+ * Changes made through the admin endpoints, persisted to a file when a path is configured. They
+ * are kept when the configuration is reloaded.
+ */
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    changes: RwLock<Changes>,
+    path: Option<String>,
+}
+
+impl Allowlist {
+    /**
+     * Start with the changes persisted at this path, if any
+     */
+    pub fn load(path: Option<String>) -> Allowlist {
+        let changes = path
+            .as_ref()
+            .and_then(|path| match fs::read(path) {
+                Ok(content) => serde_json::from_slice(&content)
+                    .map_err(|e| error!("Ignoring the invalid allowlist of {} : {}", path, e))
+                    .ok(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    error!("Could not read the allowlist of {} : {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Allowlist {
+            changes: RwLock::new(changes),
+            path,
+        }
+    }
+
+    /**
+     * Whether the project is accepted, given whether the configuration accepts it
+     */
+    pub fn project_is_allowed(&self, id: u64, configured: bool) -> bool {
+        let changes = self.changes.read().unwrap();
+        changes.added_projects.contains(&id)
+            || (configured && !changes.removed_projects.contains(&id))
+    }
+
+    /**
+     * The configured hosts without the removed ones, followed by the added ones
+     */
+    pub fn hosts(&self, configured: &[Host]) -> Vec<Host> {
+        let changes = self.changes.read().unwrap();
+        let mut hosts: Vec<Host> = configured
+            .iter()
+            .filter(|host| !changes.removed_hosts.contains(&host.to_string()))
+            .cloned()
+            .collect();
+        for host in changes.added_hosts.iter() {
+            match Host::from_str(host) {
+                Ok(host) if !hosts.contains(&host) => hosts.push(host),
+                _ => {}
+            }
+        }
+        hosts
+    }
+
+    pub fn add_project(&self, id: ProjectId) -> Result<(), AllowlistError> {
+        self.change(|changes| {
+            changes.removed_projects.remove(&id.0);
+            changes.added_projects.insert(id.0);
+        })
+    }
+
+    pub fn remove_project(&self, id: ProjectId) -> Result<(), AllowlistError> {
+        self.change(|changes| {
+            changes.added_projects.remove(&id.0);
+            changes.removed_projects.insert(id.0);
+        })
+    }
+
+    pub fn add_host(&self, host: &str) -> Result<(), AllowlistError> {
+        let host = Host::from_str(host).map_err(AllowlistError::InvalidHost)?.to_string();
+        self.change(|changes| {
+            changes.removed_hosts.remove(&host);
+            changes.added_hosts.insert(host);
+        })
+    }
+
+    pub fn remove_host(&self, host: &str) -> Result<(), AllowlistError> {
+        let host = Host::from_str(host).map_err(AllowlistError::InvalidHost)?.to_string();
+        self.change(|changes| {
+            changes.added_hosts.remove(&host);
+            changes.removed_hosts.insert(host);
+        })
+    }
+
+    fn change<F: FnOnce(&mut Changes)>(&self, f: F) -> Result<(), AllowlistError> {
+        let mut changes = self.changes.write().unwrap();
+        f(&mut changes);
+        info!("Allowlist changed : {:?}", *changes);
+        if let Some(path) = &self.path {
+            let content = serde_json::to_vec(&*changes).map_err(io::Error::from);
+            content
+                .and_then(|content| fs::write(path, content))
+                .map_err(AllowlistError::Persist)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * The changes, as persisted
+     */
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(&*self.changes.read().unwrap()).unwrap_or_default()
+    }
+}
@@ -0,0 +1,41 @@
+use crate::envelope::{self, BodyError, ItemEdit, SentryEnvelope};
+use serde_json::Value;
+
+/**
+ * Tag added to the events of envelopes that broke audited rules, holding the rule names
+ */
+pub const FLAGGED_TAG: &str = "tunnel.flagged";
+
+pub const USER_AGENT: &str = "user_agent";
+pub const COUNTRY: &str = "country";
+pub const BANNED: &str = "banned";
+pub const CONTENT_TYPE: &str = "content_type";
+pub const DUPLICATE: &str = "duplicate";
+pub const SDK: &str = "sdk";
+
+/**
+ * Rules that can be audited instead of enforced
+ */
+pub const RULES: &[&str] = &[USER_AGENT, COUNTRY, BANNED, CONTENT_TYPE, DUPLICATE, SDK];
+
+/**
+ * Tag the events and transactions of the envelope with the rules it broke. Returns the number of
+ * tagged items.
+ */
+pub fn tag_flagged(sentry_instance: &mut SentryEnvelope, rules: &[&str]) -> Result<usize, BodyError> {
+    if rules.is_empty() {
+        return Ok(0);
+    }
+    sentry_instance.edit_items(|item| {
+        if !matches!(item.item_type(), Some("event") | Some("transaction")) {
+            return ItemEdit::Keep;
+        }
+        match serde_json::from_slice(item.payload) {
+            Ok(mut event @ Value::Object(_)) => {
+                envelope::set_tag(&mut event, FLAGGED_TAG, rules.join(","));
+                ItemEdit::Replace(event.to_string().into_bytes())
+            }
+            _ => ItemEdit::Keep,
+        }
+    })
+}
@@ -0,0 +1,161 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sentry_types::{DateTime, TimeZone, Utc};
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/**
+ * A token accepted on the tunnel endpoints, until its optional expiry. When `projects` is set,
+ * the token can only submit to those projects.
+ */
+#[derive(Clone, Debug)]
+pub struct AuthToken {
+    pub token: String,
+    pub expires: Option<DateTime<Utc>>,
+    pub projects: Option<Vec<String>>,
+}
+
+impl FromStr for AuthToken {
+    type Err = String;
+
+    /**
+     * Parse `token` or `token@expiry`, the expiry being an RFC 3339 date or a unix timestamp
+     */
+    fn from_str(entry: &str) -> Result<AuthToken, String> {
+        let entry = entry.trim();
+        let (token, expires) = match entry.split_once('@') {
+            Some((token, expires)) => {
+                let expires = match i64::from_str(expires) {
+                    Ok(timestamp) => Utc.timestamp_opt(timestamp, 0).single(),
+                    Err(_) => DateTime::parse_from_rfc3339(expires)
+                        .ok()
+                        .map(|expires| expires.with_timezone(&Utc)),
+                };
+                match expires {
+                    Some(expires) => (token, Some(expires)),
+                    None => return Err(format!("Invalid expiry for auth token : {}", entry)),
+                }
+            }
+            None => (entry, None),
+        };
+        if token.is_empty() {
+            return Err("Auth tokens can not be empty".to_string());
+        }
+        Ok(AuthToken {
+            token: token.to_string(),
+            expires,
+            projects: None,
+        })
+    }
+}
+
+impl AuthToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= Utc::now())
+    }
+}
+
+/**
+ * A username and password accepted in the `Authorization: Basic` header of the tunnel endpoints
+ */
+#[derive(Clone, Debug)]
+pub struct BasicCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl FromStr for BasicCredentials {
+    type Err = String;
+
+    /**
+     * Parse `username:password`, the password may contain colons but not the username
+     */
+    fn from_str(entry: &str) -> Result<BasicCredentials, String> {
+        match entry.trim().split_once(':') {
+            Some((username, password)) if !username.is_empty() && !password.is_empty() => {
+                Ok(BasicCredentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            _ => Err("Basic auth credentials must be 'username:password' pairs".to_string()),
+        }
+    }
+}
+
+impl BasicCredentials {
+    /**
+     * The value following `Basic ` in the header of the clients using these credentials
+     */
+    pub fn encoded(&self) -> String {
+        BASE64.encode(format!("{}:{}", self.username, self.password))
+    }
+}
+
+/**
+ * The request could not be authenticated
+ */
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+}
+
+impl Error for AuthError {}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => f.write_str("Missing auth token."),
+            AuthError::InvalidToken => f.write_str("Invalid auth token."),
+            AuthError::ExpiredToken => f.write_str("Expired auth token."),
+        }
+    }
+}
+
+/**
+ * Compare without leaking the position of the first difference through timing
+ */
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/**
+ * Find the token presented by a client among the configured ones
+ */
+pub fn authenticate<'a>(tokens: &'a [AuthToken], presented: &str) -> Result<&'a AuthToken, AuthError> {
+    let token = tokens
+        .iter()
+        .find(|token| constant_time_eq(token.token.as_bytes(), presented.as_bytes()))
+        .ok_or(AuthError::InvalidToken)?;
+    if token.is_expired() {
+        return Err(AuthError::ExpiredToken);
+    }
+    Ok(token)
+}
+
+/**
+ * Find the credentials presented, base64 encoded, by a client among the configured ones
+ */
+pub fn authenticate_basic<'a>(
+    credentials: &'a [BasicCredentials],
+    presented: &str,
+) -> Result<&'a BasicCredentials, AuthError> {
+    let decoded = BASE64.decode(presented).map_err(|_| AuthError::InvalidToken)?;
+    let (username, password) = std::str::from_utf8(&decoded)
+        .ok()
+        .and_then(|decoded| decoded.split_once(':'))
+        .ok_or(AuthError::InvalidToken)?;
+    credentials
+        .iter()
+        .find(|entry| {
+            // Both are compared, not to tell through timing whether the username exists
+            let username_matches = constant_time_eq(entry.username.as_bytes(), username.as_bytes());
+            let password_matches = constant_time_eq(entry.password.as_bytes(), password.as_bytes());
+            username_matches & password_matches
+        })
+        .ok_or(AuthError::InvalidToken)
+}
@@ -0,0 +1,37 @@
+use crate::config::Config;
+use crate::server::{dispatch, router};
+use ::axum::extract::{ConnectInfo, State};
+use ::axum::routing::any;
+use gotham::hyper::{Body, Request, Response};
+use gotham::router::Router;
+
+use std::net::SocketAddr;
+
+/**
+ * The routes of the tunnel as an axum router, to nest into an existing application at any path,
+ * `app.nest("/sentry", sentry_tunnel::axum::routes(config))` for instance. Requests go through
+ * the layers of the application before reaching the tunnel.
+ */
+pub fn routes(config: Config) -> ::axum::Router {
+    let tunnel_router = router(&config.tunnel_path.clone(), config);
+    ::axum::Router::new()
+        .route("/", any(handle))
+        .route("/*path", any(handle))
+        .with_state(tunnel_router)
+}
+
+/**
+ * The client address is only known when the application is served with
+ * `into_make_service_with_connect_info`
+ */
+async fn handle(
+    State(router): State<Router>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let client_addr = client.map_or_else(
+        || SocketAddr::from(([0, 0, 0, 0], 0)),
+        |ConnectInfo(addr)| addr,
+    );
+    dispatch(&router, request, client_addr, None).await
+}
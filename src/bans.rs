@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * Clients that are temporarily refused, like scanners probing honeypot paths
+ */
+#[derive(Debug)]
+pub struct BanList {
+    duration: Duration,
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl BanList {
+    pub fn new(duration: Duration) -> BanList {
+        BanList {
+            duration,
+            bans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ban(&self, ip: IpAddr) {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, until| *until > Instant::now());
+        bans.insert(ip, Instant::now() + self.duration);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
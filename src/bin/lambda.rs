@@ -0,0 +1,27 @@
+use log::*;
+use sentry_tunnel::config::Config;
+use sentry_tunnel::monitoring;
+use sentry_tunnel::redact;
+use sentry_tunnel::serverless;
+
+#[tokio::main]
+pub async fn main() {
+    let mut stderr_log = stderrlog::new();
+    stderr_log.verbosity(3).modules([module_path!()]); // Error, Warn and Info
+    redact::init(stderr_log).unwrap();
+    let reporting = monitoring::init();
+
+    let served = match Config::new_from_env_variables() {
+        Ok(config) => {
+            info!("{}", config);
+            serverless::run_lambda(config).await.map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = served {
+        error!("{}", e);
+        monitoring::capture_error(&e);
+        reporting.close();
+        std::process::exit(1)
+    }
+}
@@ -0,0 +1,270 @@
+use envmnt::ListOptions;
+use isahc::{AsyncReadResponseExt, Request, RequestExt};
+use serde_json::json;
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/**
+ * Kinds of envelopes sent by the benchmark, in the order they are cycled through
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Error,
+    Session,
+    Replay(usize),
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::Error => f.write_str("error"),
+            Kind::Session => f.write_str("session"),
+            Kind::Replay(size) => f.write_fmt(format_args!("replay {}B", size)),
+        }
+    }
+}
+
+/**
+ * How to reach the tunnel and what traffic to send it
+ */
+#[derive(Debug)]
+struct BenchConfig {
+    url: String,
+    dsn: String,
+    requests: usize,
+    concurrency: usize,
+    kinds: Vec<Kind>,
+}
+
+impl BenchConfig {
+    /**
+     * Read the configuration from the env variables :
+     * - BENCH_TUNNEL_URL : Url of the tunnel endpoint, `http://127.0.0.1:7878/tunnel` by default.
+     * - BENCH_DSN : Dsn put in the envelope headers. Its host must be one of the remote hosts of
+     *   the tunnel and its project one of its project ids.
+     * - BENCH_REQUESTS : Number of envelopes to send. 1000 by default.
+     * - BENCH_CONCURRENCY : Number of envelopes sent at the same time. 16 by default.
+     * - BENCH_REPLAY_SIZES : Comma separated list of replay recording sizes in bytes.
+     *   `10000,100000,1000000` by default, empty to send no replay.
+     */
+    fn new_from_env_variables() -> Result<BenchConfig, String> {
+        let mut options = ListOptions::new();
+        options.separator = Some(",".to_string());
+        let dsn = envmnt::get_parse("BENCH_DSN")
+            .map_err(|_| "Please set 'BENCH_DSN' to a dsn accepted by the tunnel.".to_string())?;
+        let replay_sizes = match envmnt::get_list_with_options("BENCH_REPLAY_SIZES", &options) {
+            Some(sizes) => sizes
+                .iter()
+                .filter(|size| !size.trim().is_empty())
+                .map(|size| {
+                    size.trim()
+                        .parse::<usize>()
+                        .map_err(|_| format!("Invalid 'BENCH_REPLAY_SIZES' entry : {}", size))
+                })
+                .collect::<Result<Vec<usize>, String>>()?,
+            None => vec![10_000, 100_000, 1_000_000],
+        };
+        let mut kinds = vec![Kind::Error, Kind::Session];
+        kinds.extend(replay_sizes.into_iter().map(Kind::Replay));
+        Ok(BenchConfig {
+            url: envmnt::get_or("BENCH_TUNNEL_URL", "http://127.0.0.1:7878/tunnel"),
+            dsn,
+            requests: envmnt::get_usize("BENCH_REQUESTS", 1000),
+            concurrency: envmnt::get_usize("BENCH_CONCURRENCY", 16).max(1),
+            kinds,
+        })
+    }
+}
+
+/**
+ * An event id unique to this run
+ */
+fn event_id(sequence: usize) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:016x}{:016x}", nanos as u64, sequence as u64)
+}
+
+/**
+ * A realistic envelope of this kind. Errors vary so that duplicate collapsing does not drop them.
+ */
+fn envelope(dsn: &str, kind: Kind, sequence: usize) -> Vec<u8> {
+    let event_id = event_id(sequence);
+    let header = json!({
+        "event_id": event_id,
+        "dsn": dsn,
+        "sdk": { "name": "sentry.javascript.browser", "version": "7.99.0" },
+    });
+    let mut envelope = format!("{}\n", header).into_bytes();
+    match kind {
+        Kind::Error => {
+            let message = format!("Cannot read properties of undefined (reading 'f{}')", sequence);
+            let event = json!({
+                "event_id": event_id,
+                "level": "error",
+                "platform": "javascript",
+                "release": "tunnel-bench@1.0.0",
+                "exception": { "values": [{
+                    "type": "TypeError",
+                    "value": message,
+                }]},
+            });
+            envelope.extend(format!("{}\n{}\n", json!({ "type": "event" }), event).into_bytes());
+        }
+        Kind::Session => {
+            let session = json!({
+                "sid": event_id,
+                "init": true,
+                "status": "ok",
+                "errors": 0,
+                "started": "2024-01-01T00:00:00Z",
+                "attrs": { "release": "tunnel-bench@1.0.0", "environment": "bench" },
+            });
+            envelope.extend(
+                format!("{}\n{}\n", json!({ "type": "session" }), session).into_bytes(),
+            );
+        }
+        Kind::Replay(size) => {
+            let replay_event = json!({
+                "type": "replay_event",
+                "replay_id": event_id,
+                "segment_id": 0,
+                "replay_type": "session",
+            });
+            envelope.extend(
+                format!("{}\n{}\n", json!({ "type": "replay_event" }), replay_event).into_bytes(),
+            );
+            let item_header = json!({ "type": "replay_recording", "length": size });
+            envelope.extend(format!("{}\n", item_header).into_bytes());
+            envelope.extend((0..size).map(|i| b'a' + (i % 26) as u8));
+            envelope.push(b'\n');
+        }
+    }
+    envelope
+}
+
+/**
+ * Outcome of a single envelope
+ */
+#[derive(Debug)]
+struct Sample {
+    kind: Kind,
+    bytes: usize,
+    latency: Duration,
+    failed: bool,
+}
+
+async fn send(config: &BenchConfig, kind: Kind, sequence: usize) -> Sample {
+    let body = envelope(&config.dsn, kind, sequence);
+    let bytes = body.len();
+    let start = Instant::now();
+    let response = match Request::post(&config.url)
+        .header("Content-Type", "application/x-sentry-envelope")
+        .body(body)
+    {
+        Ok(request) => request.send_async().await.ok(),
+        Err(_) => None,
+    };
+    let failed = match response {
+        Some(mut response) => {
+            let _ = response.consume().await;
+            !response.status().is_success()
+        }
+        None => true,
+    };
+    Sample {
+        kind,
+        bytes,
+        latency: start.elapsed(),
+        failed,
+    }
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    sorted[((sorted.len() - 1) * percentile) / 100]
+}
+
+fn report(label: &str, samples: &[&Sample]) {
+    let mut latencies: Vec<Duration> = samples.iter().map(|sample| sample.latency).collect();
+    latencies.sort();
+    let failures = samples.iter().filter(|sample| sample.failed).count();
+    println!(
+        "{:<16} {:>8} {:>8} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?}",
+        label,
+        samples.len(),
+        failures,
+        percentile(&latencies, 50),
+        percentile(&latencies, 95),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default(),
+    );
+}
+
+#[tokio::main]
+pub async fn main() {
+    let config = match BenchConfig::new_from_env_variables() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    println!(
+        "Sending {} envelopes to {} with a concurrency of {}",
+        config.requests, config.url, config.concurrency
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let workers: Vec<_> = (0..config.concurrency)
+        .map(|_| {
+            let config = config.clone();
+            let next = next.clone();
+            tokio::spawn(async move {
+                let mut samples = vec![];
+                loop {
+                    let sequence = next.fetch_add(1, Ordering::Relaxed);
+                    if sequence >= config.requests {
+                        return samples;
+                    }
+                    let kind = config.kinds[sequence % config.kinds.len()];
+                    samples.push(send(&config, kind, sequence).await);
+                }
+            })
+        })
+        .collect();
+    let mut samples = vec![];
+    for worker in workers {
+        samples.extend(worker.await.unwrap_or_default());
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<16} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "kind", "requests", "failures", "p50", "p95", "p99", "max"
+    );
+    for kind in &config.kinds {
+        let of_kind: Vec<&Sample> = samples.iter().filter(|sample| sample.kind == *kind).collect();
+        report(&kind.to_string(), &of_kind);
+    }
+    report("total", &samples.iter().collect::<Vec<&Sample>>());
+    let bytes: usize = samples.iter().map(|sample| sample.bytes).sum();
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "{} envelopes in {:.2?} : {:.1} envelopes/s, {:.2} MB/s",
+        samples.len(),
+        elapsed,
+        samples.len() as f64 / seconds,
+        bytes as f64 / seconds / 1_000_000.0
+    );
+    if samples.iter().any(|sample| sample.failed) {
+        std::process::exit(2)
+    }
+}
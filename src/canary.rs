@@ -0,0 +1,48 @@
+use sentry_types::Dsn;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/**
+ * Share of the envelopes of a project sent to a secondary sentry instead of the one of their dsn
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CanaryRoute {
+    pub percentage: u64,
+    pub dsn: Dsn,
+}
+
+/**
+ * Picks the envelopes of each project that are sent to its canary dsn
+ */
+#[derive(Debug)]
+pub struct Canary {
+    routes: HashMap<String, CanaryRoute>,
+    envelopes: Mutex<HashMap<String, u64>>,
+}
+
+impl Canary {
+    pub fn new(routes: HashMap<String, CanaryRoute>) -> Canary {
+        Canary {
+            routes,
+            envelopes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * The dsn the next envelope of this project must be sent to, if it is a canary one.
+     * Canary envelopes are evenly spread : with 10%, every tenth envelope is picked.
+     */
+    pub fn pick(&self, project_id: &str) -> Option<&Dsn> {
+        let route = self.routes.get(project_id)?;
+        let mut envelopes = self.envelopes.lock().unwrap();
+        let seen = envelopes.entry(project_id.to_string()).or_default();
+        *seen += 1;
+        let picked = *seen * route.percentage / 100 > (*seen - 1) * route.percentage / 100;
+        if picked {
+            Some(&route.dsn)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::*;
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/**
+ * The request body uses an encoding the tunnel can not decode
+ */
+#[derive(Debug)]
+pub struct UnsupportedEncoding(pub String);
+
+impl Error for UnsupportedEncoding {}
+
+impl Display for UnsupportedEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "Unsupported Content-Encoding '{}', expected gzip, br or zstd.",
+            self.0
+        ))
+    }
+}
+
+/**
+ * A `Content-Encoding` of the envelopes received, or forwarded once compressed
+ */
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Encoding, String> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" => Ok(Encoding::Gzip),
+            "br" => Ok(Encoding::Brotli),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => Err(format!(
+                "Unsupported upstream encoding '{}', expected gzip, br or zstd",
+                name.trim()
+            )),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        })
+    }
+}
+
+impl Encoding {
+    pub fn compress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Encoding::Brotli => {
+                let mut compressed = vec![];
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                    writer.write_all(bytes)?;
+                }
+                Ok(compressed)
+            }
+            Encoding::Zstd => zstd::stream::encode_all(bytes, 3),
+        }
+    }
+
+    /**
+     * Decompress a body, failing once it exceeds `max` bytes so that a small body can not expand
+     * into an unbounded one
+     */
+    pub fn decompress(&self, bytes: &[u8], max: u64) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Encoding::Gzip => Box::new(GzDecoder::new(bytes)),
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(bytes, 4096)),
+            Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(bytes)?),
+        };
+        let mut decompressed = vec![];
+        if decoder.take(max + 1).read_to_end(&mut decompressed)? as u64 > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the decompressed body is too big",
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+/**
+ * The encoding of a request body, from its `Content-Encoding` header. None when the body is not
+ * encoded.
+ */
+pub fn request_encoding(header: Option<&str>) -> Result<Option<Encoding>, UnsupportedEncoding> {
+    match header.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(name) if name.eq_ignore_ascii_case("identity") => Ok(None),
+        Some(name) => Encoding::from_str(name)
+            .map(Some)
+            .map_err(|_| UnsupportedEncoding(name.to_string())),
+    }
+}
+
+/**
+ * Picks the encoding of the envelopes forwarded to each upstream host. Encodings rejected by a
+ * host, older self-hosted Sentry versions for instance, are not used for that host anymore.
+ */
+#[derive(Debug)]
+pub struct UpstreamCompression {
+    encodings: Vec<Encoding>,
+    threshold: u64,
+    rejected: RwLock<HashMap<String, HashSet<Encoding>>>,
+}
+
+impl UpstreamCompression {
+    /**
+     * Compress the bodies of at least `threshold` bytes with the first of `encodings`, in order of
+     * preference, that the host did not reject
+     */
+    pub fn new(encodings: Vec<Encoding>, threshold: u64) -> UpstreamCompression {
+        UpstreamCompression {
+            encodings,
+            threshold,
+            rejected: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * The encoding of a body of `size` bytes sent to `host`, None to send it as is
+     */
+    pub fn pick(&self, host: &str, size: usize) -> Option<Encoding> {
+        if (size as u64) < self.threshold {
+            return None;
+        }
+        let rejected = self.rejected.read().unwrap();
+        let rejected = rejected.get(host);
+        self.encodings
+            .iter()
+            .find(|encoding| !rejected.is_some_and(|rejected| rejected.contains(encoding)))
+            .copied()
+    }
+
+    pub fn reject(&self, host: &str, encoding: Encoding) {
+        warn!("{} does not accept {} envelopes, falling back", host, encoding);
+        self.rejected
+            .write()
+            .unwrap()
+            .entry(host.to_string())
+            .or_default()
+            .insert(encoding);
+    }
+}
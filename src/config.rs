@@ -1,32 +1,675 @@
+use crate::acme::{Challenges, LETS_ENCRYPT_DIRECTORY};
+use crate::allowlist::Allowlist;
+use crate::audit;
+use crate::auth::{AuthToken, BasicCredentials};
+use crate::compression::Encoding;
+use crate::canary::CanaryRoute;
+use crate::discovery::{DiscoveredProjects, DiscoveryConfig};
+pub use crate::envelope::{Host, ProjectId};
+use crate::envelope::KNOWN_ITEM_TYPES;
+use crate::forward::RetryPolicy;
+use crate::geoip::GeoIp;
+use crate::redact::{self, REDACTED};
+use crate::region;
+use crate::sdk::SdkRule;
+use crate::vault::VaultConfig;
 use envmnt::ListOptions;
 
+use sentry_types::Dsn;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Display, Formatter};
-use url::Url;
-use log::error;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use log::warn;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Host(pub String);
+/**
+ * Lowercase fragments of the User-Agent of well known bots, crawlers and headless browsers
+ */
+pub const KNOWN_BOT_USER_AGENTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "headlesschrome",
+    "phantomjs",
+    "lighthouse",
+    "pingdom",
+    "python-requests",
+    "scrapy",
+    "wget",
+];
 
-impl Display for Host {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        self.0.fmt(f)
-    }
-}
+/**
+ * Variables that can be provided as files of TUNNEL_CONFIG_DIR, a mounted ConfigMap or Secret for
+ * instance, and are reloaded when those files change
+ */
+pub const RELOADABLE_VARIABLES: &[&str] = &[
+    "TUNNEL_REMOTE_HOST",
+    "TUNNEL_PROJECT_IDS",
+    "TUNNEL_AUTH_TOKENS",
+    "TUNNEL_TOKEN_PROJECTS",
+];
 
-#[derive(Clone, Debug)]
+/**
+ * Request headers browsers may send to the tunnel from another origin when none are configured
+ */
+pub const CORS_DEFAULT_HEADERS: &[&str] = &["Content-Type", "Content-Encoding", "Authorization"];
+
+/**
+ * Content types sentry SDKs post envelopes with
+ */
+pub const ENVELOPE_CONTENT_TYPES: &[&str] = &[
+    "application/x-sentry-envelope",
+    "application/octet-stream",
+    "application/json",
+    "text/plain",
+];
+
+/**
+ * The configuration of the tunnel. It can be deserialized from a file, using the names of the
+ * fields and the formats of the matching env variables for the values that are parsed, tokens,
+ * dsns or canary routes for instance. Missing fields take their default value. It is serialized
+ * the same way, with the dsn keys, tokens, passwords and secrets redacted.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Config {
+    #[serde(deserialize_with = "remote_hosts", serialize_with = "to_strings")]
     pub remote_hosts: Vec<Host>,
-    pub project_ids: Vec<String>,
+    #[serde(deserialize_with = "from_strings", serialize_with = "to_strings")]
+    pub project_ids: Vec<ProjectId>,
+    // Every project is accepted, the hosts are still checked
+    pub allow_all_projects: bool,
     pub port: u16,
     pub tunnel_path: String,
+    // Served like the tunnel path, for frontends posting to other urls
+    pub extra_paths: Vec<String>,
+    // Project ids and remote hosts of the tunnel paths that do not use the global ones
+    pub path_projects: HashMap<String, Vec<String>>,
+    #[serde(deserialize_with = "path_remote_hosts", serialize_with = "path_hosts_to_strings")]
+    pub path_remote_hosts: HashMap<String, Vec<Host>>,
     pub ip: String,
+    pub session_aggregation_window: Option<u64>,
+    pub streaming_threshold: Option<u64>,
+    pub max_body_size: Option<u64>,
+    pub max_attachment_size: u64,
+    pub buffer_pool_size: usize,
+    pub max_in_flight: Option<usize>,
+    pub max_buffered_bytes: Option<u64>,
+    pub upstream_rate: Option<u64>,
+    pub upstream_max_delay: u64,
+    pub upstream_max_connections: usize,
+    pub upstream_idle_connections: usize,
+    #[serde(deserialize_with = "from_strings", serialize_with = "to_strings")]
+    pub upstream_encodings: Vec<Encoding>,
+    pub compression_threshold: u64,
+    pub upload_timeout: Option<u64>,
+    pub relay_responses: bool,
+    pub rate_limit_cache: bool,
+    pub forward_retries: u32,
+    pub retry_base_delay: u64,
+    pub retry_max_delay: u64,
+    pub spool_dir: Option<String>,
+    pub spool_max_size: u64,
+    pub spool_max_age: u64,
+    pub async_forwarding: bool,
+    pub queue_size: usize,
+    pub queue_workers: usize,
+    pub processing_budget: Option<u64>,
+    pub spill_threshold: Option<u64>,
+    pub spill_dir: Option<String>,
+    pub strict_items: bool,
+    pub silent_drop: bool,
+    pub allowed_items: Vec<String>,
+    pub allowed_content_types: Vec<String>,
+    pub otlp_path: Option<String>,
+    #[serde(deserialize_with = "optional_from_string", serialize_with = "redacted_dsn")]
+    pub otlp_dsn: Option<Dsn>,
+    pub h3_port: Option<u16>,
+    pub tls_port: Option<u16>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_cert_pem: Option<String>,
+    #[serde(serialize_with = "redact::optional_secret")]
+    pub tls_key_pem: Option<String>,
+    pub acme_domains: Vec<String>,
+    pub acme_email: Option<String>,
+    pub acme_directory: String,
+    pub acme_dir: String,
+    #[serde(skip)]
+    pub acme_challenges: Arc<Challenges>,
+    pub grpc: bool,
+    pub websocket_path: Option<String>,
+    #[serde(serialize_with = "redacted_values")]
+    pub signing_secrets: HashMap<String, String>,
+    pub daily_quotas: HashMap<String, u64>,
+    pub monthly_quotas: HashMap<String, u64>,
+    #[serde(deserialize_with = "canary_routes", serialize_with = "redacted_canary_routes")]
+    pub canary_routes: HashMap<String, CanaryRoute>,
+    #[serde(deserialize_with = "project_upstreams", serialize_with = "upstream_entries")]
+    pub project_upstreams: HashMap<String, Host>,
+    pub filter_bots: bool,
+    pub denied_user_agents: Vec<String>,
+    #[serde(deserialize_with = "from_strings", serialize_with = "to_strings")]
+    pub allowed_sdks: Vec<SdkRule>,
+    #[serde(rename = "geoip_database", deserialize_with = "geoip_database", skip_serializing)]
+    pub geoip: Option<Arc<GeoIp>>,
+    pub allowed_countries: Vec<String>,
+    pub denied_countries: Vec<String>,
+    pub allowed_regions: Vec<String>,
+    pub denied_regions: Vec<String>,
+    pub region_proxies: HashMap<String, String>,
+    pub client_ip_header: Option<String>,
+    pub max_replay_recording_size: Option<u64>,
+    pub spam_window: Option<u64>,
+    pub spam_limit: u64,
+    pub idempotency_window: Option<u64>,
+    pub honeypot_paths: Vec<String>,
+    pub ban_duration: u64,
+    pub tls_client_ca_path: Option<String>,
+    pub client_cert_projects: HashMap<String, Vec<String>>,
+    #[serde(deserialize_with = "from_strings", serialize_with = "redacted_tokens")]
+    pub auth_tokens: Vec<AuthToken>,
+    #[serde(deserialize_with = "from_strings", serialize_with = "redacted_credentials")]
+    pub basic_credentials: Vec<BasicCredentials>,
+    pub audited_rules: Vec<String>,
+    pub vault: Option<VaultConfig>,
+    pub discovery: Option<DiscoveryConfig>,
+    #[serde(skip)]
+    pub discovered_projects: Arc<DiscoveredProjects>,
+    pub config_dir: Option<String>,
+    #[serde(deserialize_with = "from_strings", serialize_with = "redacted_tokens")]
+    pub admin_tokens: Vec<AuthToken>,
+    pub toggles_path: Option<String>,
+    pub allowlist_path: Option<String>,
+    #[serde(skip)]
+    pub allowlist: Arc<Allowlist>,
+    pub stats_path: Option<String>,
+    pub readiness_upstream_check: bool,
+    pub access_log: bool,
+    pub tracing_endpoint: Option<String>,
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_headers: Vec<String>,
+    pub drain_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            remote_hosts: vec![],
+            project_ids: vec![],
+            allow_all_projects: false,
+            port: 7878,
+            tunnel_path: "/tunnel".to_string(),
+            extra_paths: vec![],
+            path_projects: HashMap::new(),
+            path_remote_hosts: HashMap::new(),
+            ip: "127.0.0.1".to_string(),
+            session_aggregation_window: None,
+            streaming_threshold: None,
+            max_body_size: None,
+            max_attachment_size: 100_000_000,
+            buffer_pool_size: 16,
+            max_in_flight: None,
+            max_buffered_bytes: None,
+            upstream_rate: None,
+            upstream_max_delay: 5000,
+            upstream_max_connections: 0,
+            upstream_idle_connections: 0,
+            upstream_encodings: vec![],
+            compression_threshold: 1024,
+            upload_timeout: None,
+            relay_responses: false,
+            rate_limit_cache: false,
+            forward_retries: 0,
+            retry_base_delay: 100,
+            retry_max_delay: 5000,
+            spool_dir: None,
+            spool_max_size: 100_000_000,
+            spool_max_age: 86400,
+            async_forwarding: false,
+            queue_size: 1000,
+            queue_workers: 4,
+            processing_budget: None,
+            spill_threshold: None,
+            spill_dir: None,
+            strict_items: false,
+            silent_drop: false,
+            allowed_items: Config::known_item_types(),
+            allowed_content_types: Config::envelope_content_types(),
+            otlp_path: None,
+            otlp_dsn: None,
+            h3_port: None,
+            tls_port: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_cert_pem: None,
+            tls_key_pem: None,
+            acme_domains: vec![],
+            acme_email: None,
+            acme_directory: LETS_ENCRYPT_DIRECTORY.to_string(),
+            acme_dir: "acme".to_string(),
+            acme_challenges: Arc::new(Challenges::default()),
+            grpc: false,
+            websocket_path: None,
+            signing_secrets: HashMap::new(),
+            daily_quotas: HashMap::new(),
+            monthly_quotas: HashMap::new(),
+            canary_routes: HashMap::new(),
+            project_upstreams: HashMap::new(),
+            filter_bots: false,
+            denied_user_agents: vec![],
+            allowed_sdks: vec![],
+            geoip: None,
+            allowed_countries: vec![],
+            denied_countries: vec![],
+            allowed_regions: vec![],
+            denied_regions: vec![],
+            region_proxies: HashMap::new(),
+            client_ip_header: None,
+            max_replay_recording_size: None,
+            spam_window: None,
+            spam_limit: 10,
+            idempotency_window: None,
+            honeypot_paths: vec![],
+            ban_duration: 3600,
+            tls_client_ca_path: None,
+            client_cert_projects: HashMap::new(),
+            auth_tokens: vec![],
+            basic_credentials: vec![],
+            audited_rules: vec![],
+            vault: None,
+            discovery: None,
+            discovered_projects: Arc::new(DiscoveredProjects::default()),
+            config_dir: None,
+            admin_tokens: vec![],
+            toggles_path: None,
+            allowlist_path: None,
+            allowlist: Arc::new(Allowlist::default()),
+            stats_path: None,
+            readiness_upstream_check: false,
+            access_log: false,
+            tracing_endpoint: None,
+            cors_allowed_origins: vec![],
+            cors_allowed_headers: Config::cors_default_headers(),
+            drain_timeout: 25,
+        }
+    }
+}
+
+/**
+ * The value read from a variable, or its default once its problem is added to `errors`, so that
+ * every problem of the configuration is reported at once
+ */
+fn reported<T: Default>(errors: &mut Vec<String>, read: Result<T, String>) -> T {
+    read.unwrap_or_else(|e| {
+        errors.push(e);
+        T::default()
+    })
+}
+
+/**
+ * The number a variable is set to, None when it is not set. A value that is not a number is added
+ * to `errors` rather than silently ignored.
+ */
+fn env_number<T: FromStr>(errors: &mut Vec<String>, variable: &str) -> Option<T> {
+    let value = envmnt::get_or(variable, "");
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    match value.parse::<T>() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            errors.push(format!("Invalid '{}', expected a number : {}", variable, value));
+            None
+        }
+    }
+}
+
+/**
+ * Why a path can not be the route of a handler, if it can not
+ */
+fn route_problem(path: &str) -> Option<&'static str> {
+    if !path.starts_with('/') {
+        Some("must start with a '/'")
+    } else if path.contains(|c: char| c.is_whitespace() || c == '?' || c == '#') {
+        Some("must not contain spaces, '?' or '#'")
+    } else if path.contains("//") {
+        Some("must not contain empty segments")
+    } else if path.split('/').any(|segment| segment.starts_with(':') || segment == "*") {
+        // Those segments would match any value
+        Some("must not contain segments starting with ':' or equal to '*'")
+    } else {
+        None
+    }
+}
+
+/**
+ * Fields holding state shared at runtime rather than configuration
+ */
+const RUNTIME_FIELDS: &[&str] = &["acme_challenges", "discovered_projects", "allowlist"];
+
+/**
+ * Fields only read when the tunnel starts, a reloaded configuration changing them is only applied
+ * after a restart
+ */
+pub const RESTART_FIELDS: &[&str] = &[
+    "port",
+    "ip",
+    "tunnel_path",
+    "extra_paths",
+    "otlp_path",
+    "grpc",
+    "websocket_path",
+    "h3_port",
+    "tls_port",
+    "acme_domains",
+    "admin_tokens",
+    "session_aggregation_window",
+    "buffer_pool_size",
+    "max_in_flight",
+    "upstream_rate",
+    "upstream_max_delay",
+    "upstream_max_connections",
+    "upstream_idle_connections",
+    "upstream_encodings",
+    "compression_threshold",
+    "rate_limit_cache",
+    "spool_dir",
+    "spool_max_size",
+    "spool_max_age",
+    "async_forwarding",
+    "queue_size",
+    "queue_workers",
+    "daily_quotas",
+    "monthly_quotas",
+    "canary_routes",
+    "spam_window",
+    "spam_limit",
+    "idempotency_window",
+    "honeypot_paths",
+    "ban_duration",
+    "toggles_path",
+    "stats_path",
+    "access_log",
+    "cors_allowed_origins",
+    "cors_allowed_headers",
+];
+
+/**
+ * Sections a config file can group fields in, with the keys they hold and the fields of `Config`
+ * these stand for
+ */
+pub const FILE_SECTIONS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "upstreams",
+        &[
+            ("hosts", "remote_hosts"),
+            ("max_connections", "upstream_max_connections"),
+            ("idle_connections", "upstream_idle_connections"),
+            ("rate", "upstream_rate"),
+            ("max_delay", "upstream_max_delay"),
+            ("encodings", "upstream_encodings"),
+            ("compression_threshold", "compression_threshold"),
+            ("relay_responses", "relay_responses"),
+            ("retries", "forward_retries"),
+            ("retry_base_delay", "retry_base_delay"),
+            ("retry_max_delay", "retry_max_delay"),
+            ("region_proxies", "region_proxies"),
+        ],
+    ),
+    (
+        "limits",
+        &[
+            ("max_body_size", "max_body_size"),
+            ("max_attachment_size", "max_attachment_size"),
+            ("max_in_flight", "max_in_flight"),
+            ("max_buffered_bytes", "max_buffered_bytes"),
+            ("max_replay_recording_size", "max_replay_recording_size"),
+            ("upload_timeout", "upload_timeout"),
+            ("processing_budget", "processing_budget"),
+            ("daily_quotas", "daily_quotas"),
+            ("monthly_quotas", "monthly_quotas"),
+            ("spam_window", "spam_window"),
+            ("spam_limit", "spam_limit"),
+        ],
+    ),
+    (
+        "tls",
+        &[
+            ("port", "tls_port"),
+            ("h3_port", "h3_port"),
+            ("cert_path", "tls_cert_path"),
+            ("key_path", "tls_key_path"),
+            ("client_ca_path", "tls_client_ca_path"),
+            ("client_cert_projects", "client_cert_projects"),
+            ("acme_domains", "acme_domains"),
+            ("acme_email", "acme_email"),
+            ("acme_directory", "acme_directory"),
+            ("acme_dir", "acme_dir"),
+        ],
+    ),
+];
+
+/**
+ * Move the keys of the sections of a config file to the top level, under the names of their
+ * fields
+ */
+fn flatten_sections(mut value: serde_json::Value) -> Result<serde_json::Value, String> {
+    let fields = value
+        .as_object_mut()
+        .ok_or_else(|| "Expected a map of fields".to_string())?;
+    for (section, keys) in FILE_SECTIONS {
+        let entries = match fields.remove(*section) {
+            Some(serde_json::Value::Object(entries)) => entries,
+            Some(_) => return Err(format!("'{}' must be a section of fields", section)),
+            None => continue,
+        };
+        for (key, entry) in entries {
+            let field = keys
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, field)| *field)
+                .ok_or_else(|| format!("Unknown field '{}' in section '{}'", key, section))?;
+            if fields.contains_key(field) {
+                return Err(format!(
+                    "'{}' of section '{}' is also set as '{}'",
+                    key, section, field
+                ));
+            }
+            fields.insert(field.to_string(), entry);
+        }
+    }
+    Ok(value)
+}
+
+fn remote_hosts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Host>, D::Error> {
+    let hosts = Vec::<String>::deserialize(deserializer)?;
+    Config::clean_remote_hosts(&hosts).map_err(de::Error::custom)
+}
+
+/**
+ * A value parsed like the matching env variable
+ */
+fn optional_from_string<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) => T::from_str(&value).map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn from_strings<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| T::from_str(value).map_err(de::Error::custom))
+        .collect()
+}
+
+fn canary_routes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, CanaryRoute>, D::Error> {
+    Config::parse_canary_routes(&Vec::<String>::deserialize(deserializer)?)
+        .map_err(de::Error::custom)
+}
+
+fn path_remote_hosts<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Vec<Host>>, D::Error> {
+    HashMap::<String, Vec<String>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(path, hosts)| Ok((path, Config::clean_remote_hosts(&hosts)?)))
+        .collect::<Result<_, String>>()
+        .map_err(de::Error::custom)
+}
+
+fn project_upstreams<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Host>, D::Error> {
+    Config::parse_project_upstreams(&Vec::<String>::deserialize(deserializer)?)
+        .map_err(de::Error::custom)
+}
+
+fn geoip_database<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Arc<GeoIp>>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(path) => GeoIp::open(&path)
+            .map(|geoip| Some(Arc::new(geoip)))
+            .map_err(|e| {
+                de::Error::custom(format!("Could not open the GeoIP database {} : {}", path, e))
+            }),
+        None => Ok(None),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn to_strings<S, T>(values: &Vec<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Display,
+{
+    serializer.collect_seq(values.iter().map(T::to_string))
+}
+
+fn redacted_dsn<S: Serializer>(dsn: &Option<Dsn>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_some(&dsn.as_ref().map(|dsn| redact::redact(&dsn.to_string())))
+}
+
+fn redacted_values<S: Serializer>(
+    secrets: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(secrets.keys().map(|key| (key, REDACTED)))
+}
+
+/**
+ * The `project_id:percentage:dsn` entries of the routes, without the keys of the dsns
+ */
+fn redacted_canary_routes<S: Serializer>(
+    routes: &HashMap<String, CanaryRoute>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut entries: Vec<String> = routes
+        .iter()
+        .map(|(project_id, route)| {
+            redact::redact(&format!("{}:{}:{}", project_id, route.percentage, route.dsn))
+        })
+        .collect();
+    entries.sort();
+    serializer.collect_seq(entries)
+}
+
+fn path_hosts_to_strings<S: Serializer>(
+    path_hosts: &HashMap<String, Vec<Host>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(path_hosts.iter().map(|(path, hosts)| {
+        let hosts: Vec<String> = hosts.iter().map(Host::to_string).collect();
+        (path, hosts)
+    }))
+}
+
+fn upstream_entries<S: Serializer>(
+    upstreams: &HashMap<String, Host>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut entries: Vec<String> = upstreams
+        .iter()
+        .map(|(project_id, host)| format!("{}:{}", project_id, host))
+        .collect();
+    entries.sort();
+    serializer.collect_seq(entries)
+}
+
+/**
+ * The expiry and projects of the tokens, which matter when a request is refused
+ */
+#[allow(clippy::ptr_arg)]
+fn redacted_tokens<S: Serializer>(
+    tokens: &Vec<AuthToken>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(tokens.iter().map(|token| {
+        json!({
+            "token": REDACTED,
+            "expires": token.expires.map(|expires| expires.to_rfc3339()),
+            "projects": token.projects,
+        })
+    }))
+}
+
+#[allow(clippy::ptr_arg)]
+fn redacted_credentials<S: Serializer>(
+    credentials: &Vec<BasicCredentials>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        credentials
+            .iter()
+            .map(|credentials| format!("{}:{}", credentials.username, REDACTED)),
+    )
+}
+
+fn join<T: Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(T::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
 }
 
 impl Display for Config {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "Listening on {}:{}{}\nForwarding requests to : {:?}\nValid project ids : {:?}",
-            self.ip, self.port, self.tunnel_path, self.remote_hosts, self.project_ids
+            "Listening on {}:{}{}\nForwarding requests to : {}\nValid project ids : {}",
+            self.ip,
+            self.port,
+            self.tunnel_paths().join(", "),
+            join(&self.remote_hosts),
+            if self.allow_all_projects {
+                "all".to_string()
+            } else {
+                join(&self.project_ids)
+            }
         ))
     }
 }
@@ -34,59 +677,1618 @@ impl Display for Config {
 impl Config {
     /**
      * Create a new config from env variables :
-     * - TUNNEL_REMOTE_HOST : Comma separated list of valid sentry relays
+     * - TUNNEL_REMOTE_HOST : Comma separated list of valid sentry relays, or patterns of their
+     *   host names, `https://*.ingest.sentry.io` for instance
      * - TUNNEL_PROJECT_IDS : Comma separated list of valid project ids that can be forwarded to
-     * sentry
+     *   sentry, `*` accepting every project of the remote hosts
      * - TUNNEL_LISTEN_PORT : Optionnal listen port, 7878 by default
      * - TUNNEL_PATH : Url path where this tunnel is waiting for sentry requests. By default
+     * - TUNNEL_EXTRA_PATHS : Optional comma separated list of other url paths served like
+     *   TUNNEL_PATH, `/monitoring/bugs` for instance
+     * - TUNNEL_PATH_PROJECTS : Comma separated list of `path:project_id|project_id` pairs. The
+     *   envelopes posted on those tunnel paths are checked against their own project ids instead
+     *   of TUNNEL_PROJECT_IDS.
+     * - TUNNEL_PATH_REMOTE_HOSTS : Comma separated list of `path:url|url` pairs, the remote hosts
+     *   of those tunnel paths instead of TUNNEL_REMOTE_HOST.
      * - TUNNEL_IP : Listen interface. Optional, 127.0.0.1 by default.
+     * - TUNNEL_SESSION_AGGREGATION_WINDOW : Optional window in seconds during which `session`
+     *   items are aggregated into `sessions` items before being forwarded. Disabled by default.
+     * - TUNNEL_STREAMING_THRESHOLD : Optional body size in bytes above which requests are streamed
+     *   to sentry instead of being buffered. Disabled by default.
+     * - TUNNEL_MAX_BODY_SIZE : Optional size in bytes of the biggest body accepted on TUNNEL_PATH.
+     *   Bigger ones are answered with a 413 status. 10 MB by default, 100 MB when bodies are
+     *   streamed.
+     * - TUNNEL_MAX_ATTACHMENT_SIZE : Maximum size in bytes of a streamed attachment item. 100 MB
+     *   by default.
+     * - TUNNEL_BUFFER_POOL_SIZE : Number of body buffers of each size class kept for reuse. 16 by
+     *   default, 0 disables the pool.
+     * - TUNNEL_MAX_IN_FLIGHT : Optional number of envelope requests handled at the same time.
+     *   Further requests are answered with a 503 status before their body is read.
+     * - TUNNEL_MAX_BUFFERED_BYTES : Optional number of bytes of bodies held in memory at the same
+     *   time. Requests whose body would exceed it are answered with a 503 status.
+     * - TUNNEL_UPSTREAM_RATE : Optional number of envelopes forwarded per second. Bursts are spread
+     *   evenly instead of being forwarded at once.
+     * - TUNNEL_UPSTREAM_MAX_DELAY : Milliseconds an envelope may wait for its turn when the rate is
+     *   limited, 5000 by default. Envelopes that would wait longer get a 503 status.
+     * - TUNNEL_UPSTREAM_MAX_CONNECTIONS : Optional number of connections opened to a sentry host
+     *   at the same time. No limit by default.
+     * - TUNNEL_UPSTREAM_IDLE_CONNECTIONS : Optional number of idle connections kept open to be
+     *   reused. The default of the HTTP client otherwise.
+     * - TUNNEL_UPSTREAM_ENCODINGS : Optional comma separated list of encodings among `gzip`, `br`
+     *   and `zstd`, by order of preference. Envelopes are compressed with the first one the sentry
+     *   relay did not reject. Disabled by default.
+     * - TUNNEL_COMPRESSION_THRESHOLD : Size in bytes from which envelopes are compressed, 1024 by
+     *   default.
+     * - TUNNEL_UPLOAD_TIMEOUT : Optional number of seconds a client has to send its request body.
+     *   Slower uploads are aborted with a 408 status. Disabled by default.
+     * - TUNNEL_RELAY_RESPONSES : Answer the envelopes posted on TUNNEL_PATH with the status,
+     *   `X-Sentry-*` headers and body of the response of sentry. Disabled by default, envelopes are
+     *   answered with a 200 status once forwarded.
+     * - TUNNEL_RATE_LIMIT_CACHE : Remember the rate limits sentry answers with, and reject the
+     *   envelopes they apply to with a 429 status until they expire. Disabled by default.
+     * - TUNNEL_FORWARD_RETRIES : Number of times a buffered envelope is sent again when sentry can
+     *   not be reached or answers a 5xx status. 0 by default.
+     * - TUNNEL_RETRY_BASE_DELAY : Milliseconds before the first retry, doubled for each next one.
+     *   100 by default.
+     * - TUNNEL_RETRY_MAX_DELAY : Maximum milliseconds between two retries, 5000 by default.
+     * - TUNNEL_SPOOL_DIR : Optional directory where the envelopes sentry could not take are
+     *   written, to be sent again once it is back. Disabled by default.
+     * - TUNNEL_SPOOL_MAX_SIZE : Maximum size in bytes of the spool, 100 MB by default.
+     * - TUNNEL_SPOOL_MAX_AGE : Seconds after which spooled envelopes are dropped, 86400 by default.
+     * - TUNNEL_ASYNC_FORWARDING : Answer buffered envelopes with a 200 status once they are
+     *   queued, before they are forwarded. Disabled by default.
+     * - TUNNEL_QUEUE_SIZE : Number of envelopes waiting to be forwarded, beyond which envelopes
+     *   are answered with a 503 status. 1000 by default, split evenly between the workers.
+     * - TUNNEL_QUEUE_WORKERS : Number of envelopes forwarded at the same time from the queue, 4 by
+     *   default. The envelopes of a project are always forwarded by the same worker, in order.
+     * - TUNNEL_PROCESSING_BUDGET : Optional number of milliseconds an envelope can spend in the
+     *   processing pipeline. Once spent, the remaining optional stages are skipped and the
+     *   envelope is forwarded anyway.
+     * - TUNNEL_SPILL_THRESHOLD : Optional body size in bytes above which streamed bodies are
+     *   written to a temporary file before being forwarded. Disabled by default.
+     * - TUNNEL_SPILL_DIR : Directory of those temporary files. The system temporary directory by
+     *   default.
+     * - TUNNEL_SILENT_DROP : Answer envelopes of unknown projects or hosts with a 200 status and
+     *   drop them, instead of a 400 status. False by default.
+     * - TUNNEL_STRICT_ITEMS : Reject envelopes containing item types that are not allowed. False by
+     *   default, in which case those envelopes are only logged.
+     * - TUNNEL_ALLOWED_ITEMS : Comma separated list of allowed item types. Every item type known
+     *   by sentry by default.
+     * - TUNNEL_ALLOWED_CONTENT_TYPES : Comma separated list of content types accepted on the tunnel
+     *   path, `*` accepting any. The ones used by sentry SDKs by default.
+     * - TUNNEL_OTLP_PATH : Optional url path of an OTLP/HTTP endpoint accepting traces, which are
+     *   converted to sentry transactions. Requires TUNNEL_OTLP_DSN.
+     * - TUNNEL_OTLP_DSN : The dsn transactions converted from OTLP traces are sent to.
+     * - TUNNEL_H3_PORT : Optional UDP port of an experimental HTTP/3 listener. Requires the
+     *   `http3` feature, TUNNEL_TLS_CERT_PATH and TUNNEL_TLS_KEY_PATH.
+     * - TUNNEL_TLS_PORT : Optional TCP port of an HTTPS listener, next to the plain one. Requires
+     *   the `tls` feature, TUNNEL_TLS_CERT_PATH and TUNNEL_TLS_KEY_PATH.
+     * - TUNNEL_TLS_CERT_PATH : Path to a PEM certificate chain.
+     * - TUNNEL_TLS_KEY_PATH : Path to the PEM private key of the certificate.
+     * - TUNNEL_ACME_DOMAINS : Comma separated list of domains a certificate is obtained for from
+     *   an ACME server, and renewed, when TUNNEL_TLS_CERT_PATH and TUNNEL_TLS_KEY_PATH are not
+     *   provided. Requires the `acme` feature.
+     * - TUNNEL_ACME_EMAIL : Optional contact address of the ACME account.
+     * - TUNNEL_ACME_DIRECTORY : Directory url of the ACME server, Let's Encrypt by default.
+     * - TUNNEL_ACME_DIR : Directory where the ACME account, certificate and key are stored,
+     *   `acme` by default.
+     * - TUNNEL_TLS_CLIENT_CA_PATH : Optional path to PEM CA certificates. When set, TLS clients
+     *   must present a certificate signed by one of them.
+     * - TUNNEL_CLIENT_CERT_PROJECTS : Comma separated list of `name:project_id|project_id` pairs
+     *   restricting the projects a client certificate, identified by its CN or a DNS SAN, can
+     *   submit to.
+     * - TUNNEL_GRPC : Enable the `sentry_tunnel.Tunnel/SubmitEnvelope` gRPC method. False by
+     *   default.
+     * - TUNNEL_WEBSOCKET_PATH : Optional url path of a WebSocket endpoint where clients can push
+     *   several envelopes over a single connection. Disabled by default.
+     * - TUNNEL_SIGNING_SECRETS : Comma separated list of `project_id:secret` pairs. Requests for
+     *   those projects must be signed with an HMAC of their body keyed by the project secret.
+     * - TUNNEL_DAILY_QUOTAS : Comma separated list of `project_id:events` pairs. Events of those
+     *   projects are dropped once that many were forwarded during the current UTC day.
+     * - TUNNEL_MONTHLY_QUOTAS : Same as TUNNEL_DAILY_QUOTAS, for the current UTC month.
+     * - TUNNEL_CANARY : Comma separated list of `project_id:percentage:dsn` entries. That
+     *   percentage of the envelopes of the project is sent to the dsn instead of its own one.
+     * - TUNNEL_PROJECT_UPSTREAMS : Comma separated list of `project_id:url` entries. Envelopes of
+     *   those projects are sent to the sentry host of the url whatever the host of their dsn.
+     * - TUNNEL_FILTER_BOTS : Drop requests from well known bots and headless browsers. False by
+     *   default.
+     * - TUNNEL_DENIED_USER_AGENTS : Comma separated list of User-Agent fragments, requests whose
+     *   User-Agent contains one of them are dropped. Case insensitive.
+     * - TUNNEL_ALLOWED_SDKS : Comma separated list of `name` or `name>=version` entries. When set,
+     *   envelopes sent by other SDKs, or older versions of them, are dropped.
+     * - TUNNEL_GEOIP_DATABASE : Path to a MaxMind country or city database, required by the
+     *   country lists.
+     * - TUNNEL_ALLOWED_COUNTRIES : Comma separated list of ISO country codes. When set, only
+     *   requests from those countries are accepted.
+     * - TUNNEL_DENIED_COUNTRIES : Comma separated list of ISO country codes whose requests are
+     *   rejected.
+     * - TUNNEL_ALLOWED_REGIONS : Comma separated list of sentry.io regions, `us` or `de` for
+     *   instance. When set, envelopes for other sentry.io regions are rejected.
+     * - TUNNEL_DENIED_REGIONS : Comma separated list of sentry.io regions whose envelopes are
+     *   rejected.
+     * - TUNNEL_REGION_PROXIES : Comma separated list of `region:proxy_url` pairs. Envelopes for
+     *   the sentry.io region are forwarded through its proxy.
+     * - TUNNEL_CLIENT_IP_HEADER : Optional header holding the client address when the tunnel
+     *   runs behind a proxy, `X-Forwarded-For` for instance. The first address is used.
+     * - TUNNEL_MAX_REPLAY_RECORDING_SIZE : Optional size in bytes above which `replay_recording`
+     *   items are removed from envelopes before they are forwarded. Disabled by default.
+     * - TUNNEL_SPAM_WINDOW : Optional window in seconds during which identical events sent by a
+     *   client are collapsed. Disabled by default.
+     * - TUNNEL_SPAM_LIMIT : Number of identical events forwarded per window, 10 by default.
+     * - TUNNEL_IDEMPOTENCY_WINDOW : Optional window in seconds during which retries of an envelope
+     *   get the response of the first submission instead of being forwarded again. Disabled by
+     *   default.
+     * - TUNNEL_AUTH_TOKENS : Comma separated list of `token` or `token@expiry` entries. When set,
+     *   requests must present one of the tokens that is not expired in their `Authorization`
+     *   header. Expiries are RFC 3339 dates or unix timestamps.
+     * - TUNNEL_TOKEN_PROJECTS : Comma separated list of `token:project_id|project_id` pairs
+     *   restricting the projects a token can submit to. Other tokens are not restricted.
+     * - TUNNEL_BASIC_AUTH : Comma separated list of `username:password` pairs. When set, requests
+     *   can authenticate with one of them in a `Authorization: Basic` header instead of a token.
+     * - TUNNEL_HONEYPOT_PATHS : Comma separated list of decoy url paths. Clients requesting them
+     *   are logged and banned.
+     * - TUNNEL_BAN_DURATION : Duration of bans in seconds, 3600 by default. 0 only logs clients.
+     * - TUNNEL_AUDIT_RULES : Comma separated list of rules (user_agent, country, banned,
+     *   content_type, duplicate, sdk) that flag the envelopes breaking them instead of dropping
+     *   them.
+     * - TUNNEL_VAULT_ADDR : Optional address of a Vault server the auth tokens, signing secrets and
+     *   TLS certificate are read from at startup, in addition to the ones configured here.
+     * - TUNNEL_VAULT_SECRET_PATH : API path of the secret holding them, required by Vault.
+     * - TUNNEL_VAULT_ROLE : Role of the Kubernetes auth method used to log in to Vault.
+     * - TUNNEL_VAULT_TOKEN : Vault token used when no role is configured.
+     * - TUNNEL_SENTRY_ORG : Optional slug of the Sentry organization whose projects are listed
+     *   through the Sentry API, their envelopes are accepted in addition to TUNNEL_PROJECT_IDS.
+     * - TUNNEL_SENTRY_API_TOKEN : Auth token with the `project:read` scope, required to list them.
+     * - TUNNEL_SENTRY_API_URL : Url of the Sentry server listing them, https://sentry.io by
+     *   default.
+     * - TUNNEL_PROJECT_DISCOVERY_INTERVAL : Seconds between two listings, 300 by default.
+     * - TUNNEL_CONFIG_DIR : Optional directory whose files, named after the reloadable variables,
+     *   override them. They are watched and reloaded when they change.
+     * - TUNNEL_ADMIN_TOKENS : Comma separated list of `token` or `token@expiry` entries giving
+     *   access to the admin endpoints, which are disabled when it is not set.
+     * - TUNNEL_TOGGLES_PATH : Optional file where the filters toggled through the admin endpoints
+     *   are persisted.
+     * - TUNNEL_ALLOWLIST_PATH : Optional file where the projects and hosts added or removed
+     *   through the admin endpoints are persisted.
+     * - TUNNEL_STATS_PATH : Optional file where per project counters are persisted, so that they
+     *   survive restarts. Disabled by default.
+     * - TUNNEL_READINESS_UPSTREAM_CHECK : Report the tunnel as not ready on `/readyz` while none
+     *   of the remote hosts can be reached. Disabled by default.
+     * - TUNNEL_ACCESS_LOG : Write a line to stdout for every request, whatever the log level.
+     *   Disabled by default.
+     * - TUNNEL_TRACING_ENDPOINT : Optional url of an OTLP/gRPC collector the spans of the requests
+     *   are exported to. Requires the `otel` feature.
+     * - TUNNEL_CORS_ALLOWED_ORIGINS : Comma separated list of origins, `https://app.example.com`
+     *   for instance, allowed to post envelopes from a browser. `*` allows any. Disabled by
+     *   default.
+     * - TUNNEL_CORS_ALLOWED_HEADERS : Comma separated list of the request headers allowed from
+     *   those origins. `Content-Type`, `Content-Encoding` and `Authorization` by default.
+     * - TUNNEL_DRAIN_TIMEOUT : Seconds the requests in progress and the queued envelopes have to
+     *   be forwarded on SIGTERM or SIGINT, 25 by default.
      */
     pub fn new_from_env_variables() -> Result<Config, String> {
+        let (config, errors) = Config::read_env_variables()?;
+        config.finish_reporting(errors)
+    }
+
+    /**
+     * The configuration of the env variables, not validated yet, along with the errors found on
+     * the variables
+     */
+    fn read_env_variables() -> Result<(Config, Vec<String>), String> {
         let mut options = ListOptions::new();
         options.separator = Some(",".to_string());
-        let remote_hosts  = envmnt::get_list_with_options("TUNNEL_REMOTE_HOST", &options).ok_or_else(|| "Missing sentry remote. Please set the environnement variable 'TUNNEL_REMOTE_HOST' to specify the sentry remote.".to_string())?;
-        let project_ids = envmnt::get_list_with_options("TUNNEL_PROJECT_IDS", &options)
-            .ok_or_else(|| {
-                "Project ID unspecified. Use 'export TUNNEL_PROJECT_IDS' to provide valid ids."
-                    .to_string()
-            })?;
-        let port = envmnt::get_u16("TUNNEL_LISTEN_PORT", 7878);
+        // Errors on the variables are reported along with the ones found by `validate`
+        let mut errors = vec![];
+        let remote_hosts = Config::clean_remote_hosts(
+            &envmnt::get_list_with_options("TUNNEL_REMOTE_HOST", &options).unwrap_or_default(),
+        )
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            vec![]
+        });
+        let (project_ids, allow_all_projects) = Config::parse_project_ids(
+            &envmnt::get_list_with_options("TUNNEL_PROJECT_IDS", &options).unwrap_or_default(),
+        )
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            (vec![], false)
+        });
+        let port = match envmnt::get_or("TUNNEL_LISTEN_PORT", "7878").trim().parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                errors.push(format!(
+                    "Invalid 'TUNNEL_LISTEN_PORT', expected a number from 0 to 65535 : {}",
+                    envmnt::get_or("TUNNEL_LISTEN_PORT", "")
+                ));
+                7878
+            }
+        };
         let tunnel_path: String =
             envmnt::get_parse("TUNNEL_PATH").unwrap_or_else(|_| "/tunnel".to_string());
+        let extra_paths = envmnt::get_list_with_options("TUNNEL_EXTRA_PATHS", &options)
+            .map(|paths| {
+                paths
+                    .iter()
+                    .map(|path| path.trim().to_string())
+                    .filter(|path| !path.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let path_projects = Config::parse_project_lists(
+            "TUNNEL_PATH_PROJECTS",
+            &envmnt::get_list_with_options("TUNNEL_PATH_PROJECTS", &options).unwrap_or_default(),
+        )
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            HashMap::new()
+        });
+        let path_remote_hosts = Config::parse_path_remote_hosts(
+            &envmnt::get_list_with_options("TUNNEL_PATH_REMOTE_HOSTS", &options)
+                .unwrap_or_default(),
+        )
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            HashMap::new()
+        });
         let ip: String = envmnt::get_parse("TUNNEL_IP").unwrap_or_else(|_| "127.0.0.1".to_string());
-        let valid_remote_hosts = Config::clean_remote_hosts(&remote_hosts);
-        if valid_remote_hosts.len() == 0 {
-            Err("No remote hosts to forward sentry envelopes to".to_string())
-        } else {
-            Ok(Config {
-                remote_hosts : valid_remote_hosts,
-                project_ids,
-                port,
-                tunnel_path,
-                ip,
+        let session_aggregation_window =
+            env_number::<u64>(&mut errors, "TUNNEL_SESSION_AGGREGATION_WINDOW")
+                .filter(|window| *window != 0);
+        let streaming_threshold: Option<u64> =
+            env_number(&mut errors, "TUNNEL_STREAMING_THRESHOLD");
+        let max_body_size =
+            env_number::<u64>(&mut errors, "TUNNEL_MAX_BODY_SIZE").filter(|size| *size != 0);
+        let max_attachment_size =
+            env_number(&mut errors, "TUNNEL_MAX_ATTACHMENT_SIZE").unwrap_or(100_000_000);
+        let buffer_pool_size = env_number(&mut errors, "TUNNEL_BUFFER_POOL_SIZE").unwrap_or(16);
+        let max_in_flight: Option<usize> = env_number(&mut errors, "TUNNEL_MAX_IN_FLIGHT");
+        let max_buffered_bytes: Option<u64> = env_number(&mut errors, "TUNNEL_MAX_BUFFERED_BYTES");
+        let upstream_rate =
+            env_number::<u64>(&mut errors, "TUNNEL_UPSTREAM_RATE").filter(|rate| *rate != 0);
+        let upstream_max_delay =
+            env_number(&mut errors, "TUNNEL_UPSTREAM_MAX_DELAY").unwrap_or(5000);
+        let upstream_max_connections =
+            env_number(&mut errors, "TUNNEL_UPSTREAM_MAX_CONNECTIONS").unwrap_or(0);
+        let upstream_idle_connections =
+            env_number(&mut errors, "TUNNEL_UPSTREAM_IDLE_CONNECTIONS").unwrap_or(0);
+        let upstream_encodings = reported(
+            &mut errors,
+            envmnt::get_list_with_options("TUNNEL_UPSTREAM_ENCODINGS", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|name| Encoding::from_str(name))
+                .collect::<Result<Vec<Encoding>, String>>(),
+        );
+        let compression_threshold =
+            env_number(&mut errors, "TUNNEL_COMPRESSION_THRESHOLD").unwrap_or(1024);
+        let upload_timeout =
+            env_number::<u64>(&mut errors, "TUNNEL_UPLOAD_TIMEOUT").filter(|timeout| *timeout != 0);
+        let relay_responses = envmnt::is_or("TUNNEL_RELAY_RESPONSES", false);
+        let rate_limit_cache = envmnt::is_or("TUNNEL_RATE_LIMIT_CACHE", false);
+        let forward_retries = env_number(&mut errors, "TUNNEL_FORWARD_RETRIES").unwrap_or(0);
+        let retry_base_delay = env_number(&mut errors, "TUNNEL_RETRY_BASE_DELAY").unwrap_or(100);
+        let retry_max_delay = env_number(&mut errors, "TUNNEL_RETRY_MAX_DELAY").unwrap_or(5000);
+        let spool_dir: Option<String> = envmnt::get_parse("TUNNEL_SPOOL_DIR").ok();
+        let spool_max_size =
+            env_number(&mut errors, "TUNNEL_SPOOL_MAX_SIZE").unwrap_or(100_000_000);
+        let spool_max_age = env_number(&mut errors, "TUNNEL_SPOOL_MAX_AGE").unwrap_or(86400);
+        let async_forwarding = envmnt::is_or("TUNNEL_ASYNC_FORWARDING", false);
+        let queue_size = env_number(&mut errors, "TUNNEL_QUEUE_SIZE").unwrap_or(1000);
+        let queue_workers = env_number(&mut errors, "TUNNEL_QUEUE_WORKERS").unwrap_or(4);
+        let processing_budget: Option<u64> = env_number(&mut errors, "TUNNEL_PROCESSING_BUDGET");
+        let spill_threshold: Option<u64> = env_number(&mut errors, "TUNNEL_SPILL_THRESHOLD");
+        let spill_dir: Option<String> = envmnt::get_parse("TUNNEL_SPILL_DIR").ok();
+        let strict_items = envmnt::is_or("TUNNEL_STRICT_ITEMS", false);
+        let silent_drop = envmnt::is_or("TUNNEL_SILENT_DROP", false);
+        let allowed_items = envmnt::get_list_with_options("TUNNEL_ALLOWED_ITEMS", &options)
+            .map(|items| items.iter().map(|item| item.trim().to_string()).collect())
+            .unwrap_or_else(Config::known_item_types);
+        let allowed_content_types =
+            envmnt::get_list_with_options("TUNNEL_ALLOWED_CONTENT_TYPES", &options)
+                .map(|content_types| {
+                    content_types
+                        .iter()
+                        .map(|content_type| content_type.trim().to_lowercase())
+                        .filter(|content_type| !content_type.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(Config::envelope_content_types);
+        let otlp_path: Option<String> = envmnt::get_parse("TUNNEL_OTLP_PATH").ok();
+        let otlp_dsn = reported(
+            &mut errors,
+            match envmnt::get_or("TUNNEL_OTLP_DSN", "").as_str() {
+                "" => Ok(None),
+                dsn => Dsn::from_str(dsn)
+                    .map(Some)
+                    .map_err(|e| format!("Invalid 'TUNNEL_OTLP_DSN' : {}", e)),
+            },
+        );
+        let h3_port: Option<u16> = env_number(&mut errors, "TUNNEL_H3_PORT");
+        let tls_port: Option<u16> = env_number(&mut errors, "TUNNEL_TLS_PORT");
+        let tls_cert_path: Option<String> = envmnt::get_parse("TUNNEL_TLS_CERT_PATH").ok();
+        let tls_key_path: Option<String> = envmnt::get_parse("TUNNEL_TLS_KEY_PATH").ok();
+        let acme_domains = envmnt::get_list_with_options("TUNNEL_ACME_DOMAINS", &options)
+            .map(|domains| {
+                domains
+                    .iter()
+                    .map(|domain| domain.trim().to_lowercase())
+                    .filter(|domain| !domain.is_empty())
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        let acme_email: Option<String> = envmnt::get_parse("TUNNEL_ACME_EMAIL").ok();
+        let acme_directory = envmnt::get_or("TUNNEL_ACME_DIRECTORY", LETS_ENCRYPT_DIRECTORY);
+        let acme_dir = envmnt::get_or("TUNNEL_ACME_DIR", "acme");
+        let tls_client_ca_path: Option<String> = envmnt::get_parse("TUNNEL_TLS_CLIENT_CA_PATH").ok();
+        let client_cert_projects = reported(
+            &mut errors,
+            Config::parse_project_lists(
+                "TUNNEL_CLIENT_CERT_PROJECTS",
+                &envmnt::get_list_with_options("TUNNEL_CLIENT_CERT_PROJECTS", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        let grpc = envmnt::is_or("TUNNEL_GRPC", false);
+        let websocket_path: Option<String> = envmnt::get_parse("TUNNEL_WEBSOCKET_PATH").ok();
+        let signing_secrets = reported(
+            &mut errors,
+            Config::parse_signing_secrets(
+                &envmnt::get_list_with_options("TUNNEL_SIGNING_SECRETS", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        let daily_quotas = reported(
+            &mut errors,
+            Config::parse_quotas(
+                "TUNNEL_DAILY_QUOTAS",
+                &envmnt::get_list_with_options("TUNNEL_DAILY_QUOTAS", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        let monthly_quotas = reported(
+            &mut errors,
+            Config::parse_quotas(
+                "TUNNEL_MONTHLY_QUOTAS",
+                &envmnt::get_list_with_options("TUNNEL_MONTHLY_QUOTAS", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        let canary_routes = reported(
+            &mut errors,
+            Config::parse_canary_routes(
+                &envmnt::get_list_with_options("TUNNEL_CANARY", &options).unwrap_or_default(),
+            ),
+        );
+        let project_upstreams = reported(
+            &mut errors,
+            Config::parse_project_upstreams(
+                &envmnt::get_list_with_options("TUNNEL_PROJECT_UPSTREAMS", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        let filter_bots = envmnt::is_or("TUNNEL_FILTER_BOTS", false);
+        let denied_user_agents = envmnt::get_list_with_options("TUNNEL_DENIED_USER_AGENTS", &options)
+            .map(|fragments| {
+                fragments
+                    .iter()
+                    .map(|fragment| fragment.trim().to_lowercase())
+                    .filter(|fragment| !fragment.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let allowed_sdks = reported(
+            &mut errors,
+            envmnt::get_list_with_options("TUNNEL_ALLOWED_SDKS", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| SdkRule::from_str(entry))
+                .collect::<Result<Vec<SdkRule>, String>>(),
+        );
+        let country_list = |variable: &str| -> Vec<String> {
+            envmnt::get_list_with_options(variable, &options)
+                .map(|countries| {
+                    countries
+                        .iter()
+                        .map(|country| country.trim().to_uppercase())
+                        .filter(|country| !country.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let allowed_countries = country_list("TUNNEL_ALLOWED_COUNTRIES");
+        let denied_countries = country_list("TUNNEL_DENIED_COUNTRIES");
+        let region_list = |variable: &str| -> Vec<String> {
+            envmnt::get_list_with_options(variable, &options)
+                .map(|regions| {
+                    regions
+                        .iter()
+                        .map(|region| region.trim().to_lowercase())
+                        .filter(|region| !region.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let allowed_regions = region_list("TUNNEL_ALLOWED_REGIONS");
+        let denied_regions = region_list("TUNNEL_DENIED_REGIONS");
+        let region_proxies = reported(
+            &mut errors,
+            Config::parse_region_proxies(
+                &envmnt::get_list_with_options("TUNNEL_REGION_PROXIES", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        let geoip = reported(
+            &mut errors,
+            match envmnt::get_or("TUNNEL_GEOIP_DATABASE", "").as_str() {
+                "" => Ok(None),
+                path => GeoIp::open(path)
+                    .map(|geoip| Some(Arc::new(geoip)))
+                    .map_err(|e| format!("Could not open the GeoIP database {} : {}", path, e)),
+            },
+        );
+        let client_ip_header: Option<String> = envmnt::get_parse("TUNNEL_CLIENT_IP_HEADER").ok();
+        let max_replay_recording_size: Option<u64> =
+            env_number(&mut errors, "TUNNEL_MAX_REPLAY_RECORDING_SIZE");
+        let spam_window =
+            env_number::<u64>(&mut errors, "TUNNEL_SPAM_WINDOW").filter(|window| *window != 0);
+        let spam_limit = env_number(&mut errors, "TUNNEL_SPAM_LIMIT").unwrap_or(10);
+        let idempotency_window = env_number::<u64>(&mut errors, "TUNNEL_IDEMPOTENCY_WINDOW")
+            .filter(|window| *window != 0);
+        let honeypot_paths = envmnt::get_list_with_options("TUNNEL_HONEYPOT_PATHS", &options)
+            .map(|paths| {
+                paths
+                    .iter()
+                    .map(|path| path.trim().to_string())
+                    .filter(|path| !path.is_empty())
+                    .collect()
             })
+            .unwrap_or_default();
+        let ban_duration = env_number(&mut errors, "TUNNEL_BAN_DURATION").unwrap_or(3600);
+        let mut auth_tokens = reported(
+            &mut errors,
+            envmnt::get_list_with_options("TUNNEL_AUTH_TOKENS", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| AuthToken::from_str(entry))
+                .collect::<Result<Vec<AuthToken>, String>>(),
+        );
+        let token_projects = reported(
+            &mut errors,
+            Config::parse_project_lists(
+                "TUNNEL_TOKEN_PROJECTS",
+                &envmnt::get_list_with_options("TUNNEL_TOKEN_PROJECTS", &options)
+                    .unwrap_or_default(),
+            ),
+        );
+        reported(
+            &mut errors,
+            Config::bind_token_projects(&mut auth_tokens, token_projects, "TUNNEL_TOKEN_PROJECTS"),
+        );
+        for token in auth_tokens.iter().filter(|token| token.is_expired()) {
+            warn!(
+                "An auth token expired on {}, it can be removed from 'TUNNEL_AUTH_TOKENS'",
+                token.expires.unwrap_or_default()
+            );
         }
+        let basic_credentials = reported(
+            &mut errors,
+            envmnt::get_list_with_options("TUNNEL_BASIC_AUTH", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| BasicCredentials::from_str(entry))
+                .collect::<Result<Vec<BasicCredentials>, String>>(),
+        );
+        let audited_rules = envmnt::get_list_with_options("TUNNEL_AUDIT_RULES", &options)
+            .unwrap_or_default()
+            .iter()
+            .map(|rule| rule.trim().to_lowercase())
+            .filter(|rule| !rule.is_empty())
+            .collect::<Vec<String>>();
+        let vault = reported(
+            &mut errors,
+            match envmnt::get_or("TUNNEL_VAULT_ADDR", "").as_str() {
+                "" => Ok(None),
+                address => envmnt::get_parse("TUNNEL_VAULT_SECRET_PATH")
+                    .map(|secret_path| {
+                        Some(VaultConfig {
+                            address: address.to_string(),
+                            secret_path,
+                            role: envmnt::get_parse("TUNNEL_VAULT_ROLE").ok(),
+                            token: envmnt::get_parse("TUNNEL_VAULT_TOKEN").ok(),
+                        })
+                    })
+                    .map_err(|_| {
+                        "'TUNNEL_VAULT_ADDR' requires 'TUNNEL_VAULT_SECRET_PATH'".to_string()
+                    }),
+            },
+        );
+        let discovery_interval =
+            env_number(&mut errors, "TUNNEL_PROJECT_DISCOVERY_INTERVAL").unwrap_or(300);
+        let discovery = reported(
+            &mut errors,
+            match envmnt::get_or("TUNNEL_SENTRY_ORG", "").as_str() {
+                "" => Ok(None),
+                organization => envmnt::get_parse("TUNNEL_SENTRY_API_TOKEN")
+                    .map(|token| {
+                        Some(DiscoveryConfig {
+                            api_url: envmnt::get_or("TUNNEL_SENTRY_API_URL", "https://sentry.io"),
+                            organization: organization.to_string(),
+                            token,
+                            interval: discovery_interval,
+                        })
+                    })
+                    .map_err(|_| {
+                        "'TUNNEL_SENTRY_ORG' requires 'TUNNEL_SENTRY_API_TOKEN'".to_string()
+                    }),
+            },
+        );
+        let config_dir: Option<String> = envmnt::get_parse("TUNNEL_CONFIG_DIR").ok();
+        let admin_tokens = reported(
+            &mut errors,
+            envmnt::get_list_with_options("TUNNEL_ADMIN_TOKENS", &options)
+                .unwrap_or_default()
+                .iter()
+                .map(|entry| AuthToken::from_str(entry))
+                .collect::<Result<Vec<AuthToken>, String>>(),
+        );
+        let toggles_path: Option<String> = envmnt::get_parse("TUNNEL_TOGGLES_PATH").ok();
+        let allowlist_path: Option<String> = envmnt::get_parse("TUNNEL_ALLOWLIST_PATH").ok();
+        let stats_path: Option<String> = envmnt::get_parse("TUNNEL_STATS_PATH").ok();
+        let readiness_upstream_check = envmnt::is_or("TUNNEL_READINESS_UPSTREAM_CHECK", false);
+        let access_log = envmnt::is_or("TUNNEL_ACCESS_LOG", false);
+        let tracing_endpoint: Option<String> = envmnt::get_parse("TUNNEL_TRACING_ENDPOINT").ok();
+        let cors_allowed_origins =
+            envmnt::get_list_with_options("TUNNEL_CORS_ALLOWED_ORIGINS", &options)
+                .unwrap_or_default();
+        let drain_timeout = env_number(&mut errors, "TUNNEL_DRAIN_TIMEOUT").unwrap_or(25);
+        let cors_allowed_headers =
+            envmnt::get_list_with_options("TUNNEL_CORS_ALLOWED_HEADERS", &options)
+                .map(|headers| {
+                    headers
+                        .iter()
+                        .map(|header| header.trim().to_string())
+                        .filter(|header| !header.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(Config::cors_default_headers);
+        let config = Config {
+            remote_hosts,
+            project_ids,
+            allow_all_projects,
+            port,
+            tunnel_path,
+            extra_paths,
+            path_projects,
+            path_remote_hosts,
+            ip,
+            session_aggregation_window,
+            streaming_threshold,
+            max_body_size,
+            max_attachment_size,
+            buffer_pool_size,
+            max_in_flight,
+            max_buffered_bytes,
+            upstream_rate,
+            upstream_max_delay,
+            upstream_max_connections,
+            upstream_idle_connections,
+            upstream_encodings,
+            compression_threshold,
+            upload_timeout,
+            relay_responses,
+            rate_limit_cache,
+            forward_retries,
+            retry_base_delay,
+            retry_max_delay,
+            spool_dir,
+            spool_max_size,
+            spool_max_age,
+            async_forwarding,
+            queue_size,
+            queue_workers,
+            processing_budget,
+            spill_threshold,
+            spill_dir,
+            strict_items,
+            silent_drop,
+            allowed_items,
+            allowed_content_types,
+            otlp_path,
+            otlp_dsn,
+            h3_port,
+            tls_port,
+            tls_cert_path,
+            tls_key_path,
+            tls_cert_pem: None,
+            tls_key_pem: None,
+            acme_domains,
+            acme_email,
+            acme_directory,
+            acme_dir,
+            acme_challenges: Arc::new(Challenges::default()),
+            grpc,
+            websocket_path,
+            signing_secrets,
+            daily_quotas,
+            monthly_quotas,
+            canary_routes,
+            project_upstreams,
+            filter_bots,
+            denied_user_agents,
+            allowed_sdks,
+            geoip,
+            allowed_countries,
+            denied_countries,
+            allowed_regions,
+            denied_regions,
+            region_proxies,
+            client_ip_header,
+            max_replay_recording_size,
+            spam_window,
+            spam_limit,
+            idempotency_window,
+            honeypot_paths,
+            ban_duration,
+            tls_client_ca_path,
+            client_cert_projects,
+            auth_tokens,
+            basic_credentials,
+            audited_rules,
+            vault,
+            discovery,
+            config_dir,
+            admin_tokens,
+            toggles_path,
+            allowlist_path,
+            stats_path,
+            readiness_upstream_check,
+            access_log,
+            tracing_endpoint,
+            cors_allowed_origins,
+            cors_allowed_headers,
+            drain_timeout,
+        };
+        Ok((config, errors))
     }
 
-    pub fn project_id_is_allowed(&self, id: u64) -> bool {
-        let id_str = format!("{}", id);
-        self.project_ids.contains(&id_str)
+    /**
+     * Read the configuration from a JSON, TOML or YAML file, after its extension, whose fields are
+     * named after the fields of `Config`, at the top level or in the sections of `FILE_SECTIONS`.
+     * Values parsed from env variables, like tokens or dsns, use the same format.
+     */
+    pub fn new_from_file(path: &str) -> Result<Config, String> {
+        Config::read_file(path)?.finish()
+    }
+
+    /**
+     * Read the configuration from a file, then replace its values with the ones of the env
+     * variables that are set
+     */
+    pub fn new_from_file_and_env_variables(path: &str) -> Result<Config, String> {
+        let mut config = Config::read_file(path)?;
+        let (env, errors) = Config::read_env_variables()?;
+        config.override_with(env);
+        config.finish_reporting(errors)
+    }
+
+    fn read_file(path: &str) -> Result<Config, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Could not read {} : {}", path, e))?;
+        let invalid = |e: String| format!("Invalid configuration file {} : {}", path, e);
+        let value: serde_json::Value = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+            _ => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        }
+        .map_err(invalid)?;
+        let value = flatten_sections(value).map_err(invalid)?;
+        serde_json::from_value(value).map_err(|e| invalid(e.to_string()))
+    }
+
+    /**
+     * Replace the values of the configuration with the ones of `env` whose variable is set
+     */
+    fn override_with(&mut self, env: Config) {
+        let is_set = |variable: &str| !envmnt::get_or(variable, "").trim().is_empty();
+        macro_rules! override_fields {
+            ($($variable:literal => $field:ident,)*) => {
+                $(
+                    if is_set($variable) {
+                        self.$field = env.$field;
+                    }
+                )*
+            };
+        }
+        override_fields! {
+            "TUNNEL_REMOTE_HOST" => remote_hosts,
+            "TUNNEL_PROJECT_IDS" => project_ids,
+            "TUNNEL_PROJECT_IDS" => allow_all_projects,
+            "TUNNEL_LISTEN_PORT" => port,
+            "TUNNEL_PATH" => tunnel_path,
+            "TUNNEL_EXTRA_PATHS" => extra_paths,
+            "TUNNEL_PATH_PROJECTS" => path_projects,
+            "TUNNEL_PATH_REMOTE_HOSTS" => path_remote_hosts,
+            "TUNNEL_IP" => ip,
+            "TUNNEL_SESSION_AGGREGATION_WINDOW" => session_aggregation_window,
+            "TUNNEL_STREAMING_THRESHOLD" => streaming_threshold,
+            "TUNNEL_MAX_BODY_SIZE" => max_body_size,
+            "TUNNEL_MAX_ATTACHMENT_SIZE" => max_attachment_size,
+            "TUNNEL_BUFFER_POOL_SIZE" => buffer_pool_size,
+            "TUNNEL_MAX_IN_FLIGHT" => max_in_flight,
+            "TUNNEL_MAX_BUFFERED_BYTES" => max_buffered_bytes,
+            "TUNNEL_UPSTREAM_RATE" => upstream_rate,
+            "TUNNEL_UPSTREAM_MAX_DELAY" => upstream_max_delay,
+            "TUNNEL_UPSTREAM_MAX_CONNECTIONS" => upstream_max_connections,
+            "TUNNEL_UPSTREAM_IDLE_CONNECTIONS" => upstream_idle_connections,
+            "TUNNEL_UPSTREAM_ENCODINGS" => upstream_encodings,
+            "TUNNEL_COMPRESSION_THRESHOLD" => compression_threshold,
+            "TUNNEL_UPLOAD_TIMEOUT" => upload_timeout,
+            "TUNNEL_RELAY_RESPONSES" => relay_responses,
+            "TUNNEL_RATE_LIMIT_CACHE" => rate_limit_cache,
+            "TUNNEL_FORWARD_RETRIES" => forward_retries,
+            "TUNNEL_RETRY_BASE_DELAY" => retry_base_delay,
+            "TUNNEL_RETRY_MAX_DELAY" => retry_max_delay,
+            "TUNNEL_SPOOL_DIR" => spool_dir,
+            "TUNNEL_SPOOL_MAX_SIZE" => spool_max_size,
+            "TUNNEL_SPOOL_MAX_AGE" => spool_max_age,
+            "TUNNEL_ASYNC_FORWARDING" => async_forwarding,
+            "TUNNEL_QUEUE_SIZE" => queue_size,
+            "TUNNEL_QUEUE_WORKERS" => queue_workers,
+            "TUNNEL_PROCESSING_BUDGET" => processing_budget,
+            "TUNNEL_SPILL_THRESHOLD" => spill_threshold,
+            "TUNNEL_SPILL_DIR" => spill_dir,
+            "TUNNEL_STRICT_ITEMS" => strict_items,
+            "TUNNEL_SILENT_DROP" => silent_drop,
+            "TUNNEL_ALLOWED_ITEMS" => allowed_items,
+            "TUNNEL_ALLOWED_CONTENT_TYPES" => allowed_content_types,
+            "TUNNEL_OTLP_PATH" => otlp_path,
+            "TUNNEL_OTLP_DSN" => otlp_dsn,
+            "TUNNEL_H3_PORT" => h3_port,
+            "TUNNEL_TLS_PORT" => tls_port,
+            "TUNNEL_TLS_CERT_PATH" => tls_cert_path,
+            "TUNNEL_TLS_KEY_PATH" => tls_key_path,
+            "TUNNEL_ACME_DOMAINS" => acme_domains,
+            "TUNNEL_ACME_EMAIL" => acme_email,
+            "TUNNEL_ACME_DIRECTORY" => acme_directory,
+            "TUNNEL_ACME_DIR" => acme_dir,
+            "TUNNEL_TLS_CLIENT_CA_PATH" => tls_client_ca_path,
+            "TUNNEL_CLIENT_CERT_PROJECTS" => client_cert_projects,
+            "TUNNEL_GRPC" => grpc,
+            "TUNNEL_WEBSOCKET_PATH" => websocket_path,
+            "TUNNEL_SIGNING_SECRETS" => signing_secrets,
+            "TUNNEL_DAILY_QUOTAS" => daily_quotas,
+            "TUNNEL_MONTHLY_QUOTAS" => monthly_quotas,
+            "TUNNEL_CANARY" => canary_routes,
+            "TUNNEL_PROJECT_UPSTREAMS" => project_upstreams,
+            "TUNNEL_FILTER_BOTS" => filter_bots,
+            "TUNNEL_DENIED_USER_AGENTS" => denied_user_agents,
+            "TUNNEL_ALLOWED_SDKS" => allowed_sdks,
+            "TUNNEL_ALLOWED_COUNTRIES" => allowed_countries,
+            "TUNNEL_DENIED_COUNTRIES" => denied_countries,
+            "TUNNEL_ALLOWED_REGIONS" => allowed_regions,
+            "TUNNEL_DENIED_REGIONS" => denied_regions,
+            "TUNNEL_REGION_PROXIES" => region_proxies,
+            "TUNNEL_GEOIP_DATABASE" => geoip,
+            "TUNNEL_CLIENT_IP_HEADER" => client_ip_header,
+            "TUNNEL_MAX_REPLAY_RECORDING_SIZE" => max_replay_recording_size,
+            "TUNNEL_SPAM_WINDOW" => spam_window,
+            "TUNNEL_SPAM_LIMIT" => spam_limit,
+            "TUNNEL_IDEMPOTENCY_WINDOW" => idempotency_window,
+            "TUNNEL_HONEYPOT_PATHS" => honeypot_paths,
+            "TUNNEL_BAN_DURATION" => ban_duration,
+            // Along with the projects of TUNNEL_TOKEN_PROJECTS, that are bound to them
+            "TUNNEL_AUTH_TOKENS" => auth_tokens,
+            "TUNNEL_BASIC_AUTH" => basic_credentials,
+            "TUNNEL_AUDIT_RULES" => audited_rules,
+            "TUNNEL_VAULT_ADDR" => vault,
+            "TUNNEL_SENTRY_ORG" => discovery,
+            "TUNNEL_CONFIG_DIR" => config_dir,
+            "TUNNEL_ADMIN_TOKENS" => admin_tokens,
+            "TUNNEL_TOGGLES_PATH" => toggles_path,
+            "TUNNEL_ALLOWLIST_PATH" => allowlist_path,
+            "TUNNEL_STATS_PATH" => stats_path,
+            "TUNNEL_READINESS_UPSTREAM_CHECK" => readiness_upstream_check,
+            "TUNNEL_ACCESS_LOG" => access_log,
+            "TUNNEL_TRACING_ENDPOINT" => tracing_endpoint,
+            "TUNNEL_CORS_ALLOWED_ORIGINS" => cors_allowed_origins,
+            "TUNNEL_CORS_ALLOWED_HEADERS" => cors_allowed_headers,
+            "TUNNEL_DRAIN_TIMEOUT" => drain_timeout,
+        }
+    }
+
+    /**
+     * The secrets of the configuration, to be redacted from the logs
+     */
+    pub fn secrets(&self) -> Vec<String> {
+        self.auth_tokens
+            .iter()
+            .map(|token| token.token.clone())
+            .chain(self.signing_secrets.values().cloned())
+            .chain(self.basic_credentials.iter().map(|entry| entry.password.clone()))
+            .chain(self.discovery.iter().map(|discovery| discovery.token.clone()))
+            .collect()
+    }
+
+    /**
+     * Carry the runtime state of `current` over to this configuration replacing it, the tasks
+     * started along with the tunnel keep writing into it. The allowlist is only kept while it is
+     * read from the same file.
+     */
+    pub fn keep_runtime_state(&mut self, current: &Config) {
+        self.acme_challenges = current.acme_challenges.clone();
+        self.discovered_projects = current.discovered_projects.clone();
+        if self.allowlist_path == current.allowlist_path {
+            self.allowlist = current.allowlist.clone();
+        }
+    }
+
+    /**
+     * The configuration as pretty printed JSON, with its secrets redacted and its fields sorted
+     */
+    pub fn to_redacted_json(&self) -> String {
+        let value = serde_json::to_value(self).expect("the configuration serializes to JSON");
+        serde_json::to_string_pretty(&value).unwrap()
+    }
+
+    /**
+     * Names of the fields whose value differs in `other`, compared on their serialized value. The
+     * fields serialized without their secrets are compared on their debug output, and the state
+     * shared at runtime, like the ACME challenges, is not compared.
+     */
+    pub fn changed_fields(&self, other: &Config) -> Vec<String> {
+        let serialized = |config: &Config| match serde_json::to_value(config) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+        let (fields, other_fields) = (serialized(self), serialized(other));
+        let mut changed: Vec<String> = fields
+            .iter()
+            .filter(|(name, value)| other_fields.get(name.as_str()) != Some(*value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let secrets = self.secret_fields().into_iter().zip(other.secret_fields());
+        for ((name, value), (_, other_value)) in secrets {
+            if value != other_value && !changed.iter().any(|field| field == name) {
+                changed.push(name.to_string());
+            }
+        }
+        changed.retain(|name| !RUNTIME_FIELDS.contains(&name.as_str()));
+        changed.sort();
+        changed
+    }
+
+    /**
+     * Debug output of the fields whose secrets are redacted or skipped when serialized
+     */
+    fn secret_fields(&self) -> Vec<(&'static str, String)> {
+        let signing_secrets: BTreeMap<_, _> = self.signing_secrets.iter().collect();
+        let canary_routes: BTreeMap<_, _> = self.canary_routes.iter().collect();
+        vec![
+            ("otlp_dsn", format!("{:?}", self.otlp_dsn)),
+            ("tls_key_pem", format!("{:?}", self.tls_key_pem)),
+            ("signing_secrets", format!("{:?}", signing_secrets)),
+            ("canary_routes", format!("{:?}", canary_routes)),
+            ("geoip", format!("{:?}", self.geoip)),
+            ("auth_tokens", format!("{:?}", self.auth_tokens)),
+            ("basic_credentials", format!("{:?}", self.basic_credentials)),
+            ("admin_tokens", format!("{:?}", self.admin_tokens)),
+            ("vault", format!("{:?}", self.vault)),
+            ("discovery", format!("{:?}", self.discovery)),
+        ]
+    }
+
+    /**
+     * Finish the configuration, reporting the problems it has along with `errors`, the ones
+     * found while reading it
+     */
+    fn finish_reporting(self, mut errors: Vec<String>) -> Result<Config, String> {
+        match self.finish() {
+            Ok(config) if errors.is_empty() => Ok(config),
+            Ok(_) => Err(errors.join("\n")),
+            Err(e) => {
+                errors.push(e);
+                Err(errors.join("\n"))
+            }
+        }
+    }
+
+    /**
+     * Fill the values derived from other ones, then validate the configuration
+     */
+    fn finish(mut self) -> Result<Config, String> {
+        if !self.acme_domains.is_empty() {
+            let in_acme_dir =
+                |file: &str| Path::new(&self.acme_dir).join(file).display().to_string();
+            let cert_path = self.tls_cert_path.clone().unwrap_or_else(|| in_acme_dir("cert.pem"));
+            let key_path = self.tls_key_path.clone().unwrap_or_else(|| in_acme_dir("key.pem"));
+            self.tls_cert_path = Some(cert_path);
+            self.tls_key_path = Some(key_path);
+        }
+        if self.allowlist_path.is_some() {
+            self.allowlist = Arc::new(Allowlist::load(self.allowlist_path.clone()));
+        }
+        // Browsers send their origin without a trailing slash, in lowercase
+        self.cors_allowed_origins = self
+            .cors_allowed_origins
+            .iter()
+            .map(|origin| origin.trim().trim_end_matches('/').to_lowercase())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        self.validate()?;
+        Ok(self)
+    }
+
+    /**
+     * Check the values that depend on each other. Every problem found is reported, one per line.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+        if self.remote_hosts.is_empty()
+            && self.project_upstreams.is_empty()
+            && self.path_remote_hosts.is_empty()
+        {
+            errors.push(
+                "No remote hosts to forward envelopes to, set 'TUNNEL_REMOTE_HOST'".to_string(),
+            );
+        }
+        if self.project_ids.is_empty()
+            && !self.allow_all_projects
+            && self.discovery.is_none()
+            && self.path_projects.is_empty()
+        {
+            errors.push(
+                "No valid project ids, set 'TUNNEL_PROJECT_IDS' or 'TUNNEL_SENTRY_ORG'".to_string(),
+            );
+        }
+        if self.ip.parse::<IpAddr>().is_err() {
+            errors.push(format!("Invalid listen address in 'TUNNEL_IP' : {}", self.ip));
+        }
+        let mut paths = vec![
+            ("TUNNEL_PATH", Some(&self.tunnel_path)),
+            ("TUNNEL_OTLP_PATH", self.otlp_path.as_ref()),
+            ("TUNNEL_WEBSOCKET_PATH", self.websocket_path.as_ref()),
+        ];
+        paths.extend(self.extra_paths.iter().map(|path| ("TUNNEL_EXTRA_PATHS", Some(path))));
+        for (variable, path) in paths {
+            if let Some(path) = path {
+                if let Some(problem) = route_problem(path) {
+                    errors.push(format!("'{}' {} : {}", variable, problem, path));
+                }
+            }
+        }
+        let tunnel_paths = self.tunnel_paths();
+        for (i, path) in tunnel_paths.iter().enumerate() {
+            if tunnel_paths[..i].contains(path) {
+                errors.push(format!("The tunnel path {} is listed twice", path));
+            }
+        }
+        let path_variables = [
+            ("TUNNEL_PATH_PROJECTS", self.path_projects.keys().collect::<Vec<_>>()),
+            ("TUNNEL_PATH_REMOTE_HOSTS", self.path_remote_hosts.keys().collect()),
+        ];
+        for (variable, paths) in path_variables {
+            for path in paths.into_iter().filter(|path| !tunnel_paths.contains(*path)) {
+                errors.push(format!("'{}' names {} which is not a tunnel path", variable, path));
+            }
+        }
+        for id in self.path_projects.values().flatten() {
+            if let Err(e) = ProjectId::from_str(id) {
+                errors.push(format!("'TUNNEL_PATH_PROJECTS' : {}", e));
+            }
+        }
+        if self.otlp_path.is_some() && self.otlp_dsn.is_none() {
+            errors.push("An OTLP path is configured but 'TUNNEL_OTLP_DSN' is missing".to_string());
+        }
+        if !self.client_cert_projects.is_empty() && self.tls_client_ca_path.is_none() {
+            errors.push(
+                "'TUNNEL_CLIENT_CERT_PROJECTS' requires 'TUNNEL_TLS_CLIENT_CA_PATH'".to_string(),
+            );
+        }
+        if self.geoip.is_none()
+            && !(self.allowed_countries.is_empty() && self.denied_countries.is_empty())
+        {
+            errors.push("Country lists require 'TUNNEL_GEOIP_DATABASE'".to_string());
+        }
+        if self
+            .vault
+            .as_ref()
+            .is_some_and(|vault| vault.role.is_none() && vault.token.is_none())
+        {
+            errors.push("Vault requires 'TUNNEL_VAULT_ROLE' or 'TUNNEL_VAULT_TOKEN'".to_string());
+        }
+        if self.h3_port.is_some()
+            && self.vault.is_none()
+            && (self.tls_cert_path.is_none() || self.tls_key_path.is_none())
+        {
+            errors.push(
+                "HTTP/3 requires 'TUNNEL_TLS_CERT_PATH' and 'TUNNEL_TLS_KEY_PATH'".to_string(),
+            );
+        }
+        if self.tls_port.is_some()
+            && self.vault.is_none()
+            && (self.tls_cert_path.is_none() || self.tls_key_path.is_none())
+        {
+            errors.push(
+                "'TUNNEL_TLS_PORT' requires 'TUNNEL_TLS_CERT_PATH' and 'TUNNEL_TLS_KEY_PATH'"
+                    .to_string(),
+            );
+        }
+        if self.tls_port.is_some() && self.tls_port == Some(self.port) {
+            errors.push(format!(
+                "'TUNNEL_TLS_PORT' and 'TUNNEL_LISTEN_PORT' must differ : {}",
+                self.port
+            ));
+        }
+        for (region, proxy) in &self.region_proxies {
+            if url::Url::parse(proxy).is_err() {
+                errors.push(format!("Invalid proxy url for the region {} : {}", region, proxy));
+            }
+        }
+        for rule in self
+            .audited_rules
+            .iter()
+            .filter(|rule| !audit::RULES.contains(&rule.as_str()))
+        {
+            errors.push(format!(
+                "Unknown rule in 'TUNNEL_AUDIT_RULES' : {}, expected one of {}",
+                rule,
+                audit::RULES.join(", ")
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /**
+     * Add the secrets read from Vault : `auth_tokens`, `token_projects` and `signing_secrets`,
+     * formatted like the matching variables, and the PEM encoded `tls_cert` and `tls_key`
+     */
+    pub fn apply_vault_secrets(&mut self, secrets: &HashMap<String, String>) -> Result<(), String> {
+        let list = |field: &str| -> Vec<String> {
+            secrets
+                .get(field)
+                .map(|list| {
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|entry| !entry.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        for entry in list("auth_tokens") {
+            self.auth_tokens.push(AuthToken::from_str(&entry)?);
+        }
+        let token_projects = Config::parse_project_lists("token_projects", &list("token_projects"))?;
+        Config::bind_token_projects(&mut self.auth_tokens, token_projects, "token_projects")?;
+        self.signing_secrets
+            .extend(Config::parse_signing_secrets(&list("signing_secrets"))?);
+        if let Some(tls_cert) = secrets.get("tls_cert") {
+            self.tls_cert_pem = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = secrets.get("tls_key") {
+            self.tls_key_pem = Some(tls_key.clone());
+        }
+        if (self.h3_port.is_some() || self.tls_port.is_some())
+            && (self.tls_cert_path.is_none() && self.tls_cert_pem.is_none()
+                || self.tls_key_path.is_none() && self.tls_key_pem.is_none())
+        {
+            return Err(
+                "TLS listeners require a TLS certificate and key, from Vault or 'TUNNEL_TLS_CERT_PATH' and 'TUNNEL_TLS_KEY_PATH'"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /**
+     * Override the reloadable variables with the files of the directory named after them, holding
+     * comma or line separated values. Tokens replaced by the file keep their projects.
+     */
+    pub fn with_files(&self, dir: &str) -> Result<Config, String> {
+        let read = |variable: &str| -> Result<Option<Vec<String>>, String> {
+            match fs::read_to_string(Path::new(dir).join(variable)) {
+                Ok(content) => Ok(Some(
+                    content
+                        .split(|c: char| c == ',' || c == '\n')
+                        .map(str::trim)
+                        .filter(|value| !value.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                )),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("Could not read {}/{} : {}", dir, variable, e)),
+            }
+        };
+        let mut config = self.clone();
+        if let Some(remote_hosts) = read("TUNNEL_REMOTE_HOST")? {
+            config.remote_hosts = Config::clean_remote_hosts(&remote_hosts)?;
+            if config.remote_hosts.is_empty() {
+                return Err("No remote hosts to forward sentry envelopes to".to_string());
+            }
+        }
+        if let Some(project_ids) = read("TUNNEL_PROJECT_IDS")? {
+            (config.project_ids, config.allow_all_projects) =
+                Config::parse_project_ids(&project_ids)?;
+        }
+        if let Some(entries) = read("TUNNEL_AUTH_TOKENS")? {
+            config.auth_tokens = vec![];
+            for entry in entries {
+                let mut token = AuthToken::from_str(&entry)?;
+                token.projects = self
+                    .auth_tokens
+                    .iter()
+                    .find(|previous| previous.token == token.token)
+                    .and_then(|previous| previous.projects.clone());
+                config.auth_tokens.push(token);
+            }
+        }
+        if let Some(pairs) = read("TUNNEL_TOKEN_PROJECTS")? {
+            let token_projects = Config::parse_project_lists("TUNNEL_TOKEN_PROJECTS", &pairs)?;
+            Config::bind_token_projects(
+                &mut config.auth_tokens,
+                token_projects,
+                "TUNNEL_TOKEN_PROJECTS",
+            )?;
+        }
+        Ok(config)
     }
 
-    pub fn clean_remote_hosts(hosts : &[String]) -> Vec<Host>{
-        let mut result = vec!();
-        for host in hosts {
-            if let Ok(host_url) = Url::parse(host) {
-                if let Some(hostname) = host_url.host_str() {
-                    result.push(Host(hostname.to_string()))
-                } else {
-                    error!("{} is not an URL to a remote host", host_url)
+    /**
+     * PEM encoded certificate chain and private key, read from Vault or from their files
+     */
+    pub fn tls_certificate(&self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let read = |pem: &Option<String>, path: &Option<String>| match (pem, path) {
+            (Some(pem), _) => Ok(pem.clone().into_bytes()),
+            (None, Some(path)) => fs::read(path),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No TLS certificate configured",
+            )),
+        };
+        Ok((
+            read(&self.tls_cert_pem, &self.tls_cert_path)?,
+            read(&self.tls_key_pem, &self.tls_key_path)?,
+        ))
+    }
+
+    /**
+     * Restrict the tokens to their projects
+     */
+    fn bind_token_projects(
+        auth_tokens: &mut [AuthToken],
+        token_projects: HashMap<String, Vec<String>>,
+        variable: &str,
+    ) -> Result<(), String> {
+        for (token, projects) in token_projects {
+            match auth_tokens.iter_mut().find(|auth_token| auth_token.token == token) {
+                Some(auth_token) => auth_token.projects = Some(projects),
+                None => {
+                    return Err(format!(
+                        "'{}' references a token missing from the auth tokens",
+                        variable
+                    ))
                 }
             }
-            else {
-                error!("{} is not a valid url", host)
+        }
+        Ok(())
+    }
+
+    pub fn known_item_types() -> Vec<String> {
+        KNOWN_ITEM_TYPES.iter().map(|item| item.to_string()).collect()
+    }
+
+    pub fn cors_default_headers() -> Vec<String> {
+        CORS_DEFAULT_HEADERS.iter().map(|header| header.to_string()).collect()
+    }
+
+    pub fn envelope_content_types() -> Vec<String> {
+        ENVELOPE_CONTENT_TYPES
+            .iter()
+            .map(|content_type| content_type.to_string())
+            .collect()
+    }
+
+    /**
+     * Returns true if envelopes can be posted with this Content-Type header. Parameters such as
+     * the charset are ignored, and requests without a content type are accepted.
+     */
+    pub fn content_type_is_allowed(&self, content_type: Option<&str>) -> bool {
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None => return true,
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        self.allowed_content_types
+            .iter()
+            .any(|allowed| allowed == "*" || *allowed == media_type)
+    }
+
+    /**
+     * Parse `project_id:secret` pairs
+     */
+    pub fn parse_signing_secrets(pairs: &[String]) -> Result<HashMap<String, String>, String> {
+        let mut secrets = HashMap::new();
+        for pair in pairs {
+            match pair.trim().split_once(':') {
+                Some((project_id, secret)) if !project_id.is_empty() && !secret.is_empty() => {
+                    secrets.insert(project_id.to_string(), secret.to_string());
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid 'TUNNEL_SIGNING_SECRETS' entry, expected 'project_id:secret' : {}",
+                        pair
+                    ))
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    /**
+     * Parse `region:proxy_url` pairs
+     */
+    pub fn parse_region_proxies(pairs: &[String]) -> Result<HashMap<String, String>, String> {
+        let mut proxies = HashMap::new();
+        for pair in pairs {
+            match pair.trim().split_once(':') {
+                Some((region, proxy)) if !region.is_empty() && !proxy.is_empty() => {
+                    proxies.insert(region.to_lowercase(), proxy.to_string());
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid 'TUNNEL_REGION_PROXIES' entry, expected 'region:proxy_url' : {}",
+                        pair
+                    ))
+                }
             }
         }
-        return result
+        Ok(proxies)
+    }
+
+    /**
+     * Parse `name:project_id|project_id` pairs
+     */
+    pub fn parse_project_lists(
+        variable: &str,
+        pairs: &[String],
+    ) -> Result<HashMap<String, Vec<String>>, String> {
+        let mut projects = HashMap::new();
+        for pair in pairs {
+            match pair.trim().rsplit_once(':') {
+                Some((name, project_ids)) if !name.is_empty() && !project_ids.is_empty() => {
+                    projects.insert(
+                        name.to_string(),
+                        project_ids.split('|').map(|id| id.trim().to_string()).collect(),
+                    );
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid '{}' entry, expected 'name:project_id|project_id' : {}",
+                        variable, pair
+                    ))
+                }
+            }
+        }
+        Ok(projects)
+    }
+
+    /**
+     * Projects a client certificate with those names can submit to, `None` when it is not
+     * restricted
+     */
+    pub fn client_cert_allowed_projects(&self, names: &[String]) -> Option<Vec<String>> {
+        if self.client_cert_projects.is_empty() {
+            return None;
+        }
+        Some(
+            names
+                .iter()
+                .filter_map(|name| self.client_cert_projects.get(name))
+                .flatten()
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /**
+     * Parse `project_id:events` pairs
+     */
+    pub fn parse_quotas(variable: &str, pairs: &[String]) -> Result<HashMap<String, u64>, String> {
+        let mut quotas = HashMap::new();
+        for pair in pairs {
+            let quota = pair
+                .trim()
+                .split_once(':')
+                .and_then(|(project_id, events)| Some((project_id, u64::from_str(events).ok()?)));
+            match quota {
+                Some((project_id, events)) if !project_id.is_empty() => {
+                    quotas.insert(project_id.to_string(), events);
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid '{}' entry, expected 'project_id:events' : {}",
+                        variable, pair
+                    ))
+                }
+            }
+        }
+        Ok(quotas)
+    }
+
+    /**
+     * Where streamed bodies are spilled
+     */
+    pub fn spill_dir(&self) -> PathBuf {
+        self.spill_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    /**
+     * Parse project ids, returning them along with whether a `*` entry accepts every project
+     */
+    pub fn parse_project_ids(entries: &[String]) -> Result<(Vec<ProjectId>, bool), String> {
+        let allow_all = entries.iter().any(|entry| entry.trim() == "*");
+        let ids = entries
+            .iter()
+            .filter(|entry| entry.trim() != "*")
+            .map(|id| ProjectId::from_str(id))
+            .collect::<Result<Vec<ProjectId>, String>>()?;
+        Ok((ids, allow_all))
+    }
+
+    /**
+     * Parse `path:url|url` pairs
+     */
+    pub fn parse_path_remote_hosts(pairs: &[String]) -> Result<HashMap<String, Vec<Host>>, String> {
+        let mut path_hosts = HashMap::new();
+        for pair in pairs {
+            match pair.trim().split_once(':') {
+                Some((path, urls)) if !path.is_empty() && !urls.is_empty() => {
+                    let urls: Vec<String> = urls.split('|').map(str::to_string).collect();
+                    path_hosts.insert(path.to_string(), Config::clean_remote_hosts(&urls)?);
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid 'TUNNEL_PATH_REMOTE_HOSTS' entry, expected 'path:url|url' : {}",
+                        pair
+                    ))
+                }
+            }
+        }
+        Ok(path_hosts)
+    }
+
+    /**
+     * Parse `project_id:url` entries, the url being the one of a sentry host
+     */
+    pub fn parse_project_upstreams(entries: &[String]) -> Result<HashMap<String, Host>, String> {
+        let mut upstreams = HashMap::new();
+        for entry in entries {
+            let upstream = entry.trim().split_once(':').and_then(|(project_id, url)| {
+                let project_id = ProjectId::from_str(project_id).ok()?;
+                let host = Host::from_str(url).ok().filter(|host| !host.is_pattern())?;
+                Some((project_id, host))
+            });
+            match upstream {
+                Some((project_id, host)) => {
+                    upstreams.insert(project_id.to_string(), host);
+                }
+                None => {
+                    return Err(format!(
+                        "Invalid 'TUNNEL_PROJECT_UPSTREAMS' entry, expected 'project_id:url' : {}",
+                        entry
+                    ))
+                }
+            }
+        }
+        Ok(upstreams)
+    }
+
+    /**
+     * Parse `project_id:percentage:dsn` entries
+     */
+    pub fn parse_canary_routes(entries: &[String]) -> Result<HashMap<String, CanaryRoute>, String> {
+        let mut routes = HashMap::new();
+        for entry in entries {
+            let mut parts = entry.trim().splitn(3, ':');
+            let route = match (parts.next(), parts.next(), parts.next()) {
+                (Some(project_id), Some(percentage), Some(dsn)) if !project_id.is_empty() => {
+                    u64::from_str(percentage)
+                        .ok()
+                        .filter(|percentage| *percentage <= 100)
+                        .zip(Dsn::from_str(dsn).ok())
+                        .map(|(percentage, dsn)| (project_id, CanaryRoute { percentage, dsn }))
+                }
+                _ => None,
+            };
+            match route {
+                Some((project_id, route)) => {
+                    routes.insert(project_id.to_string(), route);
+                }
+                None => {
+                    return Err(format!(
+                        "Invalid 'TUNNEL_CANARY' entry, expected 'project_id:percentage:dsn' : {}",
+                        entry
+                    ))
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /**
+     * Returns true if requests sent with this User-Agent must be dropped
+     */
+    pub fn user_agent_is_denied(&self, user_agent: &str) -> bool {
+        let user_agent = user_agent.to_lowercase();
+        let known_bots: &[&str] = if self.filter_bots {
+            KNOWN_BOT_USER_AGENTS
+        } else {
+            &[]
+        };
+        known_bots.iter().any(|fragment| user_agent.contains(fragment))
+            || self
+                .denied_user_agents
+                .iter()
+                .any(|fragment| user_agent.contains(fragment.as_str()))
+    }
+
+    /**
+     * Returns true if requests from this country are accepted. Requests from an unknown country
+     * are only accepted when no allow list is configured.
+     */
+    pub fn country_is_allowed(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(country) => {
+                (self.allowed_countries.is_empty()
+                    || self.allowed_countries.iter().any(|allowed| allowed == country))
+                    && !self.denied_countries.iter().any(|denied| denied == country)
+            }
+            None => self.allowed_countries.is_empty(),
+        }
+    }
+
+    /**
+     * Returns true if envelopes for this host are accepted. Hosts that are not on sentry.io have
+     * no region and are always accepted.
+     */
+    pub fn region_is_allowed(&self, host: &str) -> bool {
+        match region::of_host(host) {
+            Some(region) => {
+                (self.allowed_regions.is_empty() || self.allowed_regions.contains(&region))
+                    && !self.denied_regions.contains(&region)
+            }
+            None => true,
+        }
+    }
+
+    /**
+     * Returns true if requests breaking this rule are flagged instead of dropped
+     */
+    pub fn rule_is_audited(&self, rule: &str) -> bool {
+        self.audited_rules.iter().any(|audited| audited == rule)
+    }
+
+    pub fn signing_secret(&self, id: u64) -> Option<&str> {
+        self.signing_secrets
+            .get(&format!("{}", id))
+            .map(String::as_str)
+    }
+
+    pub fn project_id_is_allowed(&self, id: u64) -> bool {
+        let configured = self.allow_all_projects
+            || self.project_ids.contains(&ProjectId(id))
+            || self.discovered_projects.contains(id);
+        self.allowlist.project_is_allowed(id, configured)
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.forward_retries,
+            base_delay: Duration::from_millis(self.retry_base_delay),
+            max_delay: Duration::from_millis(self.retry_max_delay),
+        }
+    }
+
+    /**
+     * The tunnel path followed by the extra ones
+     */
+    pub fn tunnel_paths(&self) -> Vec<String> {
+        let mut paths = vec![self.tunnel_path.clone()];
+        paths.extend(self.extra_paths.iter().cloned());
+        paths
+    }
+
+    /**
+     * The remote hosts, with the changes made through the admin endpoints
+     */
+    pub fn allowed_hosts(&self) -> Vec<Host> {
+        self.allowlist.hosts(&self.remote_hosts)
+    }
+
+    /**
+     * Whether the project is allowed on this tunnel path. A path with its own project ids only
+     * accepts those, less the projects removed through the admin API.
+     */
+    pub fn project_id_is_allowed_on(&self, path: &str, id: u64) -> bool {
+        match self.path_projects.get(path) {
+            Some(ids) => {
+                ids.contains(&id.to_string()) && self.allowlist.project_is_allowed(id, true)
+            }
+            None => self.project_id_is_allowed(id),
+        }
+    }
+
+    /**
+     * The hosts allowed on this tunnel path. A path with its own remote hosts only allows those,
+     * less the hosts removed through the admin API.
+     */
+    pub fn allowed_hosts_on(&self, path: &str) -> Vec<Host> {
+        match self.path_remote_hosts.get(path) {
+            Some(hosts) => self
+                .allowlist
+                .hosts(hosts)
+                .into_iter()
+                .filter(|host| hosts.contains(host))
+                .collect(),
+            None => self.allowed_hosts(),
+        }
+    }
+
+    /**
+     * The allowed hosts followed by the hosts of the tunnel paths and projects that have their
+     * own
+     */
+    pub fn upstream_hosts(&self) -> Vec<Host> {
+        let mut hosts = self.allowed_hosts();
+        let own_hosts = self.path_remote_hosts.values().flatten();
+        for host in own_hosts.chain(self.project_upstreams.values()) {
+            if !hosts.contains(host) {
+                hosts.push(host.clone());
+            }
+        }
+        hosts
+    }
+
+    /**
+     * The host envelopes of this project posted on `path` are sent to instead of the host of their
+     * dsn, if any. Paths with their own remote hosts only send envelopes to those.
+     */
+    pub fn project_upstream_on(&self, path: &str, id: u64) -> Option<&Host> {
+        if self.path_remote_hosts.contains_key(path) {
+            return None;
+        }
+        self.project_upstreams.get(&id.to_string())
+    }
+
+    /**
+     * Parse the remote hosts, skipping empty entries. Every invalid entry is reported.
+     */
+    pub fn clean_remote_hosts(hosts: &[String]) -> Result<Vec<Host>, String> {
+        let mut result = vec![];
+        let mut errors = vec![];
+        for host in hosts.iter().filter(|host| !host.trim().is_empty()) {
+            match Host::from_str(host) {
+                Ok(host) => result.push(host),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(format!("Invalid remote hosts : {}", errors.join(", ")))
+        }
     }
 }
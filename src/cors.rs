@@ -0,0 +1,122 @@
+use futures_util::future::{self, FutureExt};
+use gotham::handler::HandlerFuture;
+use gotham::hyper::header::{self, HeaderValue};
+use gotham::hyper::{Body, HeaderMap, Method, Response, StatusCode, Uri};
+use gotham::middleware::Middleware;
+use gotham::state::{FromState, State};
+use gotham_derive::NewMiddleware;
+
+use std::pin::Pin;
+
+// Seconds browsers may cache the answer to a preflight request
+const PREFLIGHT_MAX_AGE: u64 = 86400;
+
+// Response headers the scripts of the allowed origins can read, SDKs back off with them
+const EXPOSED_HEADERS: &str = "Retry-After, X-Sentry-Rate-Limits";
+
+/**
+ * Lets browsers post envelopes from web apps served on another origin than the tunnel. Preflight
+ * requests on the tunnel paths are answered here, and the responses to the allowed origins get
+ * the `Access-Control-Allow-Origin` header.
+ */
+#[derive(Clone, Debug, NewMiddleware)]
+pub struct Cors {
+    // No origin is allowed, the middleware does nothing, when empty
+    allowed_origins: Vec<String>,
+    allowed_headers: String,
+    tunnel_paths: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(
+        allowed_origins: &[String],
+        allowed_headers: &[String],
+        tunnel_paths: &[String],
+    ) -> Cors {
+        Cors {
+            allowed_origins: allowed_origins.to_vec(),
+            allowed_headers: allowed_headers.join(", "),
+            tunnel_paths: tunnel_paths.to_vec(),
+        }
+    }
+
+    /**
+     * The value of `Access-Control-Allow-Origin` for a request from this origin, None when it is
+     * not allowed
+     */
+    fn allow_origin(&self, origin: &str) -> Option<HeaderValue> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = origin.trim_end_matches('/').to_lowercase();
+        if self.allowed_origins.contains(&origin) {
+            HeaderValue::from_str(&origin).ok()
+        } else {
+            None
+        }
+    }
+
+    fn preflight(&self, allow_origin: Option<HeaderValue>) -> Response<Body> {
+        let allow_origin = match allow_origin {
+            Some(allow_origin) => allow_origin,
+            None => {
+                return Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, "POST, OPTIONS")
+            .header(header::ACCESS_CONTROL_ALLOW_HEADERS, self.allowed_headers.as_str())
+            .header(header::ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE)
+            .header(header::VARY, "Origin")
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+impl Middleware for Cors {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let origin = HeaderMap::borrow_from(&state)
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok());
+        let origin = match origin {
+            Some(origin) if !self.allowed_origins.is_empty() => origin.to_string(),
+            _ => return chain(state),
+        };
+        let allow_origin = self.allow_origin(&origin);
+        if Method::borrow_from(&state) == Method::OPTIONS
+            && self
+                .tunnel_paths
+                .iter()
+                .any(|path| path == Uri::borrow_from(&state).path())
+        {
+            let response = self.preflight(allow_origin);
+            return future::ok((state, response)).boxed();
+        }
+        let allow_origin = match allow_origin {
+            Some(allow_origin) => allow_origin,
+            None => return chain(state),
+        };
+        chain(state)
+            .map(move |handled| {
+                handled.map(|(state, mut response)| {
+                    let headers = response.headers_mut();
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+                    headers.insert(
+                        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                        HeaderValue::from_static(EXPOSED_HEADERS),
+                    );
+                    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+                    (state, response)
+                })
+            })
+            .boxed()
+    }
+}
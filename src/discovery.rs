@@ -0,0 +1,126 @@
+use crate::config::Config;
+use crate::envelope::ProjectId;
+use crate::redact;
+use anyhow::{anyhow, Error as AError};
+use isahc::{AsyncReadResponseExt, Request, RequestExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/**
+ * How to list the projects of the organization through the Sentry API
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiscoveryConfig {
+    // Base url of the Sentry web and API server, https://sentry.io for sentry.io
+    pub api_url: String,
+    pub organization: String,
+    // Auth token with the `project:read` scope
+    #[serde(serialize_with = "redact::secret")]
+    pub token: String,
+    // Seconds between two listings
+    pub interval: u64,
+}
+
+/**
+ * Project ids found through the Sentry API, shared by the configurations cloned from the same one
+ */
+#[derive(Debug, Default)]
+pub struct DiscoveredProjects(RwLock<HashSet<u64>>);
+
+impl DiscoveredProjects {
+    pub fn contains(&self, id: u64) -> bool {
+        self.0.read().unwrap().contains(&id)
+    }
+
+    pub fn replace(&self, ids: Vec<ProjectId>) {
+        *self.0.write().unwrap() = ids.into_iter().map(|id| id.0).collect();
+    }
+}
+
+#[derive(Deserialize)]
+struct Project {
+    id: String,
+}
+
+/**
+ * The url of the next page, from a `Link` header of the Sentry API
+ */
+fn next_page(link: &str) -> Option<String> {
+    link.split(',').find_map(|link| {
+        let mut parts = link.split(';').map(str::trim);
+        let url = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+        let parts: Vec<&str> = parts.collect();
+        if parts.contains(&r#"rel="next""#) && parts.contains(&r#"results="true""#) {
+            Some(url.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/**
+ * List the ids of every project of the organization, following the pagination
+ */
+pub async fn fetch_project_ids(config: &DiscoveryConfig) -> Result<Vec<ProjectId>, AError> {
+    let mut ids = vec![];
+    let mut url = Some(format!(
+        "{}/api/0/organizations/{}/projects/",
+        config.api_url.trim_end_matches('/'),
+        config.organization
+    ));
+    while let Some(page) = url.take() {
+        let mut response = Request::get(&page)
+            .header("Authorization", format!("Bearer {}", config.token))
+            .body(())?
+            .send_async()
+            .await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "The Sentry API answered {} to {} : {}",
+                status,
+                page,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        for project in serde_json::from_slice::<Vec<Project>>(&body)? {
+            ids.push(project.id.parse::<ProjectId>().map_err(|e| anyhow!(e))?);
+        }
+        url = response
+            .headers()
+            .get("Link")
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_page);
+    }
+    Ok(ids)
+}
+
+/**
+ * List the projects periodically, and accept the envelopes of the ones found. The last listing
+ * is kept when the Sentry API can not be reached.
+ */
+pub async fn keep_discovering(config: Config) {
+    let discovery = match &config.discovery {
+        Some(discovery) => discovery,
+        None => return,
+    };
+    loop {
+        match fetch_project_ids(discovery).await {
+            Ok(ids) => {
+                info!(
+                    "Found {} projects in the organization {}",
+                    ids.len(),
+                    discovery.organization
+                );
+                config.discovered_projects.replace(ids);
+            }
+            Err(e) => error!("Could not list the projects from the Sentry API : {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(discovery.interval)).await;
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/**
+ * Requests being handled and envelopes waiting in the forward queue, that a graceful shutdown
+ * waits for
+ */
+#[derive(Debug, Default)]
+pub struct Drain {
+    pending: AtomicUsize,
+    idle: Notify,
+    draining: AtomicBool,
+}
+
+/**
+ * A request or a queued envelope, done when dropped
+ */
+#[derive(Debug)]
+pub struct Pending {
+    drain: Arc<Drain>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if self.drain.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.drain.idle.notify_waiters();
+        }
+    }
+}
+
+impl Drain {
+    pub fn track(self: &Arc<Self>) -> Pending {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        Pending {
+            drain: self.clone(),
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Whether the shutdown started, the tunnel should not be sent new requests
+     */
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /**
+     * Start draining and wait for the pending requests and envelopes to be done, for at most
+     * `timeout`. Returns whether they were all done.
+     */
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+        let idle = async {
+            loop {
+                // Registered before the check, so that a notification in between is not missed
+                let notified = self.idle.notified();
+                if self.pending() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(timeout, idle).await.is_ok()
+    }
+}
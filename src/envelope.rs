@@ -1,21 +1,156 @@
-use crate::config::Host;
-use gotham::anyhow::Error as AError;
-use gotham::handler::IntoResponse;
-use gotham::helpers::http::response::create_response;
-use gotham::hyper::StatusCode;
-use gotham::hyper::{body::Body, Response};
-use gotham::state::State;
-use isahc::{Request, RequestExt};
-use mime::Mime;
-use sentry_types::Dsn;
-use serde_json::Value;
-
-use log::*;
+use anyhow::Error as AError;
+use regex::{Regex, RegexBuilder};
+use sentry_types::{Dsn, Utc, Uuid};
+use serde_json::{json, Value};
+use url::Url;
+
+use memchr::memchr;
 
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
 
+/**
+ * A sentry host envelopes can be forwarded to, parsed from its url. Its name can be a pattern
+ * matching several hosts, a glob like `*.ingest.sentry.io` or a regular expression starting with
+ * `^`.
+ */
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    // Matches the whole host name of a dsn, when `host` is a pattern
+    pub pattern: Option<Regex>,
+}
+
+impl PartialEq for Host {
+    fn eq(&self, other: &Host) -> bool {
+        self.scheme == other.scheme && self.host == other.host && self.port == other.port
+    }
+}
+
+impl Eq for Host {}
+
+impl FromStr for Host {
+    type Err = String;
+
+    /**
+     * Parse an http or https url, `https://sentry.example.com` for instance. The port defaults to
+     * the one of the scheme.
+     */
+    fn from_str(url: &str) -> Result<Host, String> {
+        if let Some((scheme, rest)) = url.trim().split_once("://") {
+            if rest.starts_with('^') || rest.contains('*') {
+                return Host::parse_pattern(url, scheme, rest);
+            }
+        }
+        let parsed =
+            Url::parse(url.trim()).map_err(|e| format!("{} is not a valid url : {}", url, e))?;
+        match (parsed.scheme(), parsed.host_str(), parsed.port_or_known_default()) {
+            (scheme @ ("http" | "https"), Some(host), Some(port)) => Ok(Host {
+                scheme: scheme.to_string(),
+                host: host.to_string(),
+                port,
+                pattern: None,
+            }),
+            _ => Err(format!("{} is not an URL to a remote host", url)),
+        }
+    }
+}
+
+impl Host {
+    /**
+     * Parse `scheme://pattern[:port]`. Each `*` of a glob stands for one label of the host name,
+     * and regular expressions must match the whole of it. Both ignore the case.
+     */
+    fn parse_pattern(url: &str, scheme: &str, rest: &str) -> Result<Host, String> {
+        let default_port = match scheme {
+            "http" => 80,
+            "https" => 443,
+            _ => return Err(format!("{} is not an URL to a remote host", url)),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => {
+                let port = u16::from_str(port).map_err(|_| format!("Invalid port in {}", url))?;
+                (host, port)
+            }
+            _ => (rest, default_port),
+        };
+        let regex = if host.starts_with('^') {
+            format!("^(?:{})$", host)
+        } else if host.contains(|c: char| c == '/' || c == '@' || c.is_whitespace()) {
+            return Err(format!("{} is not a valid host pattern", url));
+        } else {
+            let labels: Vec<String> = host.split('*').map(regex::escape).collect();
+            format!("^{}$", labels.join("[^.]+"))
+        };
+        let pattern = RegexBuilder::new(&regex)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| format!("{} is not a valid host pattern : {}", url, e))?;
+        Ok(Host {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            port,
+            pattern: Some(pattern),
+        })
+    }
+
+    /**
+     * Whether this host is a pattern, which can not be sent requests
+     */
+    pub fn is_pattern(&self) -> bool {
+        self.pattern.is_some()
+    }
+
+    /**
+     * Returns true if the dsn points to this host, or to a host matching its pattern, with the
+     * same scheme and port
+     */
+    pub fn matches(&self, dsn: &Dsn) -> bool {
+        let host_matches = match &self.pattern {
+            Some(pattern) => pattern.is_match(dsn.host()),
+            None => self.host == dsn.host(),
+        };
+        host_matches && self.scheme == dsn.scheme().to_string() && self.port == dsn.port()
+    }
+}
+
+impl Display for Host {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match (self.scheme.as_str(), self.port) {
+            ("http", 80) | ("https", 443) => {
+                f.write_fmt(format_args!("{}://{}", self.scheme, self.host))
+            }
+            _ => f.write_fmt(format_args!("{}://{}:{}", self.scheme, self.host, self.port)),
+        }
+    }
+}
+
+/**
+ * A sentry project id
+ */
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ProjectId(pub u64);
+
+impl FromStr for ProjectId {
+    type Err = String;
+
+    fn from_str(id: &str) -> Result<ProjectId, String> {
+        u64::from_str(id.trim())
+            .map(ProjectId)
+            .map_err(|_| format!("Invalid project id : {}", id))
+    }
+}
+
+impl Display for ProjectId {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /**
  * Represent a sentry envelope
  */
@@ -25,6 +160,79 @@ pub struct SentryEnvelope {
     pub dsn: Dsn,
 }
 
+/**
+ * Item types that sentry knows how to ingest
+ */
+pub const KNOWN_ITEM_TYPES: &[&str] = &[
+    "event",
+    "transaction",
+    "attachment",
+    "session",
+    "sessions",
+    "user_report",
+    "client_report",
+    "replay_event",
+    "replay_recording",
+    "replay_video",
+    "profile",
+    "profile_chunk",
+    "check_in",
+    "feedback",
+    "span",
+    "log",
+    "statsd",
+    "metric_meta",
+];
+
+/**
+ * Position of the newline ending the envelope header
+ */
+pub fn header_end(body: &[u8]) -> Option<usize> {
+    memchr(b'\n', body)
+}
+
+/**
+ * A single item of a sentry envelope : its header and a view on its payload
+ */
+#[derive(Debug)]
+pub struct EnvelopeItem<'a> {
+    pub header: Value,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EnvelopeItem<'a> {
+    /**
+     * Returns the item type (event, session, attachment...) if the header declares one
+     */
+    pub fn item_type(&self) -> Option<&str> {
+        self.header.get("type").and_then(Value::as_str)
+    }
+}
+
+/**
+ * Set a tag of an event payload, whose tags can be an object or a list of pairs
+ */
+pub fn set_tag(event: &mut Value, key: &str, value: String) {
+    let value = Value::from(value);
+    match event.get_mut("tags") {
+        Some(Value::Object(tags)) => {
+            tags.insert(key.to_string(), value);
+        }
+        Some(Value::Array(tags)) => tags.push(Value::Array(vec![key.into(), value])),
+        _ => event["tags"] = serde_json::json!({ key: value }),
+    }
+}
+
+/**
+ * What to do with an item when editing an envelope
+ */
+#[derive(Debug)]
+pub enum ItemEdit {
+    Keep,
+    Remove,
+    Replace(Vec<u8>),
+}
+
 /**
  * A body parsing error
  */
@@ -36,6 +244,9 @@ pub enum BodyError {
     InvalidDsnValue,
     InvalidProjectId,
     EmptyBody,
+    InvalidItem,
+    ItemTooLarge,
+    UnknownItemType(String),
 }
 
 impl Display for BodyError {
@@ -53,50 +264,245 @@ impl Display for BodyError {
             BodyError::InvalidProjectId => f.write_str("Unauthorized project ID"),
             BodyError::InvalidDsnValue => f.write_str("Failed to parse dsn value"),
             BodyError::EmptyBody => f.write_str("Empty request body"),
+            BodyError::InvalidItem => f.write_str("Failed to parse an envelope item"),
+            BodyError::ItemTooLarge => f.write_str("An envelope item exceeds the size allowed for its type"),
+            BodyError::UnknownItemType(item_type) => {
+                f.write_fmt(format_args!("Envelope item type '{}' is not allowed", item_type))
+            }
         }
     }
 }
 
 impl Error for BodyError {}
 
-impl IntoResponse for BodyError {
-    fn into_response(self, state: &State) -> Response<Body> {
-        warn!("{}", self);
-        let mime = "application/json".parse::<Mime>().unwrap();
-        create_response(state, StatusCode::BAD_REQUEST, mime, format!("{}", self))
+impl SentryEnvelope {
+    /**
+     * An envelope holding an info event, to check that the envelopes of this dsn are forwarded
+     */
+    pub fn test_event(dsn: &Dsn) -> SentryEnvelope {
+        let event_id = Uuid::new_v4().to_simple().to_string();
+        let payload = json!({
+            "event_id": event_id,
+            "level": "info",
+            "platform": "other",
+            "logger": "sentry_tunnel",
+            "message": "Test event sent by sentry_tunnel send-test-event",
+            "timestamp": Utc::now(),
+        })
+        .to_string();
+        let header = json!({
+            "event_id": event_id,
+            "dsn": dsn.to_string(),
+            "sent_at": Utc::now(),
+        });
+        let item_header = json!({
+            "type": "event",
+            "length": payload.len(),
+        });
+        SentryEnvelope {
+            raw_body: format!("{}\n{}\n{}\n", header, item_header, payload).into_bytes(),
+            dsn: dsn.clone(),
+        }
     }
-}
 
-impl SentryEnvelope {
     /**
-     * Returns true if this envelope is for an host that we are allowed to forward requests to
+     * Returns true if this envelope is for an host that we are allowed to forward requests to.
+     * The scheme and port of the dsn must match too, so that `http://sentry.example.com:8080`
+     * does not pass for `https://sentry.example.com`.
      */
     pub fn dsn_host_is_valid(&self, host: &[Host]) -> bool {
-        let envelope_host = self.dsn.host().to_string();
-        host.iter()
-            .any(|x| x.0 == envelope_host)
-    }
-
-    /**
-     * Forward this envelope to the destination sentry relay
-     */
-    pub async fn forward(&self) -> Result<(), AError> {
-        let uri = self.dsn.envelope_api_url().to_string() + "?sentry_key=" + self.dsn.public_key();
-        let request = Request::builder()
-            .uri(uri)
-            .header("Content-type", "application/x-sentry-envelope")
-            .method("POST")
-            .body(self.raw_body.clone())?;
-        info!(
-            "Sending HTTP {} {} - body length={}",
-            request.method(),
-            request.uri(),
-            self.raw_body.len()
-        );
-        match request.send_async().await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.into()),
+        host.iter().any(|x| x.matches(&self.dsn))
+    }
+
+    /**
+     * Send the envelope to another sentry host, keeping the keys and project of its dsn
+     */
+    pub fn redirect(&mut self, host: &Host) -> Result<(), AError> {
+        let keys = match self.dsn.secret_key() {
+            Some(secret_key) => format!("{}:{}", self.dsn.public_key(), secret_key),
+            None => self.dsn.public_key().to_string(),
+        };
+        self.dsn = Dsn::from_str(&format!(
+            "{}://{}@{}:{}/{}",
+            host.scheme,
+            keys,
+            host.host,
+            host.port,
+            self.dsn.project_id()
+        ))?;
+        Ok(())
+    }
+
+    /**
+     * Write the dsn into the envelope header, so that the envelope is sent to it when it is
+     * parsed again, from the spool for instance
+     */
+    pub fn write_dsn_header(&mut self) -> Result<(), BodyError> {
+        let header_end = header_end(&self.raw_body).ok_or(BodyError::InvalidNumberOfLines)?;
+        let mut header: Value = serde_json::from_slice(&self.raw_body[..header_end])
+            .map_err(BodyError::InvalidHeaderJson)?;
+        header["dsn"] = self.dsn.to_string().into();
+        let mut raw_body = header.to_string().into_bytes();
+        raw_body.extend_from_slice(&self.raw_body[header_end..]);
+        self.raw_body = raw_body;
+        Ok(())
+    }
+
+    /**
+     * The project of the dsn
+     */
+    pub fn project_id(&self) -> ProjectId {
+        ProjectId(self.dsn.project_id().value())
+    }
+
+    /**
+     * Url of the envelope endpoint of the sentry relay, authenticated with the dsn public key
+     */
+    pub fn forward_url(&self) -> String {
+        self.dsn.envelope_api_url().to_string() + "?sentry_key=" + self.dsn.public_key()
+    }
+
+    /**
+     * The envelope header, parsed from the first line only
+     */
+    fn header(&self) -> Option<Value> {
+        let header_end = header_end(&self.raw_body)?;
+        serde_json::from_slice(&self.raw_body[..header_end]).ok()
+    }
+
+    /**
+     * The id of the event carried by this envelope, as announced in its header
+     */
+    pub fn event_id(&self) -> Option<String> {
+        self.header()?.get("event_id")?.as_str().map(str::to_string)
+    }
+
+    /**
+     * Name and version of the SDK that sent the envelope, from its header
+     */
+    pub fn sdk(&self) -> Option<(String, String)> {
+        let header = self.header()?;
+        let sdk = header.get("sdk")?;
+        Some((
+            sdk.get("name")?.as_str()?.to_string(),
+            sdk.get("version").and_then(Value::as_str).unwrap_or_default().to_string(),
+        ))
+    }
+
+    /**
+     * Split the envelope body into its items. Items with an explicit `length` are read as is,
+     * others span until the next newline.
+     */
+    pub fn items(&self) -> Result<Vec<EnvelopeItem<'_>>, BodyError> {
+        let body = &self.raw_body[..];
+        let mut items = vec![];
+        let mut pos = match header_end(body) {
+            Some(header_end) => header_end + 1,
+            None => return Ok(items),
+        };
+        while pos < body.len() {
+            let header_end = memchr(b'\n', &body[pos..])
+                .map(|offset| pos + offset)
+                .unwrap_or_else(|| body.len());
+            let header_bytes = &body[pos..header_end];
+            pos = header_end + 1;
+            if header_bytes.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let header: Value =
+                serde_json::from_slice(header_bytes).map_err(|_| BodyError::InvalidItem)?;
+            let payload = match header.get("length").and_then(Value::as_u64) {
+                Some(length) => {
+                    let start = pos.min(body.len());
+                    let end = start
+                        .checked_add(length as usize)
+                        .filter(|end| *end <= body.len())
+                        .ok_or(BodyError::InvalidItem)?;
+                    // The newline after a payload with an explicit length is optional
+                    pos = if body.get(end) == Some(&b'\n') { end + 1 } else { end };
+                    &body[start..end]
+                }
+                None => {
+                    let start = pos.min(body.len());
+                    let end = memchr(b'\n', &body[start..])
+                        .map(|offset| start + offset)
+                        .unwrap_or_else(|| body.len());
+                    pos = end + 1;
+                    &body[start..end]
+                }
+            };
+            items.push(EnvelopeItem { header, payload });
+        }
+        Ok(items)
+    }
+
+    /**
+     * Returns an error for the first item whose type is not in the allowed list
+     */
+    pub fn check_item_types(&self, allowed: &[String]) -> Result<(), BodyError> {
+        for item in self.items()? {
+            let item_type = item.item_type().unwrap_or_default();
+            if !allowed.iter().any(|allowed| allowed == item_type) {
+                return Err(BodyError::UnknownItemType(item_type.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Keep, remove or replace the payload of every item, rebuilding the body only when an item
+     * is changed. Returns the number of changed items.
+     */
+    pub fn edit_items<F>(&mut self, mut edit: F) -> Result<usize, BodyError>
+    where
+        F: FnMut(&EnvelopeItem) -> ItemEdit,
+    {
+        let header_end = match header_end(&self.raw_body) {
+            Some(header_end) => header_end + 1,
+            None => return Ok(0),
+        };
+        let mut raw_body = self.raw_body[..header_end].to_vec();
+        let mut edited = 0;
+        for item in self.items()? {
+            let (mut header, payload) = match edit(&item) {
+                ItemEdit::Keep => (item.header, item.payload.to_vec()),
+                ItemEdit::Remove => {
+                    edited += 1;
+                    continue;
+                }
+                ItemEdit::Replace(payload) => {
+                    edited += 1;
+                    (item.header, payload)
+                }
+            };
+            if header.get("length").is_some() {
+                header["length"] = payload.len().into();
+            }
+            raw_body.extend_from_slice(header.to_string().as_bytes());
+            raw_body.push(b'\n');
+            raw_body.extend_from_slice(&payload);
+            raw_body.push(b'\n');
+        }
+        if edited > 0 {
+            self.raw_body = raw_body;
         }
+        Ok(edited)
+    }
+
+    /**
+     * Remove the items that do not match the predicate. Returns the number of removed items.
+     */
+    pub fn retain_items<F>(&mut self, keep: F) -> Result<usize, BodyError>
+    where
+        F: Fn(&EnvelopeItem) -> bool,
+    {
+        self.edit_items(|item| {
+            if keep(item) {
+                ItemEdit::Keep
+            } else {
+                ItemEdit::Remove
+            }
+        })
     }
 
     /**
@@ -108,22 +514,11 @@ impl SentryEnvelope {
             return Err(AError::new(BodyError::EmptyBody));
         }
 
-        // Find the first newline to extract the header
-        let header_end = body.iter().position(|&b| b == b'\n')
+        // Only the header (first line) is read, the items are left untouched
+        let header_end = header_end(&body)
             .ok_or_else(|| AError::new(BodyError::InvalidNumberOfLines))?;
-        
-        // Parse the header (first line)
-        let header_bytes = &body[..header_end];
-        let header_str = std::str::from_utf8(header_bytes)
-            .map_err(|_| AError::new(BodyError::InvalidHeaderJson(
-                serde_json::Error::io(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Header contains invalid UTF-8"
-                ))
-            )))?;
-        
-        let header: Value = serde_json::from_str(header_str)
-            .map_err(|e| BodyError::InvalidHeaderJson(e))?;
+        let header: Value = serde_json::from_slice(&body[..header_end])
+            .map_err(BodyError::InvalidHeaderJson)?;
         
         if let Some(dsn) = header.get("dsn") {
             if let Some(dsn_str) = dsn.as_str() {
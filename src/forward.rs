@@ -0,0 +1,301 @@
+use crate::compression::Encoding;
+use crate::envelope::SentryEnvelope;
+use crate::streaming::{ItemSizeLimits, LimitedItems};
+use anyhow::Error as AError;
+use futures_util::future;
+use futures_util::io::Cursor;
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
+use gotham::hyper::body::Bytes;
+use isahc::config::Configurable;
+use isahc::http::request::Builder;
+use isahc::http::{header, StatusCode, Uri};
+use isahc::{AsyncBody, AsyncReadResponseExt, HttpClient, Request, Response};
+use log::*;
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Idle connections are kept alive with TCP keepalive probes sent this often
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Client of `SentryEnvelope::forward`, for the envelopes forwarded outside of a router
+static DEFAULT_CLIENT: OnceLock<UpstreamClient> = OnceLock::new();
+
+/**
+ * The HTTP client forwarding envelopes, shared so that the connections to sentry are kept alive
+ * and reused instead of paying the TCP and TLS setup for every envelope. It is built on first
+ * use.
+ */
+#[derive(Debug, Default)]
+pub struct UpstreamClient {
+    // Connections opened to a host at the same time, 0 for no limit
+    max_connections_per_host: usize,
+    // Idle connections kept open, 0 for the default of the client
+    idle_connections: usize,
+    client: OnceLock<HttpClient>,
+}
+
+impl UpstreamClient {
+    pub fn new(max_connections_per_host: usize, idle_connections: usize) -> UpstreamClient {
+        UpstreamClient {
+            max_connections_per_host,
+            idle_connections,
+            client: OnceLock::new(),
+        }
+    }
+
+    pub fn get(&self) -> Result<&HttpClient, AError> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let mut builder = HttpClient::builder()
+            .max_connections_per_host(self.max_connections_per_host)
+            .tcp_keepalive(KEEPALIVE_INTERVAL);
+        if self.idle_connections > 0 {
+            builder = builder.connection_cache_size(self.idle_connections);
+        }
+        let client = builder.build()?;
+        // Another request may have built one meanwhile, the first one is kept
+        Ok(self.client.get_or_init(|| client))
+    }
+
+    /**
+     * Send a HEAD request to `url`, returning the status answered within `timeout`
+     */
+    pub async fn probe(&self, url: &str, timeout: Duration) -> Result<u16, AError> {
+        let request = Request::head(url).timeout(timeout).body(AsyncBody::empty())?;
+        let response = self.get()?.send_async(request).await?;
+        Ok(response.status().as_u16())
+    }
+}
+
+/**
+ * How forwards failing with a connection error or a 5xx status are retried, with an exponential
+ * backoff between attempts
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    // Retries after the first attempt, 0 to never retry
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /**
+     * The delay before the retry number `retry`, starting at 1. It doubles with each retry up to
+     * the max delay, and a random half of it is taken off so that clients failing together do
+     * not retry together.
+     */
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        let jitter = (RandomState::new().hash_one(retry) % 1000) as f64 / 2000.0;
+        delay.mul_f64(1.0 - jitter)
+    }
+
+    /**
+     * Whether a forward may succeed if it is sent again
+     */
+    pub fn is_retryable(result: &Result<UpstreamResponse, AError>) -> bool {
+        match result {
+            Ok(response) => response.status >= 500,
+            Err(e) => e
+                .downcast_ref::<isahc::Error>()
+                .is_some_and(|e| e.is_network() || e.is_timeout()),
+        }
+    }
+}
+
+/**
+ * What the sentry relay answered to a forwarded envelope, with only its `X-Sentry-*` and
+ * `Retry-After` headers
+ */
+#[derive(Clone, Debug)]
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl UpstreamResponse {
+    async fn read(mut response: Response<AsyncBody>) -> UpstreamResponse {
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| {
+                name.as_str().starts_with("x-sentry-") || name == header::RETRY_AFTER
+            })
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = response.bytes().await.unwrap_or_else(|e| {
+            warn!("Could not read the response of sentry : {}", e);
+            vec![]
+        });
+        let response = UpstreamResponse {
+            status: response.status().as_u16(),
+            headers,
+            body,
+        };
+        if response.is_rate_limited() {
+            warn!(
+                "The sentry relay rate limited an envelope : {:?}",
+                response.rate_limit_headers().collect::<Vec<_>>()
+            );
+        }
+        response
+    }
+
+    /**
+     * The value of a header kept from the response, by its lowercase name
+     */
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+    }
+
+    /**
+     * The `Retry-After` and `X-Sentry-Rate-Limits` headers, that SDKs back off with
+     */
+    pub fn rate_limit_headers(&self) -> impl Iterator<Item = &(String, String)> {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name == "retry-after" || name == "x-sentry-rate-limits")
+    }
+}
+
+impl SentryEnvelope {
+    /**
+     * Forward this envelope to the destination sentry relay
+     */
+    pub async fn forward(&mut self) -> Result<UpstreamResponse, AError> {
+        self.forward_via(DEFAULT_CLIENT.get_or_init(UpstreamClient::default), None)
+            .await
+    }
+
+    /**
+     * Forward this envelope to the destination sentry relay, through a proxy if any. The body is
+     * lent to the request instead of being copied, and is back in `raw_body` once sent.
+     */
+    #[tracing::instrument(name = "upstream", skip_all, fields(host = %self.dsn.host()))]
+    pub async fn forward_via(
+        &mut self,
+        client: &UpstreamClient,
+        proxy: Option<&str>,
+    ) -> Result<UpstreamResponse, AError> {
+        let builder = self.request_builder(proxy)?;
+        let body = Bytes::from(std::mem::take(&mut self.raw_body));
+        let forwarded = self.send_shared(builder, &body, client).await;
+        // Without a copy as long as the request released the bytes
+        self.raw_body = Vec::from(body);
+        forwarded
+    }
+
+    async fn send_shared(
+        &self,
+        builder: Builder,
+        body: &Bytes,
+        client: &UpstreamClient,
+    ) -> Result<UpstreamResponse, AError> {
+        let length = body.len() as u64;
+        let body = AsyncBody::from_reader_sized(Cursor::new(body.clone()), length);
+        let request = builder.body(body)?;
+        info!(
+            "Sending HTTP {} {} - body length={}",
+            request.method(),
+            request.uri(),
+            length
+        );
+        let response = client.get()?.send_async(request).await?;
+        Ok(UpstreamResponse::read(response).await)
+    }
+
+    /**
+     * Forward this envelope compressed with `encoding`
+     */
+    #[tracing::instrument(name = "upstream", skip_all, fields(host = %self.dsn.host()))]
+    pub async fn forward_encoded(
+        &self,
+        client: &UpstreamClient,
+        proxy: Option<&str>,
+        encoding: Encoding,
+    ) -> Result<UpstreamResponse, AError> {
+        let compressed = encoding.compress(&self.raw_body)?;
+        let request = self
+            .request_builder(proxy)?
+            .header("Content-Encoding", encoding.to_string())
+            .body(compressed)?;
+        info!(
+            "Sending HTTP {} {} - body length={}, {} length={}",
+            request.method(),
+            request.uri(),
+            self.raw_body.len(),
+            encoding,
+            request.body().len()
+        );
+        Ok(UpstreamResponse::read(client.get()?.send_async(request).await?).await)
+    }
+
+    /**
+     * Forward this envelope to the destination sentry relay, streaming the part of the body
+     * that was not read yet instead of buffering it. `raw_body` holds the bytes already read,
+     * it is sent first and left empty, and `content_length` is the size of the whole body.
+     */
+    #[tracing::instrument(name = "upstream", skip_all, fields(host = %self.dsn.host()))]
+    pub async fn forward_stream<S>(
+        &mut self,
+        rest: S,
+        content_length: u64,
+        limits: ItemSizeLimits,
+        allowed_items: Option<Vec<String>>,
+        client: &UpstreamClient,
+        proxy: Option<&str>,
+    ) -> Result<UpstreamResponse, AError>
+    where
+        S: Stream<Item = Result<Bytes, io::Error>> + Send + Sync + Unpin + 'static,
+    {
+        let head = Bytes::from(std::mem::take(&mut self.raw_body));
+        let body = stream::once(future::ready(Ok(head))).chain(rest);
+        let body = LimitedItems::new(body, limits).with_allowed_types(allowed_items);
+        let violation = body.violation();
+        let request = self
+            .request_builder(proxy)?
+            .body(AsyncBody::from_reader_sized(body.into_async_read(), content_length))?;
+        info!(
+            "Streaming HTTP {} {} - body length={}",
+            request.method(),
+            request.uri(),
+            content_length
+        );
+        match client.get()?.send_async(request).await {
+            Ok(response) => Ok(UpstreamResponse::read(response).await),
+            Err(e) => match violation.lock().unwrap().take() {
+                Some(violation) => Err(AError::new(violation)),
+                None => Err(e.into()),
+            },
+        }
+    }
+
+    fn request_builder(&self, proxy: Option<&str>) -> Result<Builder, AError> {
+        let builder = Request::builder()
+            .uri(self.forward_url())
+            .header("Content-type", "application/x-sentry-envelope")
+            .method("POST");
+        // Without a proxy of its own, the request keeps using the one of the environment if any
+        Ok(match proxy {
+            Some(proxy) => builder.proxy(Some(proxy.parse::<Uri>()?)),
+            None => builder,
+        })
+    }
+}
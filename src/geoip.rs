@@ -0,0 +1,39 @@
+use maxminddb::geoip2;
+
+use std::fmt::{Debug, Formatter};
+use std::net::IpAddr;
+
+/**
+ * A MaxMind GeoIP2 or GeoLite2 country (or city) database
+ */
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl Debug for GeoIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "GeoIp({})",
+            self.reader.metadata.database_type
+        ))
+    }
+}
+
+impl GeoIp {
+    pub fn open(path: &str) -> Result<GeoIp, maxminddb::MaxMindDBError> {
+        Ok(GeoIp {
+            reader: maxminddb::Reader::open_readfile(path)?,
+        })
+    }
+
+    /**
+     * ISO 3166-1 code of the country the address is located in, if known
+     */
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+}
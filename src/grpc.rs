@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/**
+ * Path of the `SubmitEnvelope` method of the `sentry_tunnel.Tunnel` service, see
+ * `proto/tunnel.proto`
+ */
+pub const SUBMIT_ENVELOPE_PATH: &str = "/sentry_tunnel.Tunnel/SubmitEnvelope";
+
+// gRPC status codes, see https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_INVALID_ARGUMENT: u32 = 3;
+pub const STATUS_PERMISSION_DENIED: u32 = 7;
+pub const STATUS_RESOURCE_EXHAUSTED: u32 = 8;
+pub const STATUS_UNAVAILABLE: u32 = 14;
+pub const STATUS_UNIMPLEMENTED: u32 = 12;
+pub const STATUS_UNAUTHENTICATED: u32 = 16;
+
+/**
+ * A gRPC message decoding error
+ */
+#[derive(Debug)]
+pub enum GrpcError {
+    InvalidFrame,
+    CompressedMessage,
+    InvalidMessage,
+}
+
+impl Display for GrpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrpcError::InvalidFrame => f.write_str("Invalid gRPC message frame"),
+            GrpcError::CompressedMessage => f.write_str("Compressed gRPC messages are not supported"),
+            GrpcError::InvalidMessage => f.write_str("Invalid SubmitEnvelopeRequest message"),
+        }
+    }
+}
+
+impl Error for GrpcError {}
+
+impl GrpcError {
+    pub fn status(&self) -> u32 {
+        match self {
+            GrpcError::CompressedMessage => STATUS_UNIMPLEMENTED,
+            _ => STATUS_INVALID_ARGUMENT,
+        }
+    }
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, GrpcError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or(GrpcError::InvalidMessage)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(GrpcError::InvalidMessage)
+}
+
+fn take<'a>(buf: &'a [u8], pos: &mut usize, length: u64) -> Result<&'a [u8], GrpcError> {
+    let end = pos
+        .checked_add(length as usize)
+        .filter(|end| *end <= buf.len())
+        .ok_or(GrpcError::InvalidMessage)?;
+    let taken = &buf[*pos..end];
+    *pos = end;
+    Ok(taken)
+}
+
+/**
+ * Extract the envelope from a length prefixed `SubmitEnvelopeRequest` message
+ */
+pub fn decode_submit_envelope(body: &[u8]) -> Result<Vec<u8>, GrpcError> {
+    if body.len() < 5 {
+        return Err(GrpcError::InvalidFrame);
+    }
+    if body[0] != 0 {
+        return Err(GrpcError::CompressedMessage);
+    }
+    let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let message = body
+        .get(5..5 + length)
+        .ok_or(GrpcError::InvalidFrame)?;
+
+    let mut envelope = vec![];
+    let mut pos = 0;
+    while pos < message.len() {
+        let key = read_varint(message, &mut pos)?;
+        match (key >> 3, key & 0x7) {
+            (1, 2) => {
+                let length = read_varint(message, &mut pos)?;
+                envelope = take(message, &mut pos, length)?.to_vec();
+            }
+            (_, 0) => {
+                read_varint(message, &mut pos)?;
+            }
+            (_, 1) => {
+                take(message, &mut pos, 8)?;
+            }
+            (_, 2) => {
+                let length = read_varint(message, &mut pos)?;
+                take(message, &mut pos, length)?;
+            }
+            (_, 5) => {
+                take(message, &mut pos, 4)?;
+            }
+            _ => return Err(GrpcError::InvalidMessage),
+        }
+    }
+    Ok(envelope)
+}
+
+/**
+ * A length prefixed, empty, `SubmitEnvelopeResponse` message
+ */
+pub fn encode_submit_envelope_response() -> Vec<u8> {
+    vec![0, 0, 0, 0, 0]
+}
+
+/**
+ * Percent encode a status message for the `grpc-message` header
+ */
+pub fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
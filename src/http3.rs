@@ -0,0 +1,121 @@
+use crate::config::Config;
+use crate::server::{dispatch, ClientIdentity, MAX_STREAMED_CONTENT_SIZE};
+use crate::tls;
+use anyhow::{anyhow, Error as AError};
+use gotham::hyper::body::{self, Buf, Bytes};
+use gotham::hyper::{header, Body, Request, Response};
+use gotham::router::Router;
+use h3::error::ErrorLevel;
+use h3::server::RequestStream;
+use log::*;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// Protocols of the HTTP/3 listener
+const H3_ALPN: &[&[u8]] = &[b"h3"];
+
+/**
+ * The QUIC server configuration with the current certificate of the configuration
+ */
+fn server_config(config: &Config) -> Result<quinn::ServerConfig, AError> {
+    let tls_config = tls::server_config(config, H3_ALPN)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(tls_config)))
+}
+
+/**
+ * Serve the router over HTTP/3 on the given UDP address, with the TLS certificate of the
+ * configuration, which is reloaded when it changes. When a client CA is configured, clients must
+ * present a certificate signed by one of its CAs.
+ */
+pub async fn serve(addr: SocketAddr, router: Router, config: Config) -> Result<(), AError> {
+    let endpoint = quinn::Endpoint::server(server_config(&config)?, addr)?;
+    info!("Listening for HTTP/3 on {}", addr);
+    let reloaded = endpoint.clone();
+    tokio::spawn(async move {
+        let reload = |config: &Config| -> Result<(), AError> {
+            reloaded.set_server_config(Some(server_config(config)?));
+            Ok(())
+        };
+        if let Err(e) = tls::watch_certificate(&config, "HTTP/3", reload).await {
+            error!("Could not watch the TLS certificate : {}", e);
+        }
+    });
+
+    while let Some(connecting) = endpoint.accept().await {
+        let router = router.clone();
+        tokio::spawn(async move {
+            let result = match connecting.await {
+                Ok(connection) => handle_connection(connection, router).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!("HTTP/3 connection failed : {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_connection(connection: quinn::Connection, router: Router) -> Result<(), AError> {
+    let client_addr = connection.remote_address();
+    let certs = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok());
+    let identity = tls::client_identity(certs.as_deref().map(Vec::as_slice));
+    let mut connection: h3::server::Connection<_, Bytes> =
+        h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+    loop {
+        match connection.accept().await {
+            Ok(Some((request, stream))) => {
+                let router = router.clone();
+                let identity = identity.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        handle_request(request, stream, router, client_addr, identity).await
+                    {
+                        warn!("HTTP/3 request failed : {}", e);
+                    }
+                });
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => match e.get_error_level() {
+                ErrorLevel::ConnectionError => return Err(e.into()),
+                ErrorLevel::StreamError => continue,
+            },
+        }
+    }
+}
+
+async fn handle_request<S>(
+    request: Request<()>,
+    mut stream: RequestStream<S, Bytes>,
+    router: Router,
+    client_addr: SocketAddr,
+    identity: Option<ClientIdentity>,
+) -> Result<(), AError>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let mut content = vec![];
+    while let Some(chunk) = stream.recv_data().await? {
+        content.extend_from_slice(chunk.chunk());
+        if content.len() as u64 > MAX_STREAMED_CONTENT_SIZE {
+            return Err(anyhow!("HTTP/3 request body is too big"));
+        }
+    }
+
+    let (mut parts, ()) = request.into_parts();
+    // HTTP/3 clients are not required to announce the body length
+    parts
+        .headers
+        .entry(header::CONTENT_LENGTH)
+        .or_insert_with(|| content.len().into());
+    let request = Request::from_parts(parts, Body::from(content));
+
+    let (parts, response_body) = dispatch(&router, request, client_addr, identity).await.into_parts();
+    stream.send_response(Response::from_parts(parts, ())).await?;
+    stream.send_data(body::to_bytes(response_body).await?).await?;
+    stream.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * Status and text body of a response given to an envelope
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+/**
+ * Responses given to the last envelopes, by project and event id, so that SDK retries get the
+ * same answer without being forwarded twice
+ */
+#[derive(Debug)]
+pub struct ResponseCache {
+    window: Duration,
+    responses: Mutex<HashMap<(u64, String), (CachedResponse, Instant)>>,
+}
+
+impl ResponseCache {
+    pub fn new(window: Duration) -> ResponseCache {
+        ResponseCache {
+            window,
+            responses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, project_id: u64, event_id: &str, response: CachedResponse) {
+        let mut responses = self.responses.lock().unwrap();
+        responses.retain(|_, (_, until)| *until > Instant::now());
+        responses.insert(
+            (project_id, event_id.to_string()),
+            (response, Instant::now() + self.window),
+        );
+    }
+
+    pub fn get(&self, project_id: u64, event_id: &str) -> Option<CachedResponse> {
+        let mut responses = self.responses.lock().unwrap();
+        let key = (project_id, event_id.to_string());
+        match responses.get(&key) {
+            Some((response, until)) if *until > Instant::now() => Some(response.clone()),
+            Some(_) => {
+                responses.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+}
@@ -1,3 +1,73 @@
+#[cfg(feature = "server")]
+pub mod access;
+#[cfg(feature = "server")]
+pub mod acme;
+#[cfg(feature = "actix")]
+pub mod actix;
+pub mod allowlist;
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "axum")]
+pub mod axum;
+pub mod bans;
+pub mod canary;
+#[cfg(feature = "server")]
+pub mod compression;
+#[cfg(feature = "server")]
 pub mod config;
+#[cfg(feature = "server")]
+pub mod cors;
+#[cfg(feature = "server")]
+pub mod discovery;
+#[cfg(feature = "server")]
+pub mod drain;
 pub mod envelope;
+#[cfg(feature = "server")]
+pub mod forward;
+#[cfg(feature = "server")]
+pub mod geoip;
+pub mod grpc;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod idempotency;
+#[cfg(feature = "server")]
+pub mod lifetime;
+#[cfg(feature = "server")]
+pub mod monitoring;
+pub mod otlp;
+pub mod pacing;
+pub mod pool;
+pub mod quotas;
+pub mod ratelimits;
+pub mod redact;
+pub mod region;
+#[cfg(feature = "server")]
+pub mod reload;
+pub mod sdk;
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "server")]
+pub mod serverless;
+#[cfg(feature = "server")]
+pub mod sessions;
+pub mod signing;
+pub mod spam;
+#[cfg(feature = "server")]
+pub mod spill;
+#[cfg(feature = "server")]
+pub mod spool;
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod streaming;
+#[cfg(feature = "server")]
+pub mod telemetry;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod toggles;
+#[cfg(feature = "tower")]
+pub mod tower;
+pub mod validation;
+#[cfg(feature = "server")]
+pub mod vault;
+#[cfg(all(windows, feature = "windows-service"))]
+pub mod windows;
@@ -0,0 +1,148 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Counters are written to their file at most this often, a crash loses the last interval
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/**
+ * Envelopes and bytes of a project since the counters were first saved
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectTotals {
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub forwarded_bytes: u64,
+}
+
+/**
+ * Per project counters that survive restarts, persisted to a json file
+ */
+#[derive(Debug)]
+pub struct LifetimeStats {
+    path: String,
+    totals: Mutex<BTreeMap<u64, ProjectTotals>>,
+    dirty: AtomicBool,
+    saver_started: AtomicBool,
+}
+
+impl LifetimeStats {
+    /**
+     * Start with the counters saved at this path, if any
+     */
+    pub fn load(path: String) -> LifetimeStats {
+        let totals = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                error!("Ignoring the invalid lifetime stats of {} : {}", path, e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                error!("Could not read the lifetime stats of {} : {}", path, e);
+                BTreeMap::new()
+            }
+        };
+        LifetimeStats {
+            path,
+            totals: Mutex::new(totals),
+            dirty: AtomicBool::new(false),
+            saver_started: AtomicBool::new(false),
+        }
+    }
+
+    pub fn forwarded(self: &Arc<Self>, project_id: u64, bytes: u64) {
+        self.update(project_id, |totals| {
+            totals.forwarded += 1;
+            totals.forwarded_bytes += bytes;
+        });
+    }
+
+    pub fn dropped(self: &Arc<Self>, project_id: u64) {
+        self.update(project_id, |totals| totals.dropped += 1);
+    }
+
+    fn update<F: FnOnce(&mut ProjectTotals)>(self: &Arc<Self>, project_id: u64, f: F) {
+        f(self.totals.lock().unwrap().entry(project_id).or_default());
+        self.dirty.store(true, Ordering::Relaxed);
+        self.start_saver();
+    }
+
+    pub fn totals(&self) -> BTreeMap<u64, ProjectTotals> {
+        self.totals.lock().unwrap().clone()
+    }
+
+    fn start_saver(self: &Arc<Self>) {
+        if self.saver_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let lifetime = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = lifetime.save() {
+                    error!("Could not save the lifetime stats to {} : {}", lifetime.path, e);
+                }
+            }
+        });
+    }
+
+    /**
+     * Write the counters when they changed since the last save. The file is replaced at once, so
+     * that a crash never leaves half of it.
+     */
+    pub fn save(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec_pretty(&self.totals())?;
+        let temporary = format!("{}.tmp", self.path);
+        fs::write(&temporary, content)
+            .and_then(|_| fs::rename(&temporary, &self.path))
+            .map_err(|e| {
+                self.dirty.store(true, Ordering::Relaxed);
+                e
+            })
+    }
+
+    pub fn render(&self, rendered: &mut String) {
+        let totals = self.totals();
+        let counters: [(&str, &str, fn(&ProjectTotals) -> u64); 3] = [
+            (
+                "sentry_tunnel_lifetime_envelopes_forwarded_total",
+                "Envelopes forwarded since the lifetime stats were created, by project",
+                |totals| totals.forwarded,
+            ),
+            (
+                "sentry_tunnel_lifetime_envelopes_dropped_total",
+                "Envelopes rejected or dropped since the lifetime stats were created, by project",
+                |totals| totals.dropped,
+            ),
+            (
+                "sentry_tunnel_lifetime_forwarded_bytes_total",
+                "Bytes of the envelopes forwarded since the lifetime stats were created, by project",
+                |totals| totals.forwarded_bytes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(rendered, "# HELP {} {}", name, help);
+            let _ = writeln!(rendered, "# TYPE {} counter", name);
+            for (project_id, project_totals) in &totals {
+                let _ = writeln!(
+                    rendered,
+                    "{}{{project=\"{}\"}} {}",
+                    name,
+                    project_id,
+                    value(project_totals)
+                );
+            }
+        }
+    }
+}
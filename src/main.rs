@@ -1,39 +1,434 @@
-use futures_util::future::{self, Either, FutureExt};
+use clap::{Args, Parser, Subcommand};
+use futures_util::future::{self, Either, FutureExt, LocalBoxFuture};
+use gotham::hyper::{body, header, Body, Request};
 use log::*;
-use sentry_tunnel::config::Config;
-use sentry_tunnel::server::router;
+use sentry_tunnel::config::{Config, Host};
+use sentry_tunnel::discovery;
+use sentry_tunnel::envelope::SentryEnvelope;
+use sentry_tunnel::monitoring;
+use sentry_tunnel::redact;
+use sentry_tunnel::reload::{self, ConfigSource};
+use sentry_tunnel::server::{dispatch, reloadable_router, router, SIGNATURE_HEADER};
+use sentry_tunnel::signing;
+use sentry_tunnel::telemetry;
+use sentry_tunnel::vault;
+use sentry_types::Dsn;
 use tokio::signal;
 
-#[tokio::main]
-pub async fn main() {
-    stderrlog::new()
-        .verbosity(3)
-        .modules([module_path!()])
-        .init()
-        .unwrap(); // Error, Warn and Info
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-    match Config::new_from_env_variables() {
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Forwards the envelopes of sentry SDKs to the allowed sentry hosts and projects",
+    after_help = "Every option can also be set with its env variable, see the README."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    options: ConfigOptions,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    #[command(about = "Run the tunnel, the default")]
+    Serve,
+    #[command(about = "Load and validate the configuration, then exit")]
+    ValidateConfig,
+    #[command(about = "Print the configuration as JSON, with its secrets redacted, then exit")]
+    PrintConfig,
+    #[command(about = "Send a test event through the tunnel, or directly to sentry")]
+    SendTestEvent(TestEventOptions),
+    #[command(about = "Print the version")]
+    Version,
+}
+
+#[derive(Args, Debug)]
+struct TestEventOptions {
+    #[arg(long, help = "Project the event is sent to")]
+    project_id: u64,
+    #[arg(long, default_value = "public", help = "Public key of the dsn of the project")]
+    key: String,
+    #[arg(long, help = "Sentry host of the dsn, the first remote host by default")]
+    host: Option<String>,
+    #[arg(long, help = "Send the event to sentry without going through the tunnel checks")]
+    direct: bool,
+}
+
+/**
+ * Options overriding the configuration. They are applied as their env variables, so that they
+ * take precedence over the config file, reloads included.
+ */
+#[derive(Args, Debug)]
+struct ConfigOptions {
+    #[arg(long, global = true, help = "JSON, TOML or YAML config file [TUNNEL_CONFIG_FILE]")]
+    config: Option<String>,
+    #[arg(long, global = true, help = "Port to listen on [TUNNEL_LISTEN_PORT]")]
+    port: Option<u16>,
+    #[arg(long, global = true, help = "Interface to listen on [TUNNEL_IP]")]
+    ip: Option<String>,
+    #[arg(long, global = true, help = "Path envelopes are posted to [TUNNEL_PATH]")]
+    tunnel_path: Option<String>,
+    #[arg(long, global = true, help = "Comma separated sentry hosts [TUNNEL_REMOTE_HOST]")]
+    remote_host: Option<String>,
+    #[arg(long, global = true, help = "Comma separated project ids [TUNNEL_PROJECT_IDS]")]
+    project_ids: Option<String>,
+}
+
+impl ConfigOptions {
+    fn apply(&self) {
+        let variables = [
+            ("TUNNEL_CONFIG_FILE", self.config.clone()),
+            ("TUNNEL_LISTEN_PORT", self.port.map(|port| port.to_string())),
+            ("TUNNEL_IP", self.ip.clone()),
+            ("TUNNEL_PATH", self.tunnel_path.clone()),
+            ("TUNNEL_REMOTE_HOST", self.remote_host.clone()),
+            ("TUNNEL_PROJECT_IDS", self.project_ids.clone()),
+        ];
+        for (variable, value) in variables {
+            if let Some(value) = value {
+                std::env::set_var(variable, value);
+            }
+        }
+    }
+}
+
+pub fn main() {
+    #[cfg(all(windows, feature = "windows-service"))]
+    if std::env::args().nth(1).as_deref() == Some("service") {
+        sentry_tunnel::windows::main(|shutdown| serve(shutdown).boxed_local());
+        return;
+    }
+
+    let cli = Cli::parse();
+    cli.options.apply();
+    let mut stderr_log = stderrlog::new();
+    stderr_log.verbosity(3).modules([module_path!()]); // Error, Warn and Info
+    redact::init(stderr_log).unwrap();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let reporting = monitoring::init();
+            let runtime = tokio::runtime::Runtime::new().expect("failed to start the runtime");
+            if let Err(e) = runtime.block_on(serve(shutdown_signal().boxed_local())) {
+                error!("{}", e);
+                monitoring::capture_error(&e);
+                reporting.close();
+                std::process::exit(1)
+            }
+        }
+        Command::ValidateConfig => {
+            if let Err(e) = validate_config() {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        Command::PrintConfig => match read_config(config_file().as_deref()) {
+            Ok(config) => println!("{}", config.to_redacted_json()),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        },
+        Command::SendTestEvent(options) => {
+            let runtime = tokio::runtime::Runtime::new().expect("failed to start the runtime");
+            if let Err(e) = runtime.block_on(send_test_event(options)) {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        Command::Version => println!("sentry_tunnel {}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/**
+ * Check the configuration of the config file and env variables, without the secrets of Vault,
+ * and report every problem found
+ */
+fn validate_config() -> Result<(), String> {
+    let file = config_file();
+    let source = match &file {
+        Some(path) => format!("{} and the env variables", path),
+        None => "the env variables".to_string(),
+    };
+    match read_config(file.as_deref()) {
         Ok(config) => {
-            info!("{}", config);
-            let addr = format!("{}:{}", config.ip, config.port);
-            let signal = async {
-                signal::ctrl_c().await.expect("failed to listen for event");
+            println!("The configuration of {} is valid :\n{}", source, config);
+            Ok(())
+        }
+        Err(e) => {
+            let problems: Vec<String> = e.lines().map(|line| format!("  - {}", line)).collect();
+            Err(format!(
+                "The configuration of {} is not valid, {} problems found :\n{}",
+                source,
+                problems.len(),
+                problems.join("\n")
+            ))
+        }
+    }
+}
+
+/**
+ * Wait for SIGINT, or SIGTERM which container runtimes send to stop the tunnel
+ */
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use signal::unix::SignalKind;
+
+        let mut terminate =
+            signal::unix::signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            interrupted = signal::ctrl_c() => {
+                interrupted.expect("failed to listen for event");
                 println!("Ctrl+C pressed");
-            };
+            }
+            _ = terminate.recv() => println!("SIGTERM received"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c().await.expect("failed to listen for event");
+        println!("Ctrl+C pressed");
+    }
+}
+
+/**
+ * Run the tunnel until `shutdown` completes, then stop accepting connections and drain the
+ * requests in progress
+ */
+async fn serve(shutdown: LocalBoxFuture<'static, ()>) -> Result<(), String> {
+    let source = load_config().await?;
+    let config = source.build()?;
+    redact::add_secrets(config.secrets());
+    info!("{}", config);
+    telemetry::init(&config);
+    let addr = format!("{}:{}", config.ip, config.port);
 
-            let server = gotham::init_server(addr, move || {
-                Ok(router(&config.tunnel_path.clone(), config.clone()))
-            });
-            let res = future::select(server.boxed(), signal.boxed()).await;
-            if let Either::Left((Err(err), _)) = res {
-                println!("Error starting gotham: {:?}", err);
-            } else {
-                println!("Shutting down gracefully");
+    let (router, handle) = reloadable_router(&config.tunnel_path.clone(), config.clone());
+    #[cfg(unix)]
+    {
+        let source = source.clone();
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reload::reload_on_hangup(source, handle).await {
+                error!("Could not listen for SIGHUP : {}", e);
             }
+        });
+    }
+    if config.config_dir.is_some() || source.file.is_some() {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reload::watch(source, handle).await {
+                error!("Could not watch the configuration files : {}", e);
+            }
+        });
+    }
+    if config.discovery.is_some() {
+        tokio::spawn(discovery::keep_discovering(config.clone()));
+    }
+    if !config.acme_domains.is_empty() {
+        start_acme(&config, router.clone());
+    } else {
+        start_tls_listeners(&config, &router);
+    }
+    let server = gotham::init_server(addr, router);
+    let res = future::select(server.boxed(), shutdown).await;
+    if let Either::Left((Err(err), _)) = res {
+        println!("Error starting gotham: {:?}", err);
+    } else {
+        println!("Shutting down gracefully");
+        handle.drain(Duration::from_secs(config.drain_timeout)).await;
+    }
+    telemetry::shutdown();
+    Ok(())
+}
+
+/**
+ * Send a test event of the project to sentry. Unless `direct` is set, it goes through the router
+ * of the tunnel in process, with the auth token and signature it requires if any, so that it is
+ * checked like the envelopes of SDKs.
+ */
+async fn send_test_event(options: TestEventOptions) -> Result<(), String> {
+    let config = load_config().await?.build()?;
+    let host = match &options.host {
+        Some(host) => host.parse::<Host>()?,
+        None => config
+            .remote_hosts
+            .iter()
+            .find(|host| !host.is_pattern())
+            .cloned()
+            .ok_or_else(|| "No remote host to send the event to, set --host".to_string())?,
+    };
+    let dsn = format!(
+        "{}://{}@{}:{}/{}",
+        host.scheme, options.key, host.host, host.port, options.project_id
+    )
+    .parse::<Dsn>()
+    .map_err(|e| format!("Invalid dsn : {}", e))?;
+    let mut envelope = SentryEnvelope::test_event(&dsn);
+    println!(
+        "Sending the event {} to project {} of {}",
+        envelope.event_id().unwrap_or_default(),
+        options.project_id,
+        host
+    );
+    let (status, body) = if options.direct {
+        let response = envelope
+            .forward()
+            .await
+            .map_err(|e| format!("Could not send the event to {} : {}", host, e))?;
+        (response.status, String::from_utf8_lossy(&response.body).to_string())
+    } else {
+        let project = options.project_id.to_string();
+        let mut request = Request::post(format!("http://localhost{}", config.tunnel_path))
+            .header(header::CONTENT_TYPE, "application/x-sentry-envelope")
+            .header(header::CONTENT_LENGTH, envelope.raw_body.len());
+        let token = config.auth_tokens.iter().find(|token| {
+            !token.is_expired()
+                && token.projects.as_ref().map_or(true, |projects| projects.contains(&project))
+        });
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token.token));
         }
-        Err(e) => {
-            error!("{}", e);
-            std::process::exit(1)
+        if let Some(secret) = config.signing_secrets.get(&project) {
+            request = request.header(SIGNATURE_HEADER, signing::sign(secret, &envelope.raw_body));
         }
+        let request = request
+            .body(Body::from(std::mem::take(&mut envelope.raw_body)))
+            .map_err(|e| e.to_string())?;
+        let router = router(&config.tunnel_path.clone(), config.clone());
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let response = dispatch(&router, request, client_addr, None).await;
+        let status = response.status().as_u16();
+        let body = body::to_bytes(response.into_body()).await.unwrap_or_default();
+        (status, String::from_utf8_lossy(&body).to_string())
+    };
+    println!("{} {}", status, body);
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("The event was refused with a {} status", status))
+    }
+}
+
+/**
+ * The path of the config file, given with `--config` or TUNNEL_CONFIG_FILE
+ */
+fn config_file() -> Option<String> {
+    std::env::var("TUNNEL_CONFIG_FILE").ok()
+}
+
+/**
+ * Read the configuration from the config file, overridden by the env variables that are set, or
+ * from the env variables alone
+ */
+fn read_config(file: Option<&str>) -> Result<Config, String> {
+    match file {
+        Some(path) => Config::new_from_file_and_env_variables(path),
+        None => Config::new_from_env_variables(),
+    }
+}
+
+/**
+ * Read the configuration, then the secrets stored in Vault if any
+ */
+async fn load_config() -> Result<ConfigSource, String> {
+    let file = config_file();
+    let env = read_config(file.as_deref())?;
+    let mut vault_secrets = HashMap::new();
+    if let Some(vault_config) = env.vault.clone() {
+        let lease = vault::login(&vault_config)
+            .await
+            .map_err(|e| format!("Could not log in to Vault : {}", e))?;
+        redact::add_secrets(vec![lease.token.clone()]);
+        let secrets = vault::read_secret(&vault_config, &lease)
+            .await
+            .map_err(|e| format!("Could not read the secrets from Vault : {}", e))?;
+        info!("Read {} secrets from Vault", secrets.len());
+        vault_secrets = secrets;
+        tokio::spawn(vault::keep_renewed(vault_config, lease));
+    }
+    Ok(ConfigSource {
+        env,
+        file,
+        vault_secrets,
+    })
+}
+
+/**
+ * Start the listeners that need the TLS certificate, HTTPS and HTTP/3, when their port is set
+ */
+fn start_tls_listeners(config: &Config, router: &gotham::router::Router) {
+    if let Some(tls_port) = config.tls_port {
+        start_tls(config, tls_port, router.clone());
     }
+    if let Some(h3_port) = config.h3_port {
+        start_http3(config, h3_port, router.clone());
+    }
+}
+
+#[cfg(feature = "tls")]
+fn start_tls(config: &Config, port: u16, router: gotham::router::Router) {
+    let addr = match format!("{}:{}", config.ip, port).parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Invalid HTTPS listen address : {}", e);
+            return;
+        }
+    };
+    let config = config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = sentry_tunnel::tls::serve(addr, router, config).await {
+            error!("Error starting the HTTPS listener : {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "tls"))]
+fn start_tls(_config: &Config, _port: u16, _router: gotham::router::Router) {
+    error!("TUNNEL_TLS_PORT is set but this build does not include the 'tls' feature");
+}
+
+#[cfg(feature = "http3")]
+fn start_http3(config: &Config, port: u16, router: gotham::router::Router) {
+    let addr = match format!("{}:{}", config.ip, port).parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Invalid HTTP/3 listen address : {}", e);
+            return;
+        }
+    };
+    let config = config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = sentry_tunnel::http3::serve(addr, router, config).await {
+            error!("Error starting the HTTP/3 listener : {}", e);
+        }
+    });
+}
+
+#[cfg(not(feature = "http3"))]
+fn start_http3(_config: &Config, _port: u16, _router: gotham::router::Router) {
+    error!("TUNNEL_H3_PORT is set but this build does not include the 'http3' feature");
+}
+
+/**
+ * Obtain the certificate before starting the TLS listeners, then keep it renewed
+ */
+#[cfg(feature = "acme")]
+fn start_acme(config: &Config, router: gotham::router::Router) {
+    let config = config.clone();
+    tokio::spawn(async move {
+        sentry_tunnel::acme::wait_for_certificate(&config).await;
+        start_tls_listeners(&config, &router);
+        sentry_tunnel::acme::keep_renewed(config).await;
+    });
+}
+
+#[cfg(not(feature = "acme"))]
+fn start_acme(_config: &Config, _router: gotham::router::Router) {
+    error!("TUNNEL_ACME_DOMAINS is set but this build does not include the 'acme' feature");
 }
@@ -0,0 +1,79 @@
+use log::*;
+
+/**
+ * Env variable holding the dsn the tunnel reports its own errors to
+ */
+pub const DSN_VARIABLE: &str = "SENTRY_TUNNEL_DSN";
+
+/**
+ * Keeps reporting to sentry until dropped, which sends the events still pending
+ */
+#[must_use]
+pub struct Guard {
+    #[cfg(feature = "self-monitoring")]
+    _client: Option<sentry::ClientInitGuard>,
+}
+
+impl Guard {
+    /**
+     * Send the events still pending, before the process exits without running destructors
+     */
+    pub fn close(self) {
+        #[cfg(feature = "self-monitoring")]
+        drop(self._client);
+    }
+}
+
+/**
+ * Report the panics and errors of the tunnel to the dsn of SENTRY_TUNNEL_DSN, if set. It is read
+ * before the rest of the configuration so that configuration errors are reported too.
+ */
+#[cfg(feature = "self-monitoring")]
+pub fn init() -> Guard {
+    use sentry::types::Dsn;
+    use std::str::FromStr;
+
+    let dsn = match std::env::var(DSN_VARIABLE) {
+        Ok(dsn) if !dsn.trim().is_empty() => dsn,
+        _ => return Guard { _client: None },
+    };
+    let dsn = match Dsn::from_str(dsn.trim()) {
+        Ok(dsn) => dsn,
+        Err(e) => {
+            error!("Invalid '{}', errors are not reported : {}", DSN_VARIABLE, e);
+            return Guard { _client: None };
+        }
+    };
+    info!("Reporting the errors of the tunnel to {}", dsn.host());
+    let client = sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn),
+        release: sentry::release_name!(),
+        ..Default::default()
+    });
+    Guard {
+        _client: Some(client),
+    }
+}
+
+#[cfg(not(feature = "self-monitoring"))]
+pub fn init() -> Guard {
+    if std::env::var(DSN_VARIABLE).is_ok_and(|dsn| !dsn.trim().is_empty()) {
+        error!(
+            "{} is set but this build does not include the 'self-monitoring' feature",
+            DSN_VARIABLE
+        );
+    }
+    Guard {}
+}
+
+/**
+ * Report an error of the tunnel itself, once its credentials are redacted. Does nothing when
+ * self-monitoring is not enabled.
+ */
+#[cfg(feature = "self-monitoring")]
+pub fn capture_error(message: &str) {
+    sentry::capture_message(&crate::redact::redact(message), sentry::Level::Error);
+}
+
+#[cfg(not(feature = "self-monitoring"))]
+pub fn capture_error(_message: &str) {}
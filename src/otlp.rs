@@ -0,0 +1,301 @@
+use crate::envelope::SentryEnvelope;
+use sentry_types::{Dsn, Utc, Uuid};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Map, Value};
+
+use std::collections::{HashMap, HashSet};
+
+/**
+ * An OTLP/HTTP trace export request, using the JSON encoding
+ */
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportTraceServiceRequest {
+    #[serde(default)]
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    #[serde(default)]
+    resource: Resource,
+    #[serde(default)]
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Resource {
+    #[serde(default)]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScopeSpans {
+    #[serde(default)]
+    spans: Vec<Span>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyValue {
+    key: String,
+    #[serde(default)]
+    value: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    #[serde(default)]
+    parent_span_id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    kind: u8,
+    #[serde(deserialize_with = "deserialize_nanos")]
+    start_time_unix_nano: u64,
+    #[serde(deserialize_with = "deserialize_nanos")]
+    end_time_unix_nano: u64,
+    #[serde(default)]
+    attributes: Vec<KeyValue>,
+    #[serde(default)]
+    status: Status,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Status {
+    #[serde(default)]
+    code: u8,
+}
+
+/**
+ * 64 bits integers are encoded as strings in OTLP/JSON, but some exporters use numbers
+ */
+fn deserialize_nanos<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(nanos) => nanos.parse().map_err(serde::de::Error::custom),
+        Value::Number(nanos) => nanos
+            .as_u64()
+            .ok_or_else(|| serde::de::Error::custom("invalid timestamp")),
+        _ => Err(serde::de::Error::custom("invalid timestamp")),
+    }
+}
+
+/**
+ * Convert an OTLP `AnyValue` into plain json
+ */
+fn any_value(value: &Value) -> Value {
+    let (kind, inner) = match value.as_object().and_then(|value| value.iter().next()) {
+        Some(entry) => entry,
+        None => return Value::Null,
+    };
+    match kind.as_str() {
+        "intValue" => match inner {
+            Value::String(int) => int.parse::<i64>().map(Value::from).unwrap_or(Value::Null),
+            other => other.clone(),
+        },
+        "arrayValue" => Value::Array(
+            inner
+                .get("values")
+                .and_then(Value::as_array)
+                .map(|values| values.iter().map(any_value).collect())
+                .unwrap_or_default(),
+        ),
+        "kvlistValue" => Value::Object(
+            inner
+                .get("values")
+                .and_then(Value::as_array)
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|kv| {
+                            let key = kv.get("key")?.as_str()?.to_string();
+                            Some((key, any_value(kv.get("value")?)))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ),
+        _ => inner.clone(),
+    }
+}
+
+fn attributes(attributes: &[KeyValue]) -> Map<String, Value> {
+    attributes
+        .iter()
+        .map(|kv| (kv.key.clone(), any_value(&kv.value)))
+        .collect()
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1_000_000_000.0
+}
+
+impl Span {
+    fn op(&self) -> &'static str {
+        match self.kind {
+            2 => "server",
+            3 => "client",
+            4 => "producer",
+            5 => "consumer",
+            _ => "internal",
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match self.status.code {
+            2 => "internal_error",
+            _ => "ok",
+        }
+    }
+
+    fn to_sentry_span(&self) -> Value {
+        json!({
+            "trace_id": self.trace_id,
+            "span_id": self.span_id,
+            "parent_span_id": self.parent_span_id,
+            "op": self.op(),
+            "description": self.name,
+            "start_timestamp": seconds(self.start_time_unix_nano),
+            "timestamp": seconds(self.end_time_unix_nano),
+            "status": self.status(),
+            "data": attributes(&self.attributes),
+        })
+    }
+}
+
+/**
+ * A sentry transaction built from a root span and its descendants
+ */
+pub struct Transaction {
+    pub span_count: usize,
+    pub envelope: SentryEnvelope,
+}
+
+impl ExportTraceServiceRequest {
+    /**
+     * Group spans into transactions : every span whose parent is not part of the request is the
+     * root of a transaction, the other spans are attached to the transaction of their root.
+     */
+    pub fn into_transactions(self, dsn: &Dsn) -> Vec<Transaction> {
+        let mut transactions = vec![];
+        for resource_spans in self.resource_spans {
+            let resource = attributes(&resource_spans.resource.attributes);
+            let spans: Vec<Span> = resource_spans
+                .scope_spans
+                .into_iter()
+                .flat_map(|scope_spans| scope_spans.spans)
+                .collect();
+            let ids: HashSet<(&str, &str)> = spans
+                .iter()
+                .map(|span| (span.trace_id.as_str(), span.span_id.as_str()))
+                .collect();
+            let parents: HashMap<(&str, &str), &str> = spans
+                .iter()
+                .map(|span| {
+                    (
+                        (span.trace_id.as_str(), span.span_id.as_str()),
+                        span.parent_span_id.as_str(),
+                    )
+                })
+                .collect();
+            let mut children: HashMap<(&str, &str), Vec<Value>> = HashMap::new();
+            let mut roots = vec![];
+            for span in &spans {
+                let trace_id = span.trace_id.as_str();
+                let mut root = span.span_id.as_str();
+                let mut depth = 0;
+                while let Some(parent) = parents.get(&(trace_id, root)) {
+                    if !ids.contains(&(trace_id, *parent)) || depth > spans.len() {
+                        break;
+                    }
+                    root = parent;
+                    depth += 1;
+                }
+                if root == span.span_id {
+                    roots.push(span);
+                } else {
+                    children
+                        .entry((trace_id, root))
+                        .or_default()
+                        .push(span.to_sentry_span());
+                }
+            }
+            for root in roots {
+                let children = children
+                    .remove(&(root.trace_id.as_str(), root.span_id.as_str()))
+                    .unwrap_or_default();
+                transactions.push(transaction(dsn, root, children, &resource));
+            }
+        }
+        transactions
+    }
+}
+
+fn transaction(
+    dsn: &Dsn,
+    root: &Span,
+    spans: Vec<Value>,
+    resource: &Map<String, Value>,
+) -> Transaction {
+    let event_id = Uuid::new_v4().to_simple().to_string();
+    let mut trace = json!({
+        "trace_id": root.trace_id,
+        "span_id": root.span_id,
+        "op": root.op(),
+        "status": root.status(),
+        "data": attributes(&root.attributes),
+    });
+    if !root.parent_span_id.is_empty() {
+        trace["parent_span_id"] = json!(root.parent_span_id);
+    }
+    let span_count = spans.len() + 1;
+    let mut event = json!({
+        "type": "transaction",
+        "event_id": event_id,
+        "platform": "other",
+        "transaction": root.name,
+        "start_timestamp": seconds(root.start_time_unix_nano),
+        "timestamp": seconds(root.end_time_unix_nano),
+        "contexts": {
+            "trace": trace,
+            "otel": { "resource": resource },
+        },
+        "spans": spans,
+    });
+    if let Some(release) = resource.get("service.version") {
+        event["release"] = release.clone();
+    }
+    if let Some(environment) = resource.get("deployment.environment") {
+        event["environment"] = environment.clone();
+    }
+    if let Some(service) = resource.get("service.name") {
+        event["tags"] = json!({ "service.name": service });
+    }
+
+    let payload = serde_json::to_vec(&event).unwrap_or_default();
+    let header = json!({
+        "event_id": event_id,
+        "dsn": dsn.to_string(),
+        "sent_at": Utc::now(),
+    });
+    let item_header = json!({
+        "type": "transaction",
+        "length": payload.len(),
+    });
+    let mut raw_body = format!("{}\n{}\n", header, item_header).into_bytes();
+    raw_body.extend_from_slice(&payload);
+    raw_body.push(b'\n');
+    Transaction {
+        span_count,
+        envelope: SentryEnvelope {
+            raw_body,
+            dsn: dsn.clone(),
+        },
+    }
+}
@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * The envelope would have waited longer than allowed for its turn
+ */
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl Error for QueueFull {}
+
+impl Display for QueueFull {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Too many envelopes are waiting to be forwarded.")
+    }
+}
+
+/**
+ * Leaky bucket spreading the upstream requests evenly, so that bursts reach sentry at a steady
+ * rate instead of all at once
+ */
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    max_delay: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    /**
+     * Let `rate` requests through per second, delaying each one by at most `max_delay`
+     */
+    pub fn new(rate: u64, max_delay: Duration) -> Pacer {
+        Pacer {
+            interval: Duration::from_secs(1) / rate.max(1) as u32,
+            max_delay,
+            next: Mutex::new(None),
+        }
+    }
+
+    /**
+     * Book the next free slot, returning how long to wait for it
+     */
+    pub fn reserve(&self) -> Result<Duration, QueueFull> {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let slot = next.filter(|next| *next > now).unwrap_or(now);
+        let delay = slot - now;
+        if delay > self.max_delay {
+            return Err(QueueFull);
+        }
+        *next = Some(slot + self.interval);
+        Ok(delay)
+    }
+}
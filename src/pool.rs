@@ -0,0 +1,55 @@
+use std::sync::Mutex;
+
+/**
+ * Capacities of the pooled buffers. Bodies bigger than the last class get their own allocation.
+ */
+pub const SIZE_CLASSES: &[usize] = &[4 * 1024, 64 * 1024, 1024 * 1024];
+
+/**
+ * Buffers reused to read request bodies, at most `max_buffers` of each size class are kept
+ */
+#[derive(Debug)]
+pub struct BufferPool {
+    classes: Vec<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> BufferPool {
+        BufferPool {
+            classes: SIZE_CLASSES.iter().map(|_| Mutex::new(vec![])).collect(),
+            max_buffers,
+        }
+    }
+
+    /**
+     * An empty buffer able to hold `size` bytes, reused from the pool when one is available
+     */
+    pub fn take(&self, size: usize) -> Vec<u8> {
+        match SIZE_CLASSES.iter().position(|class| *class >= size) {
+            Some(class) => self.classes[class]
+                .lock()
+                .unwrap()
+                .pop()
+                .unwrap_or_else(|| Vec::with_capacity(SIZE_CLASSES[class])),
+            None => Vec::with_capacity(size),
+        }
+    }
+
+    /**
+     * Hand a buffer back to the pool. It is dropped when the pool is full, or when it is too big
+     * for the size class it would fall in.
+     */
+    pub fn give_back(&self, mut buffer: Vec<u8>) {
+        let capacity = buffer.capacity();
+        let class = match SIZE_CLASSES.iter().rposition(|class| *class <= capacity) {
+            Some(class) if capacity <= 2 * SIZE_CLASSES[class] => class,
+            _ => return,
+        };
+        let mut buffers = self.classes[class].lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use log::*;
+use sentry_types::Utc;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Dropped events are logged at most once per period and project
+const LOG_PERIOD: Duration = Duration::from_secs(60);
+
+/**
+ * An event was dropped because its project used up its quota
+ */
+#[derive(Debug)]
+pub enum QuotaError {
+    DailyQuotaExceeded(u64),
+    MonthlyQuotaExceeded(u64),
+}
+
+impl Error for QuotaError {}
+
+impl Display for QuotaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaError::DailyQuotaExceeded(quota) => f.write_fmt(format_args!(
+                "rate_limited : the daily quota of {} events of this project is exceeded",
+                quota
+            )),
+            QuotaError::MonthlyQuotaExceeded(quota) => f.write_fmt(format_args!(
+                "rate_limited : the monthly quota of {} events of this project is exceeded",
+                quota
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    day: String,
+    daily: u64,
+    month: String,
+    monthly: u64,
+    dropped: u64,
+    last_log: Option<Instant>,
+}
+
+/**
+ * Counts the events forwarded for each project, per UTC day and month
+ */
+#[derive(Debug)]
+pub struct Quotas {
+    daily: HashMap<String, u64>,
+    monthly: HashMap<String, u64>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Quotas {
+    pub fn new(daily: HashMap<String, u64>, monthly: HashMap<String, u64>) -> Quotas {
+        Quotas {
+            daily,
+            monthly,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Count one more event for the project, or fail if one of its quotas is already used up
+     */
+    pub fn consume(&self, project_id: u64) -> Result<(), QuotaError> {
+        let project_id = format!("{}", project_id);
+        let daily = self.daily.get(&project_id).copied();
+        let monthly = self.monthly.get(&project_id).copied();
+        if daily.is_none() && monthly.is_none() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(project_id.clone()).or_default();
+        if usage.day != day {
+            usage.day = day;
+            usage.daily = 0;
+        }
+        if usage.month != month {
+            usage.month = month;
+            usage.monthly = 0;
+        }
+
+        let exceeded = match (daily, monthly) {
+            (Some(quota), _) if usage.daily >= quota => Some(QuotaError::DailyQuotaExceeded(quota)),
+            (_, Some(quota)) if usage.monthly >= quota => {
+                Some(QuotaError::MonthlyQuotaExceeded(quota))
+            }
+            _ => None,
+        };
+        match exceeded {
+            None => {
+                usage.daily += 1;
+                usage.monthly += 1;
+                Ok(())
+            }
+            Some(e) => {
+                usage.dropped += 1;
+                let log = match usage.last_log {
+                    Some(last_log) => last_log.elapsed() >= LOG_PERIOD,
+                    None => true,
+                };
+                if log {
+                    warn!(
+                        "{} - Project = {}, {} events dropped so far",
+                        e, project_id, usage.dropped
+                    );
+                    usage.last_log = Some(Instant::now());
+                }
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use log::*;
+use sentry_types::Dsn;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Seconds a 429 response without a `Retry-After` header limits every category for
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+/**
+ * Every item of the envelope belongs to a category sentry rate limited
+ */
+#[derive(Debug)]
+pub struct RateLimited {
+    // Seconds until the last of the limits hit expires
+    pub retry_after: u64,
+    // The limits hit, as an `X-Sentry-Rate-Limits` header
+    pub rate_limits: String,
+}
+
+impl Error for RateLimited {}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "rate_limited : sentry rate limited this envelope for {} more seconds",
+            self.retry_after
+        ))
+    }
+}
+
+/**
+ * The rate limit category of an envelope item type
+ */
+pub fn category(item_type: &str) -> &str {
+    match item_type {
+        "event" => "error",
+        "session" | "sessions" => "session",
+        "replay_event" | "replay_recording" | "replay_video" => "replay",
+        "check_in" => "monitor",
+        "client_report" => "internal",
+        "statsd" | "metric_meta" => "metric_bucket",
+        "log" => "log_item",
+        "user_report" | "feedback" => "user_report_v2",
+        "profile_chunk" => "profile_chunk",
+        other => other,
+    }
+}
+
+/**
+ * A limit announced by sentry, for every category when `categories` is empty
+ */
+#[derive(Debug)]
+struct Limit {
+    categories: Vec<String>,
+    scope: String,
+    until: Instant,
+}
+
+impl Limit {
+    fn covers(&self, category: &str) -> bool {
+        self.categories.is_empty() || self.categories.iter().any(|limited| limited == category)
+    }
+}
+
+/**
+ * Parse an `X-Sentry-Rate-Limits` header, made of `retry_after:categories:scope:...` entries
+ */
+fn parse(header: &str) -> Vec<(Duration, Vec<String>, String)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.trim().split(':');
+            let retry_after = parts
+                .next()?
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|retry_after| retry_after.is_finite())?;
+            let categories = parts
+                .next()
+                .unwrap_or_default()
+                .split(';')
+                .map(str::trim)
+                .filter(|category| !category.is_empty())
+                .map(String::from)
+                .collect();
+            let scope = match parts.next().map(str::trim) {
+                Some(scope @ ("project" | "key")) => scope.to_string(),
+                _ => "organization".to_string(),
+            };
+            Some((Duration::from_secs_f64(retry_after.max(0.0)), categories, scope))
+        })
+        .collect()
+}
+
+/**
+ * The keys of the limits applying to a dsn, one per scope
+ */
+fn scope_key(dsn: &Dsn, scope: &str) -> String {
+    match scope {
+        "key" => format!("{}/{}/{}", dsn.host(), dsn.project_id(), dsn.public_key()),
+        "project" => format!("{}/{}", dsn.host(), dsn.project_id()),
+        _ => dsn.host().to_string(),
+    }
+}
+
+/**
+ * Rate limits sentry answered with, remembered until they expire so that the envelopes they
+ * apply to are rejected without being forwarded, like a sentry relay does
+ */
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    limits: Mutex<HashMap<String, Vec<Limit>>>,
+}
+
+impl RateLimits {
+    /**
+     * Remember the limits of a response of sentry to an envelope of this dsn
+     */
+    pub fn update(
+        &self,
+        dsn: &Dsn,
+        status: u16,
+        rate_limits: Option<&str>,
+        retry_after: Option<&str>,
+    ) {
+        let mut announced = rate_limits.map(parse).unwrap_or_default();
+        if announced.is_empty() && status == 429 {
+            let retry_after = retry_after
+                .and_then(|retry_after| retry_after.trim().parse::<u64>().ok())
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            announced.push((
+                Duration::from_secs(retry_after),
+                vec![],
+                "organization".to_string(),
+            ));
+        }
+        if announced.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut limits = self.limits.lock().unwrap();
+        limits.retain(|_, limits| {
+            limits.retain(|limit| limit.until > now);
+            !limits.is_empty()
+        });
+        for (duration, categories, scope) in announced {
+            debug!(
+                "Sentry rate limited {:?} of {} for {:?} ({})",
+                categories,
+                dsn.host(),
+                duration,
+                scope
+            );
+            limits.entry(scope_key(dsn, &scope)).or_default().push(Limit {
+                categories,
+                scope,
+                until: now + duration,
+            });
+        }
+    }
+
+    /**
+     * Whether the items of this category, sent with this dsn, are rate limited
+     */
+    pub fn is_limited(&self, dsn: &Dsn, category: &str) -> bool {
+        self.check(dsn, &[category]).is_err()
+    }
+
+    /**
+     * Fail when every one of the categories is rate limited for this dsn. With no category, only
+     * the limits applying to every category are checked.
+     */
+    pub fn check(&self, dsn: &Dsn, categories: &[&str]) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        let limits = self.limits.lock().unwrap();
+        let active: Vec<&Limit> = ["key", "project", "organization"]
+            .iter()
+            .filter_map(|scope| limits.get(&scope_key(dsn, scope)))
+            .flatten()
+            .filter(|limit| limit.until > now)
+            .collect();
+        let mut hit: Vec<&Limit> = vec![];
+        if categories.is_empty() {
+            hit.extend(active.iter().copied().filter(|limit| limit.categories.is_empty()));
+        } else {
+            for category in categories {
+                let covering: Vec<&Limit> = active
+                    .iter()
+                    .copied()
+                    .filter(|limit| limit.covers(category))
+                    .collect();
+                if covering.is_empty() {
+                    return Ok(());
+                }
+                for limit in covering {
+                    if !hit.iter().any(|known| std::ptr::eq(*known, limit)) {
+                        hit.push(limit);
+                    }
+                }
+            }
+        }
+        let retry_after = match hit.iter().map(|limit| limit.until - now).max() {
+            Some(remaining) => remaining.as_secs_f64().ceil() as u64,
+            None => return Ok(()),
+        };
+        let rate_limits = hit
+            .iter()
+            .map(|limit| {
+                format!(
+                    "{}:{}:{}",
+                    (limit.until - now).as_secs_f64().ceil() as u64,
+                    limit.categories.join(";"),
+                    limit.scope
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(RateLimited {
+            retry_after,
+            rate_limits,
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::Serializer;
+
+use std::sync::RwLock;
+
+/**
+ * Replacement of the credentials removed from log lines
+ */
+pub const REDACTED: &str = "[redacted]";
+
+/**
+ * Query parameters and header fields holding a credential
+ */
+const CREDENTIAL_PARAMS: &[&str] = &["sentry_key=", "sentry_secret=", "Bearer "];
+
+/**
+ * Secrets known from the configuration, auth tokens for instance
+ */
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/**
+ * Scrub those secrets from every log line from now on
+ */
+pub fn add_secrets<I>(secrets: I)
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut known = SECRETS.write().unwrap();
+    for secret in secrets {
+        if !secret.is_empty() && !known.contains(&secret) {
+            known.push(secret);
+        }
+    }
+    // Longest first, so that a secret containing another one is fully redacted
+    known.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+}
+
+fn ends_credential(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '&' | ',' | ';' | '"' | '\'' | ')' | '>')
+}
+
+/**
+ * Remove the user info (the public key of a dsn) of the urls of the line
+ */
+fn redact_user_info(line: &str) -> String {
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(scheme_end) = rest.find("://") {
+        let (before, after) = rest.split_at(scheme_end + 3);
+        redacted.push_str(before);
+        let authority_end = after
+            .find(|c: char| c == '/' || c == '?' || c == '#' || ends_credential(c))
+            .unwrap_or(after.len());
+        match after[..authority_end].rfind('@') {
+            Some(at) => {
+                redacted.push_str(REDACTED);
+                rest = &after[at..];
+            }
+            None => rest = after,
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/**
+ * Remove the value following every occurrence of the parameter
+ */
+fn redact_param(line: &str, param: &str) -> String {
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(param) {
+        let (before, after) = rest.split_at(start + param.len());
+        redacted.push_str(before);
+        let value_end = after.find(ends_credential).unwrap_or(after.len());
+        if value_end > 0 {
+            redacted.push_str(REDACTED);
+        }
+        rest = &after[value_end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/**
+ * Remove dsn keys, `sentry_key` parameters, bearer tokens and known secrets from a log line
+ */
+pub fn redact(line: &str) -> String {
+    let mut redacted = redact_user_info(line);
+    for param in CREDENTIAL_PARAMS {
+        redacted = redact_param(&redacted, param);
+    }
+    for secret in SECRETS.read().unwrap().iter() {
+        redacted = redacted.replace(secret.as_str(), REDACTED);
+    }
+    redacted
+}
+
+/**
+ * Serialize a secret of the configuration as the replacement, for the fields of `serialize_with`
+ */
+pub fn secret<S: Serializer, T>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+/**
+ * Serialize an optional secret as the replacement when it is set
+ */
+pub fn optional_secret<S: Serializer, T>(
+    secret: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+/**
+ * Logger redacting every line before handing it to the wrapped one
+ */
+#[derive(Debug)]
+pub struct RedactingLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> Log for RedactingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = redact(&record.args().to_string());
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{}", line))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/**
+ * Install the logger as the global one, the wrapped logger deciding which records are written
+ */
+pub fn init<L: Log + 'static>(inner: L) -> Result<(), SetLoggerError> {
+    log::set_logger(Box::leak(Box::new(RedactingLogger { inner })))?;
+    log::set_max_level(LevelFilter::Trace);
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+// Region of the ingest hosts without one, like `o123.ingest.sentry.io`
+pub const DEFAULT_REGION: &str = "us";
+
+/**
+ * The envelope is sent to a sentry.io region whose envelopes are rejected
+ */
+#[derive(Debug)]
+pub struct DeniedRegion(pub String);
+
+impl Error for DeniedRegion {}
+
+impl Display for DeniedRegion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "Envelopes for the sentry.io region '{}' are not accepted.",
+            self.0
+        ))
+    }
+}
+
+/**
+ * Data residency region of a sentry.io host, `de` for `o123.ingest.de.sentry.io` and `us` for
+ * `o123.ingest.us.sentry.io` or `o123.ingest.sentry.io`. None for hosts that are not on sentry.io.
+ */
+pub fn of_host(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let subdomain = if host == "sentry.io" {
+        ""
+    } else {
+        host.strip_suffix(".sentry.io")?
+    };
+    match subdomain.rsplit('.').next() {
+        Some(label) if label.len() == 2 => Some(label.to_string()),
+        _ => Some(DEFAULT_REGION.to_string()),
+    }
+}
+
+/**
+ * The proxy to forward the envelopes of this host through, when its region has one
+ */
+pub fn proxy<'a>(proxies: &'a HashMap<String, String>, host: &str) -> Option<&'a str> {
+    of_host(host)
+        .and_then(|region| proxies.get(&region))
+        .map(String::as_str)
+}
@@ -0,0 +1,135 @@
+use crate::config::{Config, RESTART_FIELDS};
+use crate::monitoring;
+use crate::redact;
+use crate::server::ConfigHandle;
+use anyhow::{anyhow, Error as AError};
+use log::*;
+use notify::{Event, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/**
+ * Everything the configuration is built from : the config file and the env variables, the files
+ * of the config directory, and the Vault secrets that are only read at startup
+ */
+#[derive(Clone, Debug)]
+pub struct ConfigSource {
+    pub env: Config,
+    // Read again on each build, `env` is the configuration it was first read into
+    pub file: Option<String>,
+    pub vault_secrets: HashMap<String, String>,
+}
+
+impl ConfigSource {
+    pub fn build(&self) -> Result<Config, String> {
+        let env = match &self.file {
+            Some(path) => Config::new_from_file_and_env_variables(path)?,
+            None => self.env.clone(),
+        };
+        let mut config = match &env.config_dir {
+            Some(dir) => env.with_files(dir)?,
+            None => env,
+        };
+        config.apply_vault_secrets(&self.vault_secrets)?;
+        Ok(config)
+    }
+
+    /**
+     * Build the configuration again and replace the one of the router with it. An invalid
+     * configuration is logged and the current one is kept.
+     */
+    fn reload(&self, handle: &ConfigHandle, trigger: &str) {
+        match self.build() {
+            Ok(mut config) => {
+                let current = handle.current();
+                config.keep_runtime_state(&current);
+                let changed = current.changed_fields(&config);
+                if changed.is_empty() {
+                    debug!("The configuration {} is unchanged", trigger);
+                    return;
+                }
+                redact::add_secrets(config.secrets());
+                let (restart, live): (Vec<String>, Vec<String>) = changed
+                    .into_iter()
+                    .partition(|field| RESTART_FIELDS.contains(&field.as_str()));
+                if !live.is_empty() {
+                    info!("Reloaded the configuration {}, changed : {}", trigger, live.join(", "));
+                }
+                if !restart.is_empty() {
+                    warn!(
+                        "The configuration {} changed fields only applied after a restart : {}",
+                        trigger,
+                        restart.join(", ")
+                    );
+                }
+                handle.replace(config);
+            }
+            Err(e) => {
+                let message = format!(
+                    "Could not reload the configuration {}, keeping the current one : {}",
+                    trigger, e
+                );
+                error!("{}", message);
+                monitoring::capture_error(&message);
+            }
+        }
+    }
+}
+
+/**
+ * Replace the configuration of the router whenever the tunnel receives a SIGHUP signal
+ */
+#[cfg(unix)]
+pub async fn reload_on_hangup(source: ConfigSource, handle: ConfigHandle) -> Result<(), AError> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        source.reload(&handle, "on SIGHUP");
+    }
+    Ok(())
+}
+
+/**
+ * Watch the config file and the config directory, and replace the configuration of the router
+ * whenever their files change. An invalid configuration is logged and the current one is kept.
+ */
+pub async fn watch(source: ConfigSource, handle: ConfigHandle) -> Result<(), AError> {
+    let mut dirs = vec![];
+    if let Some(file) = &source.file {
+        dirs.push(match Path::new(file).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            // A file of the working directory
+            _ => PathBuf::from("."),
+        });
+    }
+    if let Some(dir) = &source.env.config_dir {
+        dirs.push(PathBuf::from(dir));
+    }
+    if dirs.is_empty() {
+        return Err(anyhow!("No config file or directory to watch"));
+    }
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if event.is_ok() {
+            let _ = sender.send(());
+        }
+    })?;
+    // Kubernetes replaces the files of a mount by swapping a symlink in the directory, editors
+    // often replace the file, so the directories are watched rather than the files
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        info!("Watching {} for configuration changes", dir.display());
+    }
+    while changes.recv().await.is_some() {
+        // Wait for the rest of the update before reading the files
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        while changes.try_recv().is_ok() {}
+        // Changes of the other files of the directories leave the configuration unchanged
+        source.reload(&handle, "after a file changed");
+    }
+    Ok(())
+}
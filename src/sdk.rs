@@ -0,0 +1,88 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/**
+ * An SDK allowed to submit envelopes, from its optional minimal version
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdkRule {
+    pub name: String,
+    pub min_version: Option<Vec<u64>>,
+}
+
+impl FromStr for SdkRule {
+    type Err = String;
+
+    /**
+     * Parse `name` or `name>=version`, `sentry.javascript.browser>=7` for instance
+     */
+    fn from_str(entry: &str) -> Result<SdkRule, String> {
+        let entry = entry.trim();
+        let (name, min_version) = match entry.split_once(">=") {
+            Some((name, version)) => match parse_version(version.trim()) {
+                Some(version) => (name.trim(), Some(version)),
+                None => return Err(format!("Invalid SDK version : {}", entry)),
+            },
+            None => (entry, None),
+        };
+        if name.is_empty() {
+            return Err(format!("Invalid SDK rule, expected 'name' or 'name>=version' : {}", entry));
+        }
+        Ok(SdkRule {
+            name: name.to_string(),
+            min_version,
+        })
+    }
+}
+
+impl Display for SdkRule {
+    /**
+     * The rule as it is configured, `name` or `name>=version`
+     */
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.min_version {
+            Some(version) => {
+                let version: Vec<String> = version.iter().map(u64::to_string).collect();
+                write!(f, "{}>={}", self.name, version.join("."))
+            }
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/**
+ * The numeric components of a version, without its pre-release or build suffix
+ */
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let release = version.split(|c| c == '-' || c == '+').next()?;
+    release
+        .split('.')
+        .map(|component| u64::from_str(component).ok())
+        .collect()
+}
+
+/**
+ * Returns true if the SDK matches one of the rules, or if there are none. Versions are compared
+ * component by component, missing components counting as 0.
+ */
+pub fn is_allowed(rules: &[SdkRule], name: &str, version: &str) -> bool {
+    if rules.is_empty() {
+        return true;
+    }
+    let version = parse_version(version);
+    rules.iter().filter(|rule| rule.name == name).any(|rule| {
+        match (&rule.min_version, &version) {
+            (None, _) => true,
+            (Some(min_version), Some(version)) => {
+                let len = min_version.len().max(version.len());
+                let padded = |v: &[u64]| {
+                    let mut v = v.to_vec();
+                    v.resize(len, 0);
+                    v
+                };
+                padded(version) >= padded(min_version)
+            }
+            (Some(_), None) => false,
+        }
+    })
+}
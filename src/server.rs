@@ -2,41 +2,223 @@ use anyhow::Error as AError;
 
 use gotham::handler::HandlerResult;
 use gotham::handler::IntoResponse;
+use gotham::handler::{Handler, NewHandler};
 use gotham::helpers::http::response::create_empty_response;
 use gotham::helpers::http::response::create_response;
-use gotham::hyper::{body, header, Body, HeaderMap, Response, StatusCode};
+use futures_util::future;
+use futures_util::sink::SinkExt;
+use futures_util::stream::{StreamExt, TryStreamExt};
+use gotham::hyper::body::HttpBody;
+use gotham::hyper::header::HeaderValue;
+use gotham::hyper::upgrade::OnUpgrade;
+use gotham::hyper::{body, header, Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
 use gotham::middleware::state::StateMiddleware;
+use gotham::pipeline::new_pipeline;
 use gotham::pipeline::single::single_pipeline;
-use gotham::pipeline::single_middleware;
 use gotham::router::{
     builder::build_router, builder::DefineSingleRoute, builder::DrawRoutes, Router,
 };
-use gotham::state::{FromState, State};
+use gotham::state::{client_addr, FromState, State};
 use gotham_derive::StateData;
 
 use log::*;
 
 use mime::Mime;
 
+use tracing::Instrument;
+
+use serde_json::{json, Value};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use crate::access::{self, AccessLog};
+use crate::acme;
+use crate::allowlist::AllowlistError;
+use crate::audit;
+use crate::auth::{self, AuthError, AuthToken};
+use crate::bans::BanList;
+use crate::canary::Canary;
+use crate::compression::{self, Encoding, UnsupportedEncoding, UpstreamCompression};
 use crate::config::Config;
-use crate::envelope::{BodyError, SentryEnvelope};
+use crate::cors::Cors;
+use crate::drain::{Drain, Pending};
+use crate::envelope::{self, BodyError, ItemEdit, ProjectId, SentryEnvelope};
+use crate::forward::{RetryPolicy, UpstreamClient, UpstreamResponse};
+use crate::grpc::{self, GrpcError};
+use crate::idempotency::{CachedResponse, ResponseCache};
+use crate::lifetime::LifetimeStats;
+use crate::monitoring;
+use crate::otlp::ExportTraceServiceRequest;
+use crate::pacing::{Pacer, QueueFull};
+use crate::pool::BufferPool;
+use crate::quotas::{QuotaError, Quotas};
+use crate::ratelimits::{self, RateLimited, RateLimits};
+use crate::region::{self, DeniedRegion};
+use crate::sdk;
+use crate::sessions::SessionAggregator;
+use crate::signing::{self, SignatureError};
+use crate::spam::{self, SpamFilter, Verdict};
+use crate::spill;
+use crate::spool::Spool;
+use crate::stats::{BufferedBytes, Stats};
+use crate::streaming::ItemSizeLimits;
+use crate::telemetry;
+use crate::toggles::{self, ToggleError, Toggles};
 
 // 10 MB max body
 pub const MAX_CONTENT_SIZE: u64 = 10_000_000;
 
+// 100 MB max body when streaming
+pub const MAX_STREAMED_CONTENT_SIZE: u64 = 100_000_000;
+
+// Comma separated lengths of the envelopes concatenated in a batch request
+pub const BATCH_HEADER: &str = "X-Tunnel-Envelope-Lengths";
+
+const DENIED_COUNTRY_MESSAGE: &str = "Requests from this country are not accepted.";
+
+const BANNED_CLIENT_MESSAGE: &str = "This client is temporarily banned.";
+
+const UNSUPPORTED_CONTENT_TYPE_MESSAGE: &str = "Unsupported content type.";
+
+const OVERLOADED_MESSAGE: &str = "The tunnel is overloaded, retry later.";
+
+// Seconds clients are asked to wait when the tunnel is overloaded
+const RETRY_AFTER_SECONDS: u64 = 5;
+
+// Time a remote host has to answer the upstream check of the readiness probe
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Filters toggled at runtime, requires an admin token
+pub const ADMIN_TOGGLES_PATH: &str = "/admin/toggles";
+
+// Projects and hosts added with a PUT or removed with a DELETE, requires an admin token
+pub const ADMIN_PROJECTS_PATH: &str = "/admin/projects";
+pub const ADMIN_HOSTS_PATH: &str = "/admin/hosts";
+
+// Hex encoded HMAC-SHA256 of the request body, keyed by the secret of the envelope project
+pub const SIGNATURE_HEADER: &str = "X-Tunnel-Signature";
+
 /**
  * This struct is used to share read-only data between HTTP request handlers
  */
 #[derive(Debug, StateData, Clone)]
 struct TunnelConfig {
     inner: Arc<Config>,
+    sessions: Option<Arc<SessionAggregator>>,
+    quotas: Option<Arc<Quotas>>,
+    rate_limits: Option<Arc<RateLimits>>,
+    spam: Option<Arc<SpamFilter>>,
+    responses: Option<Arc<ResponseCache>>,
+    bans: Option<Arc<BanList>>,
+    canary: Option<Arc<Canary>>,
+    buffers: Arc<BufferPool>,
+    in_flight: Option<Arc<Semaphore>>,
+    pacer: Option<Arc<Pacer>>,
+    compression: Option<Arc<UpstreamCompression>>,
+    spool: Option<Arc<Spool>>,
+    queue: Option<Arc<ForwardQueue>>,
+    client: Arc<UpstreamClient>,
+    stats: Arc<Stats>,
+    lifetime: Option<Arc<LifetimeStats>>,
+    toggles: Arc<Toggles>,
+    drain: Arc<Drain>,
+    live: ConfigHandle,
+}
+
+impl TunnelConfig {
+    /**
+     * The shared data of the request, with the last configuration loaded
+     */
+    fn current(state: &State) -> TunnelConfig {
+        let mut config = TunnelConfig::borrow_from(state).clone();
+        config.inner = config.live.config.read().unwrap().clone();
+        config
+    }
+}
+
+/**
+ * Replaces the configuration used by the handlers of a router, without restarting it, and drains
+ * the router on shutdown
+ */
+#[derive(Clone, Debug)]
+pub struct ConfigHandle {
+    config: Arc<RwLock<Arc<Config>>>,
+    drain: Arc<Drain>,
+    sessions: Option<Arc<SessionAggregator>>,
+}
+
+impl ConfigHandle {
+    pub fn current(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    /**
+     * Wait for the requests in progress and the queued envelopes to be forwarded, then forward
+     * the aggregated sessions, within `timeout`. The readiness probe fails meanwhile.
+     */
+    pub async fn drain(&self, timeout: Duration) {
+        let started = Instant::now();
+        info!("Draining {} pending requests and envelopes", self.drain.pending());
+        if !self.drain.wait(timeout).await {
+            warn!(
+                "{} requests and envelopes were still pending after {:?}, they are lost",
+                self.drain.pending(),
+                timeout
+            );
+            return;
+        }
+        if let Some(sessions) = &self.sessions {
+            let remaining = timeout.saturating_sub(started.elapsed());
+            if tokio::time::timeout(remaining, sessions.flush()).await.is_err() {
+                warn!("The aggregated sessions were not forwarded after {:?}", timeout);
+            }
+        }
+    }
 }
 
+/**
+ * Names (CN and DNS SANs) of the certificate presented by a TLS client
+ */
+#[derive(Clone, Debug, StateData)]
+pub struct ClientIdentity {
+    pub names: Vec<String>,
+}
+
+/**
+ * Where an envelope comes from
+ */
+#[derive(Clone, Debug, Default)]
+struct Origin {
+    client_ip: Option<IpAddr>,
+    // Url path of the request, tunnel paths can have their own projects and hosts
+    path: String,
+    // Projects the client certificate or auth token is restricted to
+    allowed_projects: Option<Arc<Vec<String>>>,
+    // The signature of the envelope was already verified
+    signed: bool,
+    // Audited rules the request broke
+    flags: Vec<&'static str>,
+}
+
+#[tracing::instrument(name = "parse", skip_all, fields(size = body.len()))]
 fn parse_body(body: Vec<u8>) -> Result<SentryEnvelope, AError> {
     SentryEnvelope::try_new_from_body(body)
 }
@@ -50,6 +232,10 @@ pub enum HeaderError {
     ContentIsTooBig,
     CouldNotParseContentLength,
     InvalidHost,
+    InvalidBatchLengths,
+    AmbiguousLength,
+    InvalidTransferEncoding,
+    LengthMismatch { declared: u64, received: u64 },
 }
 
 impl Error for HeaderError {}
@@ -65,6 +251,18 @@ impl Display for HeaderError {
             HeaderError::InvalidHost => f.write_str(
                 "Invalid sentry host, check your config against the dsn used in the request.",
             ),
+            HeaderError::InvalidBatchLengths => f.write_fmt(format_args!(
+                "Invalid {} header, it must list the length of every envelope of the batch.",
+                BATCH_HEADER
+            )),
+            HeaderError::AmbiguousLength => {
+                f.write_str("Conflicting Content-Length and Transfer-Encoding headers.")
+            }
+            HeaderError::InvalidTransferEncoding => f.write_str("Unsupported transfer encoding."),
+            HeaderError::LengthMismatch { declared, received } => f.write_fmt(format_args!(
+                "Received {} bytes of a body whose Content-Length is {}.",
+                received, declared
+            )),
         }
     }
 }
@@ -77,10 +275,93 @@ impl IntoResponse for HeaderError {
     }
 }
 
+impl IntoResponse for BodyError {
+    fn into_response(self, state: &State) -> Response<Body> {
+        warn!("{}", self);
+        let mime = "application/json".parse::<Mime>().unwrap();
+        create_response(state, StatusCode::BAD_REQUEST, mime, format!("{}", self))
+    }
+}
+
+/**
+ * The request body was not received before the upload timeout
+ */
+#[derive(Debug)]
+pub struct UploadTimeout;
+
+impl Error for UploadTimeout {}
+
+impl Display for UploadTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The request body was not received in time.")
+    }
+}
+
+/**
+ * Buffering the rest of the request body would exceed the buffered bytes limit
+ */
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl Error for Overloaded {}
+
+impl Display for Overloaded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(OVERLOADED_MESSAGE)
+    }
+}
+
+/**
+ * The envelope could not be delivered to sentry
+ */
+#[derive(Debug)]
+pub struct ForwardError(pub AError);
+
+impl Error for ForwardError {}
+
+impl Display for ForwardError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/**
+ * Reject requests whose body could be delimited differently by a proxy in front of the tunnel,
+ * which would allow smuggling a request in the body of another one
+ */
+fn check_framing(headers: &HeaderMap) -> Result<(), HeaderError> {
+    let mut lengths = headers.get_all(header::CONTENT_LENGTH).iter();
+    if let Some(first) = lengths.next() {
+        if lengths.any(|length| length != first) {
+            return Err(HeaderError::AmbiguousLength);
+        }
+    }
+    if !headers.contains_key(header::TRANSFER_ENCODING) {
+        return Ok(());
+    }
+    if headers.contains_key(header::CONTENT_LENGTH) {
+        return Err(HeaderError::AmbiguousLength);
+    }
+    let mut codings = vec![];
+    for encoding in headers.get_all(header::TRANSFER_ENCODING) {
+        let encoding = encoding
+            .to_str()
+            .map_err(|_| HeaderError::InvalidTransferEncoding)?;
+        codings.extend(encoding.split(',').map(|coding| coding.trim().to_lowercase()));
+    }
+    // Chunked must be the last coding and can only be applied once
+    let chunked = codings.iter().filter(|coding| *coding == "chunked").count();
+    if chunked != 1 || codings.last().map(String::as_str) != Some("chunked") {
+        return Err(HeaderError::InvalidTransferEncoding);
+    }
+    Ok(())
+}
+
 /**
- * Returns Ok if the request associated with those headers can be handled
+ * Returns the content length if the request associated with those headers can be handled
  */
-fn check_content_length(headers: &HeaderMap) -> Result<(), AError> {
+fn check_content_length(headers: &HeaderMap, max: u64) -> Result<u64, AError> {
+    check_framing(headers)?;
     if let Some(content_length_value) = headers.get(header::CONTENT_LENGTH) {
         let content_length = u64::from_str(
             content_length_value
@@ -88,90 +369,2132 @@ fn check_content_length(headers: &HeaderMap) -> Result<(), AError> {
                 .map_err(|_| AError::new(HeaderError::CouldNotParseContentLength))?,
         )
         .map_err(|_| AError::new(HeaderError::CouldNotParseContentLength))?;
-        if content_length > MAX_CONTENT_SIZE {
+        if content_length > max {
             return Err(AError::new(HeaderError::ContentIsTooBig));
         } else {
-            return Ok(());
+            return Ok(content_length);
         }
     }
     Err(AError::new(HeaderError::MissingContentLength))
 }
 
-async fn tunnel_handler(state: &mut State) -> Result<Response<Body>, AError> {
-    let headers = HeaderMap::take_from(state);
-    check_content_length(&headers)?;
+/**
+ * Read the body until the end of the envelope header, leaving the rest of the body untouched.
+ * Returns every byte read so far.
+ */
+async fn read_envelope_header(body: &mut Body) -> Result<Vec<u8>, AError> {
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        read.extend_from_slice(&chunk);
+        if read.len() as u64 > MAX_CONTENT_SIZE {
+            return Err(AError::new(BodyError::InvalidNumberOfLines));
+        }
+        // Only the new chunk is scanned
+        if envelope::header_end(&chunk).is_some() {
+            break;
+        }
+    }
+    Ok(read)
+}
 
-    let full_body = body::to_bytes(Body::take_from(state)).await?;
-    let sentry_instance = parse_body(full_body.to_vec())?;
+/**
+ * Returns true if a request breaking this rule must be refused. Requests breaking an audited rule
+ * are only flagged, and toggled off rules are ignored.
+ */
+fn enforce(config: &TunnelConfig, rule: &'static str, flags: &mut Vec<&'static str>) -> bool {
+    if !config.toggles.is_enabled(rule) {
+        return false;
+    }
+    if !config.inner.rule_is_audited(rule) {
+        return true;
+    }
+    config.stats.audited_rule_hit();
+    debug!("Flagged a request breaking the {} rule", rule);
+    flags.push(rule);
+    false
+}
 
-    let config = TunnelConfig::borrow_from(state);
-    let hosts = &config.inner.remote_hosts;
-    if config
-        .inner
-        .project_id_is_allowed(sentry_instance.dsn.project_id().value())
-    {
-        if sentry_instance.dsn_host_is_valid(hosts) {
-            match sentry_instance.forward().await {
-                Err(e) => {
-                    error!(
-                        "Failed to forward request to sentry : {} - Host = {}",
-                        e,
-                        sentry_instance.dsn.host()
-                    );
-                    let mime = "text/plain".parse::<Mime>().unwrap();
-                    let res: (StatusCode, Mime, String) =
-                        (StatusCode::INTERNAL_SERVER_ERROR, mime, format!("{}", e));
-                    let res = res.into_response(state);
-                    Ok(res)
-                }
-                Ok(_) => {
-                    let res = create_empty_response(state, StatusCode::OK);
-                    Ok(res)
-                }
-            }
-        } else {
-            Err(AError::new(HeaderError::InvalidHost))
+/**
+ * Returns true if the request was sent by a User-Agent whose requests are dropped
+ */
+fn user_agent_is_denied(
+    config: &TunnelConfig,
+    headers: &HeaderMap,
+    flags: &mut Vec<&'static str>,
+) -> bool {
+    let denied = headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .is_some_and(|user_agent| config.inner.user_agent_is_denied(user_agent))
+        && enforce(config, audit::USER_AGENT, flags);
+    if denied {
+        config.stats.bot_request_dropped();
+        debug!("Dropped a request from a denied User-Agent : {:?}", headers.get(header::USER_AGENT));
+    }
+    denied
+}
+
+/**
+ * Returns true if the request announces a content type envelopes are not posted with
+ */
+fn content_type_is_denied(
+    config: &TunnelConfig,
+    headers: &HeaderMap,
+    flags: &mut Vec<&'static str>,
+) -> bool {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type.to_str().unwrap_or_default());
+    let denied = !config.inner.content_type_is_allowed(content_type)
+        && enforce(config, audit::CONTENT_TYPE, flags);
+    if denied {
+        config.stats.content_type_rejected();
+        debug!("Rejected a request with content type {:?}", content_type);
+    }
+    denied
+}
+
+/**
+ * Address of the client, read from the configured header when the tunnel runs behind a proxy
+ */
+fn client_ip(state: &State, config: &TunnelConfig, headers: &HeaderMap) -> Option<IpAddr> {
+    match &config.inner.client_ip_header {
+        Some(client_ip_header) => headers
+            .get(client_ip_header.as_str())?
+            .to_str()
+            .ok()?
+            .split(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok(),
+        None => client_addr(state).map(|addr| addr.ip()),
+    }
+}
+
+fn origin(
+    state: &State,
+    config: &TunnelConfig,
+    headers: &HeaderMap,
+    signed: bool,
+    flags: Vec<&'static str>,
+) -> Origin {
+    let certificate_projects = ClientIdentity::try_borrow_from(state)
+        .and_then(|identity| config.inner.client_cert_allowed_projects(&identity.names));
+    let token_projects = check_auth_token(config, headers)
+        .ok()
+        .flatten()
+        .and_then(|token| token.projects.clone());
+    let allowed_projects = match (certificate_projects, token_projects) {
+        (Some(certificate_projects), Some(token_projects)) => Some(
+            certificate_projects
+                .into_iter()
+                .filter(|project| token_projects.contains(project))
+                .collect(),
+        ),
+        (certificate_projects, token_projects) => certificate_projects.or(token_projects),
+    };
+    Origin {
+        client_ip: client_ip(state, config, headers),
+        path: Uri::borrow_from(state).path().to_string(),
+        allowed_projects: allowed_projects.map(Arc::new),
+        signed,
+        flags,
+    }
+}
+
+/**
+ * The bearer token of the `Authorization` header
+ */
+fn presented_token(headers: &HeaderMap) -> Result<&str, AuthError> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or(AuthError::MissingToken)
+}
+
+/**
+ * The base64 encoded credentials of the `Authorization` header
+ */
+fn presented_basic_credentials(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| {
+            let scheme = authorization.get(..6)?;
+            scheme
+                .eq_ignore_ascii_case("Basic ")
+                .then(|| authorization[6..].trim())
+        })
+}
+
+/**
+ * Find the token presented by the request, when auth tokens or basic credentials are configured.
+ * Requests authenticated with basic credentials have no token.
+ */
+fn check_auth_token<'a>(
+    config: &'a TunnelConfig,
+    headers: &HeaderMap,
+) -> Result<Option<&'a AuthToken>, AuthError> {
+    let basic_credentials = &config.inner.basic_credentials;
+    if config.inner.auth_tokens.is_empty() && basic_credentials.is_empty() {
+        return Ok(None);
+    }
+    if let Some(presented) = presented_basic_credentials(headers) {
+        if !basic_credentials.is_empty() {
+            return auth::authenticate_basic(basic_credentials, presented).map(|_| None);
+        }
+    }
+    auth::authenticate(&config.inner.auth_tokens, presented_token(headers)?).map(Some)
+}
+
+fn unauthorized_response(state: &State, error: AuthError) -> Response<Body> {
+    warn!("{}", error);
+    let mime = "text/plain".parse::<Mime>().unwrap();
+    let mut response = create_response(state, StatusCode::UNAUTHORIZED, mime, format!("{}", error));
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+/**
+ * Returns the reason why requests from this client are refused, if they are
+ */
+fn refused_client(
+    state: &State,
+    config: &TunnelConfig,
+    headers: &HeaderMap,
+    flags: &mut Vec<&'static str>,
+) -> Option<&'static str> {
+    if let Some(bans) = &config.bans {
+        if client_ip(state, config, headers).is_some_and(|ip| bans.is_banned(ip))
+            && enforce(config, audit::BANNED, flags)
+        {
+            config.stats.banned_request_rejected();
+            return Some(BANNED_CLIENT_MESSAGE);
+        }
+    }
+    if country_is_denied(state, config, headers, flags) {
+        return Some(DENIED_COUNTRY_MESSAGE);
+    }
+    None
+}
+
+/**
+ * Returns true if the request comes from a country whose requests are rejected
+ */
+fn country_is_denied(
+    state: &State,
+    config: &TunnelConfig,
+    headers: &HeaderMap,
+    flags: &mut Vec<&'static str>,
+) -> bool {
+    let geoip = match &config.inner.geoip {
+        Some(geoip) => geoip,
+        None => return false,
+    };
+    let country = client_ip(state, config, headers).and_then(|ip| geoip.country(ip));
+    let denied = !config.inner.country_is_allowed(country.as_deref())
+        && enforce(config, audit::COUNTRY, flags);
+    if denied {
+        config.stats.country_request_rejected();
+        debug!("Rejected a request from country {:?}", country);
+    }
+    denied
+}
+
+/**
+ * Count the event carried by the envelope, if any, against the quotas of its project
+ */
+fn consume_quota(config: &TunnelConfig, sentry_instance: &SentryEnvelope) -> Result<(), AError> {
+    if let Some(quotas) = &config.quotas {
+        if sentry_instance.event_id().is_some() {
+            quotas.consume(sentry_instance.dsn.project_id().value())?;
         }
+    }
+    Ok(())
+}
+
+/**
+ * Remove the items of the categories sentry rate limited, and reject the envelope when none is
+ * left. Only the limits on every category apply to streamed envelopes, whose items are unknown.
+ */
+fn apply_rate_limits(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+    streamed: bool,
+) -> Result<(), AError> {
+    let rate_limits = match &config.rate_limits {
+        Some(rate_limits) => rate_limits,
+        None => return Ok(()),
+    };
+    let checked = if streamed {
+        rate_limits.check(&sentry_instance.dsn, &[])
     } else {
-        Err(AError::new(BodyError::InvalidProjectId))
+        let items = sentry_instance.items()?;
+        let categories: Vec<&str> = items
+            .iter()
+            .map(|item| ratelimits::category(item.item_type().unwrap_or_default()))
+            .collect();
+        rate_limits.check(&sentry_instance.dsn, &categories)
+    };
+    if let Err(e) = checked {
+        config.stats.rate_limited_envelope();
+        debug!("{} - Project = {}", e, sentry_instance.dsn.project_id());
+        return Err(AError::new(e));
+    }
+    if !streamed {
+        let dsn = sentry_instance.dsn.clone();
+        let removed = sentry_instance.retain_items(|item| {
+            let category = ratelimits::category(item.item_type().unwrap_or_default());
+            !rate_limits.is_limited(&dsn, category)
+        })?;
+        if removed > 0 {
+            debug!(
+                "Removed {} rate limited items - Project = {}",
+                removed,
+                sentry_instance.dsn.project_id()
+            );
+        }
     }
+    Ok(())
 }
 
-async fn post_tunnel_handler(mut state: State) -> HandlerResult {
-    match tunnel_handler(&mut state).await {
-        Ok(val) => Ok((state, val)),
-        Err(error) => {
-            let mime = "text/plain".parse::<Mime>().unwrap();
-            let res: (StatusCode, Mime, String) = (
-                StatusCode::BAD_REQUEST,
-                mime,
-                format!("{}", error),
+/**
+ * Remove the replay recordings that are bigger than the configured size, keeping the rest of the
+ * replay
+ */
+fn strip_replay_recordings(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+) -> Result<(), AError> {
+    let max_size = config
+        .inner
+        .max_replay_recording_size
+        .filter(|_| config.toggles.is_enabled(toggles::REPLAY_RECORDING_SIZE));
+    if let Some(max_size) = max_size {
+        let removed = sentry_instance.retain_items(|item| {
+            item.item_type() != Some("replay_recording") || item.payload.len() as u64 <= max_size
+        })?;
+        if removed > 0 {
+            config.stats.replay_recordings_stripped(removed as u64);
+            debug!(
+                "Removed {} oversized replay recordings - Project = {}",
+                removed,
+                sentry_instance.dsn.project_id()
             );
-            let response = res.into_response(&state);
-            Ok((state, response))
         }
     }
+    Ok(())
 }
 
+/**
+ * Returns true if the envelope was sent by an SDK, or a version of it, that is not allowed
+ */
+fn sdk_is_denied(
+    config: &TunnelConfig,
+    sentry_instance: &SentryEnvelope,
+    flags: &mut Vec<&'static str>,
+) -> bool {
+    let allowed_sdks = &config.inner.allowed_sdks;
+    let denied = sentry_instance
+        .sdk()
+        .is_some_and(|(name, version)| !sdk::is_allowed(allowed_sdks, &name, &version))
+        && enforce(config, audit::SDK, flags);
+    if denied {
+        config.stats.sdk_envelope_dropped();
+        debug!(
+            "Dropped an envelope from a denied SDK : {:?} - Project = {}",
+            sentry_instance.sdk(),
+            sentry_instance.dsn.project_id()
+        );
+    }
+    denied
+}
 
-async fn health_handler(state: State) -> HandlerResult {
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "text/plain")
-        .body(Body::from("OK"))
-        .unwrap();
-    Ok((state, response))
+/**
+ * Returns true if the event of the envelope is a duplicate that must be dropped. The first event
+ * forwarded after a flood is tagged with the number of dropped duplicates.
+ */
+fn collapse_duplicates(
+    config: &TunnelConfig,
+    origin: &Origin,
+    sentry_instance: &mut SentryEnvelope,
+    flags: &mut Vec<&'static str>,
+) -> Result<bool, AError> {
+    let spam_filter = match &config.spam {
+        Some(spam_filter) => spam_filter,
+        None => return Ok(false),
+    };
+    let project_id = sentry_instance.dsn.project_id().value();
+    let mut dropped = false;
+    sentry_instance.edit_items(|item| {
+        if item.item_type() != Some("event") {
+            return ItemEdit::Keep;
+        }
+        let mut event: Value = match serde_json::from_slice(item.payload) {
+            Ok(event @ Value::Object(_)) => event,
+            _ => return ItemEdit::Keep,
+        };
+        let fingerprint = match spam::fingerprint(&event) {
+            Some(fingerprint) => fingerprint,
+            None => return ItemEdit::Keep,
+        };
+        match spam_filter.check(origin.client_ip, project_id, fingerprint) {
+            Verdict::Drop => {
+                dropped = true;
+                ItemEdit::Keep
+            }
+            Verdict::Forward { collapsed: 0 } => ItemEdit::Keep,
+            Verdict::Forward { collapsed } => {
+                spam::tag_collapsed(&mut event, collapsed);
+                ItemEdit::Replace(event.to_string().into_bytes())
+            }
+        }
+    })?;
+    let dropped = dropped && enforce(config, audit::DUPLICATE, flags);
+    if dropped {
+        config.stats.duplicate_event_dropped();
+        debug!("Dropped a duplicate event - Project = {}", project_id);
+    }
+    Ok(dropped)
 }
 
-pub fn router(path: &str, config: Config) -> Router {
-    let middleware = StateMiddleware::new(TunnelConfig {
-        inner: Arc::new(config),
-    });
-    let pipeline = single_middleware(middleware);
-    let (chain, pipelines) = single_pipeline(pipeline);
+/**
+ * Reject an envelope of an unknown project or host, or drop it silently so that the valid ones
+ * can not be enumerated
+ */
+fn refuse_unknown(config: &TunnelConfig, error: AError) -> Result<(), AError> {
+    if !config.inner.silent_drop {
+        return Err(error);
+    }
+    debug!("Silently dropped an envelope : {}", error);
+    config.stats.unknown_envelope_dropped();
+    Ok(())
+}
 
-    build_router(chain, pipelines, |route| {
-        route.post(path).to_async(post_tunnel_handler);
-        route.get("/healthz").to_async(health_handler);
-    })
+/**
+ * Reject an envelope sent to a sentry.io region whose envelopes are not accepted
+ */
+fn check_region(config: &TunnelConfig, sentry_instance: &SentryEnvelope) -> Result<(), AError> {
+    let host = sentry_instance.dsn.host();
+    if config.inner.region_is_allowed(host) {
+        return Ok(());
+    }
+    config.stats.region_envelope_rejected();
+    Err(AError::new(DeniedRegion(region::of_host(host).unwrap_or_default())))
+}
+
+/**
+ * Send the envelope posted on `path` to the upstream of its project, if it has one, whatever the
+ * host of its dsn. The header of a buffered envelope is rewritten too, so that it goes there from
+ * the spool. Returns whether the envelope was redirected.
+ */
+fn route_upstream(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+    path: &str,
+    buffered: bool,
+) -> Result<bool, AError> {
+    let project_id = sentry_instance.dsn.project_id().value();
+    let host = match config.inner.project_upstream_on(path, project_id) {
+        Some(host) => host,
+        None => return Ok(false),
+    };
+    sentry_instance.redirect(host)?;
+    if buffered {
+        sentry_instance.write_dsn_header()?;
+    }
+    Ok(true)
+}
+
+/**
+ * Send the envelope to the canary dsn of its project when it is picked
+ */
+fn route_canary(config: &TunnelConfig, sentry_instance: &mut SentryEnvelope) {
+    let project_id = sentry_instance.dsn.project_id().to_string();
+    if let Some(dsn) = config.canary.as_ref().and_then(|canary| canary.pick(&project_id)) {
+        debug!("Sending an envelope of project {} to the canary {}", project_id, dsn.host());
+        config.stats.canary_envelope();
+        sentry_instance.dsn = dsn.clone();
+    }
+}
+
+/**
+ * Wait for the turn of the envelope when the upstream rate is limited
+ */
+async fn pace(config: &TunnelConfig) -> Result<(), AError> {
+    if let Some(pacer) = &config.pacer {
+        let delay = pacer.reserve()?;
+        if !delay.is_zero() {
+            config.stats.envelope_paced();
+            tokio::time::sleep(delay).await;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Forward a buffered envelope, sending it again after a growing delay while sentry can not be
+ * reached or answers a 5xx status, up to the configured number of retries
+ */
+async fn forward_buffered(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+    proxy: Option<&str>,
+) -> Result<UpstreamResponse, AError> {
+    let policy = config.inner.retry_policy();
+    let mut forwarded = forward_compressed(config, sentry_instance, proxy).await;
+    for retry in 1..=policy.retries {
+        if !RetryPolicy::is_retryable(&forwarded) {
+            break;
+        }
+        let delay = policy.delay(retry);
+        match &forwarded {
+            Ok(response) => warn!(
+                "Sentry answered {}, retrying in {:?} - Host = {}",
+                response.status,
+                delay,
+                sentry_instance.dsn.host()
+            ),
+            Err(e) => warn!(
+                "Failed to forward request to sentry : {}, retrying in {:?} - Host = {}",
+                e,
+                delay,
+                sentry_instance.dsn.host()
+            ),
+        }
+        config.stats.forward_retried();
+        tokio::time::sleep(delay).await;
+        forwarded = forward_compressed(config, sentry_instance, proxy).await;
+    }
+    forwarded
+}
+
+/**
+ * Forward a buffered envelope, compressed when upstream encodings are configured. When the sentry
+ * relay answers a 415 status, the encoding is not used for its host anymore and the envelope is
+ * sent again with the next one, or as is.
+ */
+async fn forward_compressed(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+    proxy: Option<&str>,
+) -> Result<UpstreamResponse, AError> {
+    let compression = match &config.compression {
+        Some(compression) => compression,
+        None => return sentry_instance.forward_via(&config.client, proxy).await,
+    };
+    let host = sentry_instance.dsn.host().to_string();
+    while let Some(encoding) = compression.pick(&host, sentry_instance.raw_body.len()) {
+        let response = sentry_instance
+            .forward_encoded(&config.client, proxy, encoding)
+            .await?;
+        if response.status != StatusCode::UNSUPPORTED_MEDIA_TYPE.as_u16() {
+            config.stats.envelope_compressed();
+            return Ok(response);
+        }
+        compression.reject(&host, encoding);
+    }
+    sentry_instance.forward_via(&config.client, proxy).await
+}
+
+/**
+ * Returns true if an optional processing stage can still run on an envelope whose processing
+ * started at `started`. Stages are skipped once the processing budget is spent, so that slow
+ * processing delays the envelope without dropping it.
+ */
+fn within_budget(config: &TunnelConfig, started: Instant, stage: &str) -> bool {
+    match config.inner.processing_budget {
+        Some(budget) if started.elapsed() >= Duration::from_millis(budget) => {
+            config.stats.processing_stage_skipped();
+            debug!("Skipped the {} stage, the processing budget is spent", stage);
+            false
+        }
+        _ => true,
+    }
+}
+
+/**
+ * An envelope waiting in the queue, pending until forwarded
+ */
+type Queued = (SentryEnvelope, Pending);
+
+/**
+ * Buffered envelopes waiting for the workers forwarding them, when envelopes are answered before
+ * being forwarded
+ */
+#[derive(Debug)]
+struct ForwardQueue {
+    // One queue per worker, the envelopes of a project always go through the same one so that
+    // they are forwarded in order. Envelopes are pending until forwarded, so that a shutdown
+    // waits for them.
+    senders: Vec<mpsc::Sender<Queued>>,
+    // Taken when the workers start, with the first envelope
+    receivers: Mutex<Option<Vec<mpsc::Receiver<Queued>>>>,
+}
+
+impl ForwardQueue {
+    fn new(size: usize, workers: usize) -> ForwardQueue {
+        let workers = workers.max(1);
+        let (senders, receivers) = (0..workers)
+            .map(|_| mpsc::channel((size / workers).max(1)))
+            .unzip();
+        ForwardQueue {
+            senders,
+            receivers: Mutex::new(Some(receivers)),
+        }
+    }
+
+    /**
+     * Queue the envelope, taking its body, or fail when the queue is full
+     */
+    fn push(
+        &self,
+        config: &TunnelConfig,
+        sentry_instance: &mut SentryEnvelope,
+    ) -> Result<(), QueueFull> {
+        if let Some(receivers) = self.receivers.lock().unwrap().take() {
+            start_workers(config, receivers);
+        }
+        let project_id = sentry_instance.dsn.project_id().value();
+        let sender = &self.senders[(project_id % self.senders.len() as u64) as usize];
+        let envelope = SentryEnvelope {
+            raw_body: std::mem::take(&mut sentry_instance.raw_body),
+            dsn: sentry_instance.dsn.clone(),
+        };
+        sender
+            .try_send((envelope, config.drain.track()))
+            .map_err(|e| {
+                sentry_instance.raw_body = e.into_inner().0.raw_body;
+                QueueFull
+            })
+    }
+
+}
+
+/**
+ * Start a worker forwarding the envelopes of each queue, one after the other
+ */
+fn start_workers(
+    config: &TunnelConfig,
+    receivers: Vec<mpsc::Receiver<Queued>>,
+) {
+    for mut receiver in receivers {
+        let config = config.clone();
+        tokio::spawn(async move {
+            while let Some((mut envelope, _pending)) = receiver.recv().await {
+                // The configuration may have been reloaded since the workers started
+                let mut config = config.clone();
+                config.inner = config.live.current();
+                let _ = forward_envelope(&config, &mut envelope).await;
+            }
+        });
+    }
+}
+
+/**
+ * What became of an envelope that was not rejected
+ */
+enum Delivery {
+    Dropped,
+    // Held to be forwarded later, aggregated, queued or spooled
+    Absorbed,
+    Forwarded(UpstreamResponse),
+}
+
+/**
+ * Validate an envelope against the configuration and forward it to sentry. `rest` holds the
+ * part of the body that is still to be streamed and the size of the whole body, if any.
+ */
+async fn deliver_envelope(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+    rest: Option<(Body, u64)>,
+    origin: &Origin,
+) -> Result<Delivery, AError> {
+    let started = Instant::now();
+    let hosts = config.inner.allowed_hosts_on(&origin.path);
+    let project_id = sentry_instance.dsn.project_id().value();
+    if !config.inner.project_id_is_allowed_on(&origin.path, project_id)
+        || origin
+            .allowed_projects
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&format!("{}", project_id)))
+    {
+        return refuse_unknown(config, AError::new(BodyError::InvalidProjectId))
+            .map(|_| Delivery::Dropped);
+    }
+    if !origin.signed && config.inner.signing_secret(project_id).is_some() {
+        return Err(AError::new(SignatureError::UnsignedChannel));
+    }
+    if !route_upstream(config, sentry_instance, &origin.path, rest.is_none())?
+        && !sentry_instance.dsn_host_is_valid(&hosts)
+    {
+        return refuse_unknown(config, AError::new(HeaderError::InvalidHost))
+            .map(|_| Delivery::Dropped);
+    }
+    check_region(config, sentry_instance)?;
+    let mut flags = origin.flags.clone();
+    if sdk_is_denied(config, sentry_instance, &mut flags) {
+        return Ok(Delivery::Dropped);
+    }
+    if let Some((body, content_length)) = rest {
+        let limits = ItemSizeLimits {
+            attachment: config.inner.max_attachment_size,
+            default: MAX_CONTENT_SIZE,
+        };
+        let allowed_items = if config.inner.strict_items {
+            Some(config.inner.allowed_items.clone())
+        } else {
+            None
+        };
+        apply_rate_limits(config, sentry_instance, true)?;
+        consume_quota(config, sentry_instance)?;
+        if !flags.is_empty() {
+            debug!(
+                "Forwarding a streamed envelope without its flags {:?} - Project = {}",
+                flags,
+                sentry_instance.dsn.project_id()
+            );
+        }
+        let body = match config.inner.spill_threshold {
+            Some(threshold) if content_length > threshold => {
+                let spilled = spill::spill(body, &config.inner.spill_dir())
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to spill a request body : {}", e);
+                        AError::new(ForwardError(e))
+                    })?;
+                debug!("Spilled a request body of {} bytes", spilled.size());
+                config.stats.body_spilled();
+                spilled.into_body()
+            }
+            _ => body,
+        };
+        let rest = TryStreamExt::map_err(body, io::Error::other);
+        route_canary(config, sentry_instance);
+        pace(config).await?;
+        let proxy = region::proxy(&config.inner.region_proxies, sentry_instance.dsn.host());
+        let forwarded = access::upstream(sentry_instance.forward_stream(
+            rest,
+            content_length,
+            limits,
+            allowed_items,
+            &config.client,
+            proxy,
+        ))
+        .await;
+        forwarded_delivery(config, sentry_instance, forwarded)
+    } else {
+        if let Err(e) = sentry_instance.check_item_types(&config.inner.allowed_items) {
+            if config.inner.strict_items {
+                return Err(AError::new(e));
+            }
+            warn!("{} - Project = {}", e, sentry_instance.dsn.project_id());
+        }
+        if config.spam.is_some()
+            && within_budget(config, started, "duplicates")
+            && collapse_duplicates(config, origin, sentry_instance, &mut flags)?
+        {
+            return Ok(Delivery::Dropped);
+        }
+        apply_rate_limits(config, sentry_instance, false)?;
+        consume_quota(config, sentry_instance)?;
+        if config.inner.max_replay_recording_size.is_some()
+            && within_budget(config, started, "replay recordings")
+        {
+            strip_replay_recordings(config, sentry_instance)?;
+        }
+        if !flags.is_empty() && within_budget(config, started, "audit") {
+            audit::tag_flagged(sentry_instance, &flags)?;
+        }
+        if let Some(sessions) = &config.sessions {
+            if within_budget(config, started, "sessions") && sessions.absorb(sentry_instance) {
+                return Ok(Delivery::Absorbed);
+            }
+        }
+        route_canary(config, sentry_instance);
+        pace(config).await?;
+        if let Some(queue) = &config.queue {
+            queue.push(config, sentry_instance)?;
+            return Ok(Delivery::Absorbed);
+        }
+        access::upstream(forward_envelope(config, sentry_instance)).await
+    }
+}
+
+/**
+ * Forward a buffered envelope, spooling it when sentry can not take it
+ */
+async fn forward_envelope(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+) -> Result<Delivery, AError> {
+    let proxy = region::proxy(&config.inner.region_proxies, sentry_instance.dsn.host());
+    let forwarded = forward_buffered(config, sentry_instance, proxy).await;
+    if let Some(spool) = &config.spool {
+        if RetryPolicy::is_retryable(&forwarded) && spool.push(&sentry_instance.raw_body) {
+            warn!(
+                "Spooled an envelope sentry could not take - Host = {}",
+                sentry_instance.dsn.host()
+            );
+            return Ok(Delivery::Absorbed);
+        }
+    }
+    forwarded_delivery(config, sentry_instance, forwarded)
+}
+
+/**
+ * Remember the rate limits sentry answered with, and report the failures to forward
+ */
+fn forwarded_delivery(
+    config: &TunnelConfig,
+    sentry_instance: &SentryEnvelope,
+    forwarded: Result<UpstreamResponse, AError>,
+) -> Result<Delivery, AError> {
+    match forwarded {
+        Err(e) if e.is::<BodyError>() => Err(e),
+        Err(e) => {
+            let message = format!(
+                "Failed to forward request to sentry : {} - Host = {}",
+                e,
+                sentry_instance.dsn.host()
+            );
+            error!("{}", message);
+            monitoring::capture_error(&message);
+            Err(AError::new(ForwardError(e)))
+        }
+        Ok(response) => {
+            if let Some(rate_limits) = &config.rate_limits {
+                rate_limits.update(
+                    &sentry_instance.dsn,
+                    response.status,
+                    response.header("x-sentry-rate-limits"),
+                    response.header("retry-after"),
+                );
+            }
+            Ok(Delivery::Forwarded(response))
+        }
+    }
+}
+
+/**
+ * Apply the access rules and filters to an envelope, then forward it
+ */
+#[tracing::instrument(
+    name = "process",
+    skip_all,
+    fields(project_id = sentry_instance.dsn.project_id().value())
+)]
+async fn process_envelope(
+    config: &TunnelConfig,
+    sentry_instance: &mut SentryEnvelope,
+    rest: Option<(Body, u64)>,
+    origin: &Origin,
+) -> Result<Option<UpstreamResponse>, AError> {
+    let project_id = sentry_instance.dsn.project_id().value();
+    let bytes = rest.as_ref().map_or(sentry_instance.raw_body.len() as u64, |(_, size)| *size);
+    let delivered = deliver_envelope(config, sentry_instance, rest, origin).await;
+    if let Some(lifetime) = &config.lifetime {
+        match delivered {
+            Ok(Delivery::Forwarded(_)) | Ok(Delivery::Absorbed) => {
+                lifetime.forwarded(project_id, bytes)
+            }
+            _ => lifetime.dropped(project_id),
+        }
+    }
+    delivered.map(|delivery| match delivery {
+        Delivery::Forwarded(response) => Some(response),
+        _ => None,
+    })
+}
+
+/**
+ * Split a batch of concatenated envelopes using the lengths announced in the batch header
+ */
+fn split_batch<'a>(lengths: &HeaderValue, body: &'a [u8]) -> Result<Vec<&'a [u8]>, AError> {
+    let lengths = lengths
+        .to_str()
+        .map_err(|_| AError::new(HeaderError::InvalidBatchLengths))?
+        .split(',')
+        .map(|length| usize::from_str(length.trim()))
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|_| AError::new(HeaderError::InvalidBatchLengths))?;
+    if lengths.iter().sum::<usize>() != body.len() {
+        return Err(AError::new(HeaderError::InvalidBatchLengths));
+    }
+    let mut envelopes = vec![];
+    let mut start = 0;
+    for length in lengths {
+        envelopes.push(&body[start..start + length]);
+        start += length;
+    }
+    Ok(envelopes)
+}
+
+/**
+ * Process a single envelope and describe the result with the status code a POST would get
+ */
+async fn envelope_outcome(config: &TunnelConfig, origin: &Origin, envelope: Vec<u8>) -> Value {
+    let processed = match parse_body(envelope) {
+        Ok(mut sentry_instance) => {
+            process_envelope(config, &mut sentry_instance, None, origin).await
+        }
+        Err(e) => Err(e),
+    };
+    match processed {
+        Ok(Some(upstream)) if upstream.is_rate_limited() => {
+            config.stats.upstream_rate_limited();
+            let retry_after = upstream
+                .rate_limit_headers()
+                .find(|(name, _)| name == "retry-after")
+                .map(|(_, value)| value.clone());
+            json!({ "status": upstream.status, "retry_after": retry_after })
+        }
+        Ok(_) => json!({ "status": StatusCode::OK.as_u16() }),
+        Err(e) => {
+            let status = if e.is::<ForwardError>() {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else if e.is::<QuotaError>() || e.is::<RateLimited>() {
+                StatusCode::TOO_MANY_REQUESTS
+            } else if e.is::<QueueFull>() {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else if e.is::<DeniedRegion>() {
+                StatusCode::FORBIDDEN
+            } else {
+                warn!("{}", e);
+                StatusCode::BAD_REQUEST
+            };
+            json!({ "status": status.as_u16(), "error": format!("{}", e) })
+        }
+    }
+}
+
+async fn batch_handler(
+    state: &mut State,
+    config: &TunnelConfig,
+    origin: &Origin,
+    lengths: &HeaderValue,
+    content_length: u64,
+    encoding: Option<Encoding>,
+) -> Result<Response<Body>, AError> {
+    let body = Body::take_from(state);
+    let full_body =
+        before_upload_timeout(config, read_body_pooled(body, config, content_length)).await?;
+    let full_body = decode_body(config, encoding, full_body)?;
+    let mut outcomes = vec![];
+    for envelope in split_batch(lengths, &full_body)? {
+        outcomes.push(envelope_outcome(config, origin, envelope.to_vec()).await);
+    }
+    config.buffers.give_back(full_body);
+    Ok(create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        Value::Array(outcomes).to_string(),
+    ))
+}
+
+/**
+ * Verify the signature of a request whose project requires one. The signature covers the whole
+ * body, so the rest of a streamed body is buffered before being verified, counting its bytes in
+ * `buffered`.
+ */
+async fn verify_signature(
+    config: &TunnelConfig,
+    headers: &HeaderMap,
+    sentry_instance: SentryEnvelope,
+    rest: Option<(Body, u64)>,
+    buffered: &mut BufferedBytes<'_>,
+) -> Result<(SentryEnvelope, Option<(Body, u64)>, bool), AError> {
+    let secret = match config
+        .inner
+        .signing_secret(sentry_instance.dsn.project_id().value())
+    {
+        Some(secret) => secret,
+        None => return Ok((sentry_instance, rest, false)),
+    };
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .ok_or(SignatureError::MissingSignature)?
+        .to_str()
+        .map_err(|_| SignatureError::InvalidSignature)?;
+    let mut raw_body = sentry_instance.raw_body;
+    if let Some((mut body, _)) = rest {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if !buffered.grow(chunk.len() as u64, config.inner.max_buffered_bytes) {
+                return Err(AError::new(Overloaded));
+            }
+            raw_body.extend_from_slice(&chunk);
+            if raw_body.len() as u64 > MAX_STREAMED_CONTENT_SIZE {
+                return Err(AError::new(HeaderError::ContentIsTooBig));
+            }
+        }
+    }
+    signing::verify(secret, &raw_body, signature)?;
+    let sentry_instance = SentryEnvelope {
+        raw_body,
+        dsn: sentry_instance.dsn,
+    };
+    Ok((sentry_instance, None, true))
+}
+
+/**
+ * Ask the client to retry later, without reading its body
+ */
+fn overloaded_response(state: &State, config: &TunnelConfig) -> Response<Body> {
+    config.stats.overloaded_request_rejected();
+    let mime = "text/plain".parse::<Mime>().unwrap();
+    let mut response = create_response(
+        state,
+        StatusCode::SERVICE_UNAVAILABLE,
+        mime,
+        OVERLOADED_MESSAGE,
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, RETRY_AFTER_SECONDS.into());
+    response
+}
+
+/**
+ * The response retries of the envelope should get. Upstream failures, quotas and a full queue
+ * are not final, a retry may succeed, so they are not remembered. Neither are the rejections of
+ * sentry when its responses are relayed.
+ */
+fn final_response(
+    processed: &Result<Option<UpstreamResponse>, AError>,
+    relayed: bool,
+) -> Option<CachedResponse> {
+    match processed {
+        Ok(Some(upstream)) if relayed || upstream.is_rate_limited() => {
+            if !StatusCode::from_u16(upstream.status).is_ok_and(|status| status.is_success()) {
+                return None;
+            }
+            Some(CachedResponse {
+                status: upstream.status,
+                body: String::from_utf8_lossy(&upstream.body).to_string(),
+            })
+        }
+        Ok(_) => Some(CachedResponse {
+            status: StatusCode::OK.as_u16(),
+            body: String::new(),
+        }),
+        Err(e)
+            if e.is::<ForwardError>()
+                || e.is::<QuotaError>()
+                || e.is::<RateLimited>()
+                || e.is::<QueueFull>() =>
+        {
+            None
+        }
+        Err(e) => Some(CachedResponse {
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            body: format!("{}", e),
+        }),
+    }
+}
+
+/**
+ * Answer with the status, `X-Sentry-*` headers and body sentry answered
+ */
+fn relayed_response(state: &State, upstream: UpstreamResponse) -> Response<Body> {
+    let status = StatusCode::from_u16(upstream.status).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut response = if upstream.body.is_empty() {
+        create_empty_response(state, status)
+    } else {
+        create_response(state, status, mime::APPLICATION_JSON, upstream.body)
+    };
+    append_headers(&mut response, &upstream.headers);
+    response
+}
+
+/**
+ * Answer with the status of sentry when it rate limited the envelope, 200 otherwise, and the rate
+ * limits it announced, so that SDKs back off
+ */
+fn rate_limited_response(state: &State, upstream: UpstreamResponse) -> Response<Body> {
+    let status = if upstream.is_rate_limited() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::OK
+    };
+    let mut response = create_empty_response(state, status);
+    let headers: Vec<(String, String)> = upstream.rate_limit_headers().cloned().collect();
+    append_headers(&mut response, &headers);
+    response
+}
+
+fn append_headers(response: &mut Response<Body>, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            header::HeaderName::from_str(name),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().append(name, value);
+        }
+    }
+}
+
+/**
+ * Answer a retried envelope like its first submission
+ */
+fn replayed_response(state: &State, cached: CachedResponse) -> Response<Body> {
+    let status = StatusCode::from_u16(cached.status).unwrap_or(StatusCode::OK);
+    if cached.body.is_empty() {
+        return create_empty_response(state, status);
+    }
+    let mime = "text/plain".parse::<Mime>().unwrap();
+    create_response(state, status, mime, cached.body)
+}
+
+async fn tunnel_handler(state: &mut State) -> Result<Response<Body>, AError> {
+    let headers = HeaderMap::take_from(state);
+    let config = TunnelConfig::current(state);
+    let mut flags = vec![];
+    if user_agent_is_denied(&config, &headers, &mut flags) {
+        return Ok(create_empty_response(state, StatusCode::OK));
+    }
+    if let Some(message) = refused_client(state, &config, &headers, &mut flags) {
+        let mime = "text/plain".parse::<Mime>().unwrap();
+        let res: (StatusCode, Mime, &str) = (StatusCode::FORBIDDEN, mime, message);
+        return Ok(res.into_response(state));
+    }
+    if let Err(e) = check_auth_token(&config, &headers) {
+        return Ok(unauthorized_response(state, e));
+    }
+    if content_type_is_denied(&config, &headers, &mut flags) {
+        let mime = "text/plain".parse::<Mime>().unwrap();
+        let res: (StatusCode, Mime, &str) = (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            mime,
+            UNSUPPORTED_CONTENT_TYPE_MESSAGE,
+        );
+        return Ok(res.into_response(state));
+    }
+    let content_encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .map(|encoding| String::from_utf8_lossy(encoding.as_bytes()));
+    let encoding = match compression::request_encoding(content_encoding.as_deref()) {
+        Ok(encoding) => encoding,
+        Err(e) => {
+            warn!("{}", e);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, mime, format!("{}", e));
+            return Ok(res.into_response(state));
+        }
+    };
+    let _permit = match config.in_flight.as_ref().map(|in_flight| in_flight.try_acquire()) {
+        Some(Err(_)) => return Ok(overloaded_response(state, &config)),
+        Some(Ok(permit)) => Some(permit),
+        None => None,
+    };
+    if let Some(lengths) = headers.get(BATCH_HEADER) {
+        let content_length = check_content_length(&headers, max_body_size(&config, false))?;
+        config.stats.body_received(content_length);
+        let max_buffered = config.inner.max_buffered_bytes;
+        let _buffered = match config.stats.try_buffer(content_length, max_buffered) {
+            Some(buffered) => buffered,
+            None => return Ok(overloaded_response(state, &config)),
+        };
+        let origin = origin(state, &config, &headers, false, flags);
+        return batch_handler(state, &config, &origin, lengths, content_length, encoding).await;
+    }
+
+    let streaming_threshold = config.inner.streaming_threshold;
+    let content_length =
+        check_content_length(&headers, max_body_size(&config, streaming_threshold.is_some()))?;
+    // Encoded bodies are decoded as a whole
+    let streamed = encoding.is_none()
+        && streaming_threshold.is_some_and(|threshold| content_length > threshold);
+    config.stats.body_received(content_length);
+    // Streamed bodies only hold their envelope header in memory
+    let buffered = if streamed { 0 } else { content_length };
+    let mut buffered = match config.stats.try_buffer(buffered, config.inner.max_buffered_bytes) {
+        Some(buffered) => buffered,
+        None => return Ok(overloaded_response(state, &config)),
+    };
+
+    let mut body = Body::take_from(state);
+    let (sentry_instance, rest) = if streamed {
+        let header = before_upload_timeout(&config, read_envelope_header(&mut body)).await?;
+        let sentry_instance = parse_body(header)?;
+        (sentry_instance, Some((body, content_length)))
+    } else {
+        if content_length > max_body_size(&config, false) {
+            return Err(AError::new(HeaderError::ContentIsTooBig));
+        }
+        let full_body =
+            before_upload_timeout(&config, read_body_pooled(body, &config, content_length))
+                .await?;
+        (parse_body(decode_body(&config, encoding, full_body)?)?, None)
+    };
+
+    let (mut sentry_instance, rest, signed) =
+        verify_signature(&config, &headers, sentry_instance, rest, &mut buffered).await?;
+    let retry = config.responses.as_ref().and_then(|responses| {
+        let event_id = sentry_instance.event_id()?;
+        Some((responses, sentry_instance.project_id().0, event_id))
+    });
+    if let Some((responses, project_id, event_id)) = &retry {
+        if let Some(cached) = responses.get(*project_id, event_id) {
+            config.buffers.give_back(sentry_instance.raw_body);
+            config.stats.response_replayed();
+            return Ok(replayed_response(state, cached));
+        }
+    }
+    let origin = origin(state, &config, &headers, signed, flags);
+    let processed = process_envelope(&config, &mut sentry_instance, rest, &origin).await;
+    config.buffers.give_back(sentry_instance.raw_body);
+    if let Some((responses, project_id, event_id)) = retry {
+        if let Some(response) = final_response(&processed, config.inner.relay_responses) {
+            responses.insert(project_id, &event_id, response);
+        }
+    }
+    if matches!(&processed, Ok(Some(upstream)) if upstream.is_rate_limited()) {
+        config.stats.upstream_rate_limited();
+    }
+    match processed {
+        Err(e) if e.is::<ForwardError>() => {
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::INTERNAL_SERVER_ERROR, mime, format!("{}", e));
+            let res = res.into_response(state);
+            Ok(res)
+        }
+        Err(e) if e.is::<QuotaError>() => {
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::TOO_MANY_REQUESTS, mime, format!("{}", e));
+            Ok(res.into_response(state))
+        }
+        Err(e) if e.is::<RateLimited>() => {
+            let limited = e.downcast_ref::<RateLimited>().unwrap();
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let mut res =
+                create_response(state, StatusCode::TOO_MANY_REQUESTS, mime, format!("{}", e));
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, limited.retry_after.into());
+            if let Ok(rate_limits) = HeaderValue::from_str(&limited.rate_limits) {
+                res.headers_mut().insert("X-Sentry-Rate-Limits", rate_limits);
+            }
+            Ok(res)
+        }
+        Err(e) if e.is::<QueueFull>() => Ok(overloaded_response(state, &config)),
+        Err(e) if e.is::<DeniedRegion>() => {
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) = (StatusCode::FORBIDDEN, mime, format!("{}", e));
+            Ok(res.into_response(state))
+        }
+        Err(e) => Err(e),
+        Ok(Some(upstream)) if config.inner.relay_responses => {
+            Ok(relayed_response(state, upstream))
+        }
+        Ok(Some(upstream)) => Ok(rate_limited_response(state, upstream)),
+        Ok(_) => {
+            let res = create_empty_response(state, StatusCode::OK);
+            Ok(res)
+        }
+    }
+}
+
+async fn post_tunnel_handler(mut state: State) -> HandlerResult {
+    let _pending = TunnelConfig::borrow_from(&state).drain.track();
+    let span = telemetry::request_span(HeaderMap::borrow_from(&state), "tunnel");
+    match tunnel_handler(&mut state).instrument(span).await {
+        Ok(val) => Ok((state, val)),
+        Err(error) if error.is::<UploadTimeout>() => {
+            warn!("{}", error);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let mut response =
+                create_response(&state, StatusCode::REQUEST_TIMEOUT, mime, format!("{}", error));
+            // The rest of the body is not read, the connection can not be reused
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+            Ok((state, response))
+        }
+        Err(error) if error.is::<Overloaded>() => {
+            let config = TunnelConfig::current(&state);
+            let mut response = overloaded_response(&state, &config);
+            // The rest of the body is not read, the connection can not be reused
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+            Ok((state, response))
+        }
+        Err(error)
+            if matches!(
+                error.downcast_ref::<HeaderError>(),
+                Some(HeaderError::ContentIsTooBig)
+            ) =>
+        {
+            let config = TunnelConfig::current(&state);
+            let streamed = config.inner.streaming_threshold.is_some();
+            let body = json!({
+                "error": format!("{}", error),
+                "max_body_size": max_body_size(&config, streamed),
+            });
+            let response = create_response(
+                &state,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                mime::APPLICATION_JSON,
+                body.to_string(),
+            );
+            Ok((state, response))
+        }
+        Err(error) => {
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) = (
+                StatusCode::BAD_REQUEST,
+                mime,
+                format!("{}", error),
+            );
+            let response = res.into_response(&state);
+            Ok((state, response))
+        }
+    }
+}
+
+
+async fn otlp_handler(state: &mut State) -> Result<Response<Body>, AError> {
+    let headers = HeaderMap::take_from(state);
+    let config = TunnelConfig::current(state);
+    let mut flags = vec![];
+    if let Some(message) = refused_client(state, &config, &headers, &mut flags) {
+        let mime = "text/plain".parse::<Mime>().unwrap();
+        let res: (StatusCode, Mime, &str) = (StatusCode::FORBIDDEN, mime, message);
+        return Ok(res.into_response(state));
+    }
+    if let Err(e) = check_auth_token(&config, &headers) {
+        return Ok(unauthorized_response(state, e));
+    }
+    check_content_length(&headers, MAX_CONTENT_SIZE)?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/json");
+    if !content_type.starts_with("application/json") {
+        let mime = "text/plain".parse::<Mime>().unwrap();
+        let res: (StatusCode, Mime, String) = (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            mime,
+            "Only the JSON encoding of OTLP is supported".to_string(),
+        );
+        return Ok(res.into_response(state));
+    }
+
+    let full_body = body::to_bytes(Body::take_from(state)).await?;
+    let request: ExportTraceServiceRequest = serde_json::from_slice(&full_body)?;
+
+    let dsn = config
+        .inner
+        .otlp_dsn
+        .clone()
+        .ok_or_else(|| AError::msg("No dsn configured for OTLP traces"))?;
+    let mut rejected_spans = 0;
+    let mut error_message = String::new();
+    for transaction in request.into_transactions(&dsn) {
+        let mut envelope = transaction.envelope;
+        if !config
+            .inner
+            .project_id_is_allowed(envelope.dsn.project_id().value())
+        {
+            return Err(AError::new(BodyError::InvalidProjectId));
+        }
+        if !envelope.dsn_host_is_valid(&config.inner.allowed_hosts()) {
+            return Err(AError::new(HeaderError::InvalidHost));
+        }
+        check_region(&config, &envelope)?;
+        let proxy = region::proxy(&config.inner.region_proxies, envelope.dsn.host());
+        if let Err(e) = access::upstream(forward_buffered(&config, &mut envelope, proxy)).await {
+            let message = format!(
+                "Failed to forward OTLP transaction to sentry : {} - Host = {}",
+                e,
+                envelope.dsn.host()
+            );
+            error!("{}", message);
+            monitoring::capture_error(&message);
+            rejected_spans += transaction.span_count;
+            error_message = format!("{}", e);
+        }
+    }
+
+    let response = if rejected_spans > 0 {
+        json!({
+            "partialSuccess": {
+                "rejectedSpans": rejected_spans,
+                "errorMessage": error_message,
+            }
+        })
+    } else {
+        json!({})
+    };
+    Ok(create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        response.to_string(),
+    ))
+}
+
+async fn post_otlp_handler(mut state: State) -> HandlerResult {
+    let _pending = TunnelConfig::borrow_from(&state).drain.track();
+    let span = telemetry::request_span(HeaderMap::borrow_from(&state), "otlp");
+    match otlp_handler(&mut state).instrument(span).await {
+        Ok(val) => Ok((state, val)),
+        Err(error) => {
+            warn!("{}", error);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::BAD_REQUEST, mime, format!("{}", error));
+            let response = res.into_response(&state);
+            Ok((state, response))
+        }
+    }
+}
+
+/**
+ * Wait for the body to be read, failing after the upload timeout when one is configured, so that
+ * clients trickling their body do not hold a connection for minutes
+ */
+async fn before_upload_timeout<T, F>(config: &TunnelConfig, read: F) -> Result<T, AError>
+where
+    F: Future<Output = Result<T, AError>>,
+{
+    match config.inner.upload_timeout {
+        Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), read)
+            .await
+            .unwrap_or_else(|_| {
+                config.stats.upload_timed_out();
+                Err(AError::new(UploadTimeout))
+            }),
+        None => read.await,
+    }
+}
+
+/**
+ * The size of the bodies accepted on the tunnel path, buffered or `streamed`
+ */
+fn max_body_size(config: &TunnelConfig, streamed: bool) -> u64 {
+    match config.inner.max_body_size {
+        Some(max_body_size) => max_body_size,
+        None if streamed => MAX_STREAMED_CONTENT_SIZE,
+        None => MAX_CONTENT_SIZE,
+    }
+}
+
+/**
+ * Decode a body sent with a `Content-Encoding`, giving the encoded one back to the pool
+ */
+fn decode_body(
+    config: &TunnelConfig,
+    encoding: Option<Encoding>,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, AError> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(body),
+    };
+    let decoded = encoding.decompress(&body, max_body_size(config, false)).map_err(|e| {
+        AError::msg(format!("Could not decode the {} request body : {}", encoding, e))
+    });
+    config.buffers.give_back(body);
+    decoded
+}
+
+/**
+ * Reject bodies whose size differs from their Content-Length, so that truncated uploads are not
+ * forwarded as corrupt envelopes
+ */
+fn check_received(
+    config: &TunnelConfig,
+    declared: u64,
+    received: usize,
+) -> Result<(), HeaderError> {
+    let received = received as u64;
+    if received != declared {
+        config.stats.body_length_mismatched();
+        return Err(HeaderError::LengthMismatch { declared, received });
+    }
+    Ok(())
+}
+
+/**
+ * Read a body announcing its length into a buffer of the pool. A client going away in the middle
+ * of its upload ends the body early.
+ */
+async fn read_body_pooled(
+    mut body: Body,
+    config: &TunnelConfig,
+    content_length: u64,
+) -> Result<Vec<u8>, AError> {
+    let mut read = config.buffers.take(content_length as usize);
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => read.extend_from_slice(&chunk),
+            Err(_) => break,
+        }
+        if read.len() as u64 > content_length {
+            break;
+        }
+    }
+    if let Err(e) = check_received(config, content_length, read.len()) {
+        config.buffers.give_back(read);
+        return Err(AError::new(e));
+    }
+    Ok(read)
+}
+
+/**
+ * Read a body that may not announce its length, failing once it exceeds `max` bytes
+ */
+async fn read_body_limited(mut body: Body, max: u64) -> Result<Vec<u8>, AError> {
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        read.extend_from_slice(&chunk?);
+        if read.len() as u64 > max {
+            return Err(AError::new(HeaderError::ContentIsTooBig));
+        }
+    }
+    Ok(read)
+}
+
+fn grpc_response(
+    state: &State,
+    status: u32,
+    message: &str,
+    payload: Option<Vec<u8>>,
+) -> Response<Body> {
+    let mut response = create_empty_response(state, StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    match payload {
+        // Trailers-Only response, the status is sent with the headers
+        None => {
+            headers.insert("grpc-status", status.into());
+            if let Ok(message) = HeaderValue::from_str(&grpc::encode_message(message)) {
+                headers.insert("grpc-message", message);
+            }
+        }
+        Some(payload) => {
+            let (mut sender, body) = Body::channel();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", status.into());
+            tokio::spawn(async move {
+                if sender.send_data(payload.into()).await.is_ok() {
+                    let _ = sender.send_trailers(trailers).await;
+                }
+            });
+            *response.body_mut() = body;
+        }
+    }
+    response
+}
+
+async fn post_grpc_handler(mut state: State) -> HandlerResult {
+    let config = TunnelConfig::current(&state);
+    let _pending = config.drain.track();
+    let mut flags = vec![];
+    if let Some(message) =
+        refused_client(&state, &config, HeaderMap::borrow_from(&state), &mut flags)
+    {
+        let response = grpc_response(&state, grpc::STATUS_PERMISSION_DENIED, message, None);
+        return Ok((state, response));
+    }
+    if let Err(e) = check_auth_token(&config, HeaderMap::borrow_from(&state)) {
+        warn!("{}", e);
+        let response =
+            grpc_response(&state, grpc::STATUS_UNAUTHENTICATED, &format!("{}", e), None);
+        return Ok((state, response));
+    }
+    if let Err(e) = check_framing(HeaderMap::borrow_from(&state)) {
+        warn!("{}", e);
+        let response =
+            grpc_response(&state, grpc::STATUS_INVALID_ARGUMENT, &format!("{}", e), None);
+        return Ok((state, response));
+    }
+    let origin = origin(&state, &config, HeaderMap::borrow_from(&state), false, flags);
+    let processed = match read_body_limited(Body::take_from(&mut state), MAX_CONTENT_SIZE).await {
+        Ok(body) => match grpc::decode_submit_envelope(&body) {
+            Ok(envelope) => match parse_body(envelope) {
+                Ok(mut sentry_instance) => {
+                    process_envelope(&config, &mut sentry_instance, None, &origin).await
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(AError::new(e)),
+        },
+        Err(e) => Err(e),
+    };
+    let response = match processed {
+        Ok(_) => grpc_response(
+            &state,
+            grpc::STATUS_OK,
+            "",
+            Some(grpc::encode_submit_envelope_response()),
+        ),
+        Err(e) => {
+            let status = if e.is::<ForwardError>() {
+                grpc::STATUS_UNAVAILABLE
+            } else if e.is::<QuotaError>() || e.is::<RateLimited>() {
+                grpc::STATUS_RESOURCE_EXHAUSTED
+            } else if e.is::<QueueFull>() {
+                grpc::STATUS_UNAVAILABLE
+            } else if e.is::<DeniedRegion>() {
+                grpc::STATUS_PERMISSION_DENIED
+            } else if let Some(e) = e.downcast_ref::<GrpcError>() {
+                e.status()
+            } else {
+                grpc::STATUS_INVALID_ARGUMENT
+            };
+            warn!("{}", e);
+            grpc_response(&state, status, &format!("{}", e), None)
+        }
+    };
+    Ok((state, response))
+}
+
+/**
+ * Read envelopes from a WebSocket connection until it is closed, answering each of them with its
+ * outcome
+ */
+async fn websocket_session<S>(mut socket: WebSocketStream<S>, config: TunnelConfig, origin: Origin)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(message) = socket.next().await {
+        let envelope = match message {
+            Ok(Message::Binary(envelope)) => envelope,
+            Ok(Message::Text(envelope)) => envelope.into_bytes(),
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("WebSocket connection failed : {}", e);
+                break;
+            }
+        };
+        let outcome = envelope_outcome(&config, &origin, envelope).await;
+        if let Err(e) = socket.send(Message::Text(outcome.to_string())).await {
+            warn!("WebSocket connection failed : {}", e);
+            break;
+        }
+    }
+}
+
+fn websocket_upgrade(state: &mut State) -> Result<Response<Body>, AError> {
+    let headers = HeaderMap::borrow_from(state);
+    let is_upgrade = headers
+        .get(header::UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let key = match headers.get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade => key,
+        _ => return Err(AError::msg("Expected a WebSocket upgrade request")),
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    let config = TunnelConfig::current(state);
+    let mut flags = vec![];
+    if user_agent_is_denied(&config, headers, &mut flags)
+        || refused_client(state, &config, headers, &mut flags).is_some()
+    {
+        return Ok(create_empty_response(state, StatusCode::FORBIDDEN));
+    }
+    if let Err(e) = check_auth_token(&config, headers) {
+        return Ok(unauthorized_response(state, e));
+    }
+    let origin = origin(state, &config, headers, false, flags);
+    let on_upgrade = state
+        .try_take::<OnUpgrade>()
+        .ok_or_else(|| AError::msg("This connection can not be upgraded"))?;
+
+    let socket_config = WebSocketConfig {
+        max_message_size: Some(MAX_CONTENT_SIZE as usize),
+        ..Default::default()
+    };
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let socket =
+                    WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(socket_config))
+                        .await;
+                websocket_session(socket, config, origin).await;
+            }
+            Err(e) => warn!("WebSocket upgrade failed : {}", e),
+        }
+    });
+
+    let mut response = create_empty_response(state, StatusCode::SWITCHING_PROTOCOLS);
+    let headers = response.headers_mut();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept)?);
+    Ok(response)
+}
+
+async fn get_websocket_handler(mut state: State) -> HandlerResult {
+    match websocket_upgrade(&mut state) {
+        Ok(val) => Ok((state, val)),
+        Err(error) => {
+            warn!("{}", error);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::BAD_REQUEST, mime, format!("{}", error));
+            let response = res.into_response(&state);
+            Ok((state, response))
+        }
+    }
+}
+
+/**
+ * Decoy path that no legitimate client requests : log and ban the scanner
+ */
+async fn honeypot_handler(state: State) -> HandlerResult {
+    let config = TunnelConfig::current(&state);
+    let headers = HeaderMap::borrow_from(&state);
+    let ip = client_ip(&state, &config, headers);
+    config.stats.honeypot_hit();
+    warn!(
+        "Honeypot {} {} requested by {:?} - User-Agent = {:?}",
+        Method::borrow_from(&state),
+        Uri::borrow_from(&state).path(),
+        ip,
+        headers.get(header::USER_AGENT)
+    );
+    if let (Some(bans), Some(ip)) = (&config.bans, ip) {
+        bans.ban(ip);
+    }
+    let response = create_empty_response(&state, StatusCode::NOT_FOUND);
+    Ok((state, response))
+}
+
+async fn toggles_handler(state: &mut State) -> Result<Response<Body>, AError> {
+    let headers = HeaderMap::take_from(state);
+    let config = TunnelConfig::current(state);
+    let authenticated = presented_token(&headers)
+        .and_then(|token| auth::authenticate(&config.inner.admin_tokens, token));
+    if let Err(e) = authenticated {
+        return Ok(unauthorized_response(state, e));
+    }
+    if Method::borrow_from(state) == Method::PUT {
+        check_content_length(&headers, MAX_CONTENT_SIZE)?;
+        let full_body = body::to_bytes(Body::take_from(state)).await?;
+        let changes: serde_json::Map<String, Value> = serde_json::from_slice(&full_body)?;
+        if let Err(e) = config.toggles.apply(&changes) {
+            let status = match e {
+                ToggleError::Persist(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            warn!("{}", e);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) = (status, mime, format!("{}", e));
+            return Ok(res.into_response(state));
+        }
+    }
+    Ok(create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        config.toggles.to_json().to_string(),
+    ))
+}
+
+/**
+ * List the filters and whether they are enabled, or override them with a PUT
+ */
+async fn admin_toggles_handler(mut state: State) -> HandlerResult {
+    match toggles_handler(&mut state).await {
+        Ok(val) => Ok((state, val)),
+        Err(error) => {
+            warn!("{}", error);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::BAD_REQUEST, mime, format!("{}", error));
+            let response = res.into_response(&state);
+            Ok((state, response))
+        }
+    }
+}
+
+async fn allowlist_handler(state: &mut State) -> Result<Response<Body>, AError> {
+    let headers = HeaderMap::take_from(state);
+    let config = TunnelConfig::current(state);
+    let authenticated = presented_token(&headers)
+        .and_then(|token| auth::authenticate(&config.inner.admin_tokens, token));
+    if let Err(e) = authenticated {
+        return Ok(unauthorized_response(state, e));
+    }
+    let allowlist = &config.inner.allowlist;
+    let adding = Method::borrow_from(state) == Method::PUT;
+    let path = Uri::borrow_from(state).path().to_string();
+    let changed = match path.strip_prefix(ADMIN_PROJECTS_PATH) {
+        Some(id) => {
+            let id = ProjectId::from_str(id.trim_start_matches('/')).map_err(AError::msg)?;
+            if adding {
+                allowlist.add_project(id)
+            } else {
+                allowlist.remove_project(id)
+            }
+        }
+        None => {
+            check_content_length(&headers, MAX_CONTENT_SIZE)?;
+            let full_body = body::to_bytes(Body::take_from(state)).await?;
+            let host = String::from_utf8_lossy(&full_body).trim().to_string();
+            if adding {
+                allowlist.add_host(&host)
+            } else {
+                allowlist.remove_host(&host)
+            }
+        }
+    };
+    if let Err(e) = changed {
+        let status = match e {
+            AllowlistError::Persist(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        warn!("{}", e);
+        let mime = "text/plain".parse::<Mime>().unwrap();
+        let res: (StatusCode, Mime, String) = (status, mime, format!("{}", e));
+        return Ok(res.into_response(state));
+    }
+    Ok(create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        allowlist.to_json().to_string(),
+    ))
+}
+
+/**
+ * Add a project or a host with a PUT, or remove it with a DELETE, without a redeploy
+ */
+async fn admin_allowlist_handler(mut state: State) -> HandlerResult {
+    match allowlist_handler(&mut state).await {
+        Ok(val) => Ok((state, val)),
+        Err(error) => {
+            warn!("{}", error);
+            let mime = "text/plain".parse::<Mime>().unwrap();
+            let res: (StatusCode, Mime, String) =
+                (StatusCode::BAD_REQUEST, mime, format!("{}", error));
+            let response = res.into_response(&state);
+            Ok((state, response))
+        }
+    }
+}
+
+/**
+ * Answer the HTTP-01 challenges of the ACME server with their key authorization
+ */
+async fn acme_challenge_handler(state: State) -> HandlerResult {
+    let config = TunnelConfig::current(&state);
+    let token = Uri::borrow_from(&state)
+        .path()
+        .trim_start_matches(acme::CHALLENGE_PATH)
+        .to_string();
+    let response = match config.inner.acme_challenges.get(&token) {
+        Some(key_authorization) => {
+            create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, key_authorization)
+        }
+        None => create_empty_response(&state, StatusCode::NOT_FOUND),
+    };
+    Ok((state, response))
+}
+
+async fn metrics_handler(state: State) -> HandlerResult {
+    let config = TunnelConfig::borrow_from(&state);
+    let mut rendered = config.stats.render();
+    if let Some(lifetime) = &config.lifetime {
+        lifetime.render(&mut rendered);
+    }
+    let mime = "text/plain; version=0.0.4".parse::<Mime>().unwrap();
+    let response = create_response(&state, StatusCode::OK, mime, rendered);
+    Ok((state, response))
+}
+
+async fn health_handler(state: State) -> HandlerResult {
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain")
+        .body(Body::from("OK"))
+        .unwrap();
+    Ok((state, response))
+}
+
+/**
+ * Whether the tunnel can forward envelopes : it has remote hosts to forward them to and, when
+ * the upstream check is enabled, one of them answers
+ */
+async fn readiness_handler(state: State) -> HandlerResult {
+    let config = TunnelConfig::current(&state);
+    let hosts = config.inner.upstream_hosts();
+    let not_ready = if config.drain.is_draining() {
+        Some("The tunnel is shutting down".to_string())
+    } else if hosts.is_empty() {
+        Some("No remote hosts to forward envelopes to".to_string())
+    } else if config.inner.readiness_upstream_check {
+        // Patterns name no host that could be probed
+        let probes = hosts.iter().filter(|host| !host.is_pattern()).map(|host| {
+            let url = host.to_string();
+            let client = config.client.clone();
+            async move {
+                match client.probe(&url, READINESS_TIMEOUT).await {
+                    Ok(status) if status < 500 => Ok(()),
+                    Ok(status) => Err(format!("{} answered {}", url, status)),
+                    Err(e) => Err(format!("{} is unreachable : {}", url, e)),
+                }
+            }
+        });
+        let probed = future::join_all(probes).await;
+        if probed.is_empty() || probed.iter().any(Result::is_ok) {
+            None
+        } else {
+            let errors: Vec<String> = probed.into_iter().filter_map(Result::err).collect();
+            Some(errors.join("\n"))
+        }
+    } else {
+        None
+    };
+    let response = match not_ready {
+        None => create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "OK"),
+        Some(reason) => {
+            warn!("The tunnel is not ready : {}", reason);
+            create_response(&state, StatusCode::SERVICE_UNAVAILABLE, mime::TEXT_PLAIN, reason)
+        }
+    };
+    Ok((state, response))
+}
+
+/**
+ * Run a request that was not accepted by the gotham listener through the router
+ */
+pub async fn dispatch(
+    router: &Router,
+    request: Request<Body>,
+    client_addr: SocketAddr,
+    identity: Option<ClientIdentity>,
+) -> Response<Body> {
+    let mut state = State::from_request(request, client_addr);
+    if let Some(identity) = identity {
+        state.put(identity);
+    }
+    let handler = match router.new_handler() {
+        Ok(handler) => handler,
+        Err(e) => {
+            error!("Failed to create the request handler : {}", e);
+            return create_empty_response(&state, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    match handler.handle(state).await {
+        Ok((_, response)) => response,
+        Err((state, e)) => e.into_response(&state),
+    }
+}
+
+pub fn router(path: &str, config: Config) -> Router {
+    reloadable_router(path, config).0
+}
+
+/**
+ * Build the router along with a handle replacing its configuration. Only the values read on each
+ * request are reloaded, routes, quotas and filters keep the initial configuration.
+ */
+pub fn reloadable_router(path: &str, config: Config) -> (Router, ConfigHandle) {
+    let client = Arc::new(UpstreamClient::new(
+        config.upstream_max_connections,
+        config.upstream_idle_connections,
+    ));
+    let sessions = config
+        .session_aggregation_window
+        .map(|window| {
+            Arc::new(SessionAggregator::new(
+                Duration::from_secs(window),
+                config.region_proxies.clone(),
+                client.clone(),
+            ))
+        });
+    let quotas = if config.daily_quotas.is_empty() && config.monthly_quotas.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Quotas::new(
+            config.daily_quotas.clone(),
+            config.monthly_quotas.clone(),
+        )))
+    };
+    let rate_limits = if config.rate_limit_cache {
+        Some(Arc::new(RateLimits::default()))
+    } else {
+        None
+    };
+    let spam = config.spam_window.map(|window| {
+        Arc::new(SpamFilter::new(
+            Duration::from_secs(window),
+            config.spam_limit,
+        ))
+    });
+    let responses = config
+        .idempotency_window
+        .map(|window| Arc::new(ResponseCache::new(Duration::from_secs(window))));
+    let bans = if config.honeypot_paths.is_empty() || config.ban_duration == 0 {
+        None
+    } else {
+        Some(Arc::new(BanList::new(Duration::from_secs(config.ban_duration))))
+    };
+    let canary = if config.canary_routes.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Canary::new(config.canary_routes.clone())))
+    };
+    let toggles = Arc::new(Toggles::load(config.toggles_path.clone()));
+    let buffers = Arc::new(BufferPool::new(config.buffer_pool_size));
+    let in_flight = config
+        .max_in_flight
+        .map(|max_in_flight| Arc::new(Semaphore::new(max_in_flight)));
+    let pacer = config.upstream_rate.map(|rate| {
+        Arc::new(Pacer::new(rate, Duration::from_millis(config.upstream_max_delay)))
+    });
+    let compression = if config.upstream_encodings.is_empty() {
+        None
+    } else {
+        Some(Arc::new(UpstreamCompression::new(
+            config.upstream_encodings.clone(),
+            config.compression_threshold,
+        )))
+    };
+    let stats = Arc::new(Stats::default());
+    let spool = config.spool_dir.as_ref().and_then(|dir| {
+        match Spool::open(
+            PathBuf::from(dir),
+            config.spool_max_size,
+            Duration::from_secs(config.spool_max_age),
+            config.region_proxies.clone(),
+            client.clone(),
+            stats.clone(),
+        ) {
+            Ok(spool) => Some(Arc::new(spool)),
+            Err(e) => {
+                error!("Could not open the spool directory {} : {}", dir, e);
+                None
+            }
+        }
+    });
+    if let Some(spool) = &spool {
+        // Send what a previous run spooled, when the router is built inside a runtime
+        if spool.is_pending() && tokio::runtime::Handle::try_current().is_ok() {
+            spool.start_flusher();
+        }
+    }
+    let queue = if config.async_forwarding {
+        Some(Arc::new(ForwardQueue::new(config.queue_size, config.queue_workers)))
+    } else {
+        None
+    };
+    let lifetime = config
+        .stats_path
+        .clone()
+        .map(|path| Arc::new(LifetimeStats::load(path)));
+    let admin_enabled = !config.admin_tokens.is_empty();
+    let acme_enabled = !config.acme_domains.is_empty();
+    let honeypot_paths = config.honeypot_paths.clone();
+    let otlp_path = config.otlp_path.clone();
+    let grpc_enabled = config.grpc;
+    let websocket_path = config.websocket_path.clone();
+    let extra_paths = config.extra_paths.clone();
+    let access_log = AccessLog::new(config.access_log, config.client_ip_header.clone());
+    let mut tunnel_paths = vec![path.to_string()];
+    tunnel_paths.extend(extra_paths.iter().cloned());
+    let cors = Cors::new(
+        &config.cors_allowed_origins,
+        &config.cors_allowed_headers,
+        &tunnel_paths,
+    );
+    let drain = Arc::new(Drain::default());
+    let inner = Arc::new(config);
+    let live = ConfigHandle {
+        config: Arc::new(RwLock::new(inner.clone())),
+        drain: drain.clone(),
+        sessions: sessions.clone(),
+    };
+    let middleware = StateMiddleware::new(TunnelConfig {
+        inner,
+        sessions,
+        quotas,
+        rate_limits,
+        spam,
+        responses,
+        bans,
+        canary,
+        buffers,
+        in_flight,
+        pacer,
+        compression,
+        spool,
+        queue,
+        client,
+        stats,
+        lifetime,
+        toggles,
+        drain,
+        live: live.clone(),
+    });
+    let pipeline = new_pipeline().add(access_log).add(cors).add(middleware).build();
+    let (chain, pipelines) = single_pipeline(pipeline);
+
+    let router = build_router(chain, pipelines, |route| {
+        route.post(path).to_async(post_tunnel_handler);
+        for extra_path in &extra_paths {
+            route.post(extra_path).to_async(post_tunnel_handler);
+        }
+        if grpc_enabled {
+            route
+                .post(grpc::SUBMIT_ENVELOPE_PATH)
+                .to_async(post_grpc_handler);
+        }
+        if let Some(otlp_path) = &otlp_path {
+            route.post(otlp_path).to_async(post_otlp_handler);
+        }
+        if let Some(websocket_path) = &websocket_path {
+            route.get(websocket_path).to_async(get_websocket_handler);
+        }
+        if admin_enabled {
+            route
+                .request(vec![Method::GET, Method::PUT], ADMIN_TOGGLES_PATH)
+                .to_async(admin_toggles_handler);
+            route
+                .request(
+                    vec![Method::PUT, Method::DELETE],
+                    &format!("{}/:id", ADMIN_PROJECTS_PATH),
+                )
+                .to_async(admin_allowlist_handler);
+            route
+                .request(vec![Method::PUT, Method::DELETE], ADMIN_HOSTS_PATH)
+                .to_async(admin_allowlist_handler);
+        }
+        if acme_enabled {
+            route
+                .get(&format!("{}:token", acme::CHALLENGE_PATH))
+                .to_async(acme_challenge_handler);
+        }
+        for honeypot_path in &honeypot_paths {
+            route
+                .request(
+                    vec![Method::GET, Method::HEAD, Method::POST, Method::PUT],
+                    honeypot_path,
+                )
+                .to_async(honeypot_handler);
+        }
+        route.get("/healthz").to_async(health_handler);
+        route.get("/readyz").to_async(readiness_handler);
+        route.get("/metrics").to_async(metrics_handler);
+    });
+    (router, live)
 }
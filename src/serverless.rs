@@ -0,0 +1,67 @@
+use crate::config::Config;
+use crate::server::{dispatch, router};
+use gotham::hyper::body::{self, Bytes};
+use gotham::hyper::{Body, Request, Response, StatusCode};
+use gotham::router::Router;
+use log::*;
+
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+// Built on the first call, so that quotas and filters keep their state across invocations
+static ROUTER: OnceLock<Router> = OnceLock::new();
+
+/**
+ * Handle a single request without a listener, for function runtimes. The router is built from
+ * the configuration of the first call and reused by the following ones. Function runtimes do not
+ * expose the client address, TUNNEL_CLIENT_IP_HEADER should be set to the header holding it.
+ */
+pub async fn handle(request: Request<Bytes>, config: &Config) -> Response<Bytes> {
+    let router = ROUTER.get_or_init(|| router(&config.tunnel_path, config.clone()));
+    respond_with(router, request).await
+}
+
+/**
+ * Handle a single request with a tunnel built from this configuration, without a listener or
+ * framework. Unlike `handle`, every call starts from a fresh tunnel, so quotas, bans and filters
+ * only see that request. This makes it suited to unit tests checking how a configuration treats
+ * a request.
+ */
+pub async fn respond(request: Request<Bytes>, config: &Config) -> Response<Bytes> {
+    respond_with(&router(&config.tunnel_path, config.clone()), request).await
+}
+
+async fn respond_with(router: &Router, request: Request<Bytes>) -> Response<Bytes> {
+    let client_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    let response = dispatch(router, request.map(Body::from), client_addr, None).await;
+    let (parts, response_body) = response.into_parts();
+    match body::to_bytes(response_body).await {
+        Ok(bytes) => Response::from_parts(parts, bytes),
+        Err(e) => {
+            error!("Failed to read the response body : {}", e);
+            let mut response = Response::new(Bytes::new());
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            response
+        }
+    }
+}
+
+/**
+ * Serve the tunnel from AWS Lambda, behind an API Gateway, an ALB or a function url
+ */
+#[cfg(feature = "lambda")]
+pub async fn run_lambda(config: Config) -> Result<(), lambda_http::Error> {
+    let config = &config;
+    lambda_http::run(lambda_http::service_fn(
+        move |request: lambda_http::Request| async move {
+            let (parts, request_body) = request.into_parts();
+            let request = Request::from_parts(parts, Bytes::from(request_body.to_vec()));
+            let (parts, response_body) = handle(request, config).await.into_parts();
+            Ok::<_, lambda_http::Error>(lambda_http::Response::from_parts(
+                parts,
+                lambda_http::Body::from(response_body.to_vec()),
+            ))
+        },
+    ))
+    .await
+}
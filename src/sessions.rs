@@ -0,0 +1,324 @@
+use crate::envelope::SentryEnvelope;
+use crate::forward::UpstreamClient;
+use crate::monitoring;
+use crate::region;
+use log::*;
+use sentry_types::protocol::v7::{
+    SessionAggregateItem, SessionAggregates, SessionAttributes, SessionStatus, SessionUpdate,
+};
+use sentry_types::{DateTime, Dsn, TimeZone, Utc, Uuid};
+use serde_json::json;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/**
+ * Final state of a session, ordered by severity. A session is only counted once it ended, with
+ * the most severe state reported by its updates.
+ */
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Outcome {
+    Exited,
+    Errored,
+    Abnormal,
+    Crashed,
+}
+
+impl Outcome {
+    /**
+     * Returns the outcome of a session ended by this update, None while it is still running
+     */
+    fn of(update: &SessionUpdate) -> Option<Outcome> {
+        match update.status {
+            SessionStatus::Ok => None,
+            SessionStatus::Crashed => Some(Outcome::Crashed),
+            SessionStatus::Abnormal => Some(Outcome::Abnormal),
+            _ if update.errors > 0 => Some(Outcome::Errored),
+            _ => Some(Outcome::Exited),
+        }
+    }
+
+    fn counter(self, item: &mut SessionAggregateItem) -> &mut u32 {
+        match self {
+            Outcome::Exited => &mut item.exited,
+            Outcome::Errored => &mut item.errored,
+            Outcome::Abnormal => &mut item.abnormal,
+            Outcome::Crashed => &mut item.crashed,
+        }
+    }
+}
+
+/**
+ * Sessions are aggregated per dsn, release and environment
+ */
+type BucketKey = (String, String, Option<String>);
+
+/**
+ * Inside a bucket, sessions are grouped by the minute they started and their distinct id
+ */
+type ItemKey = (DateTime<Utc>, Option<String>);
+
+#[derive(Debug)]
+struct Bucket {
+    dsn: Dsn,
+    attributes: SessionAttributes<'static>,
+    items: HashMap<ItemKey, SessionAggregateItem>,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    buckets: HashMap<BucketKey, Bucket>,
+    sessions: HashMap<Uuid, (BucketKey, ItemKey, Outcome)>,
+    /** Latest update of the sessions started in this window that did not end yet */
+    running: HashMap<Uuid, (Dsn, SessionUpdate<'static>)>,
+}
+
+/**
+ * Aggregates individual `session` items into `sessions` items that are periodically flushed to
+ * sentry, the same way relay does for high volume clients.
+ */
+#[derive(Debug)]
+pub struct SessionAggregator {
+    window: Duration,
+    proxies: HashMap<String, String>,
+    client: Arc<UpstreamClient>,
+    current: Mutex<Window>,
+    flusher_started: AtomicBool,
+}
+
+impl SessionAggregator {
+    /**
+     * Aggregate sessions over `window`, forwarding them through the proxy of their sentry.io
+     * region if any
+     */
+    pub fn new(
+        window: Duration,
+        proxies: HashMap<String, String>,
+        client: Arc<UpstreamClient>,
+    ) -> SessionAggregator {
+        SessionAggregator {
+            window,
+            proxies,
+            client,
+            current: Mutex::new(Window::default()),
+            flusher_started: AtomicBool::new(false),
+        }
+    }
+
+    /**
+     * Try to absorb an envelope into the current window. Returns false if the envelope must be
+     * forwarded as is : it contains something else than sessions, or an update for a session
+     * that was not started in the current window.
+     *
+     * Only ended sessions are aggregated. Sessions still running when the window is flushed are
+     * forwarded with their latest update, so that their later updates are not counted twice.
+     */
+    pub fn absorb(self: &Arc<Self>, envelope: &SentryEnvelope) -> bool {
+        let items = match envelope.items() {
+            Ok(items) if !items.is_empty() => items,
+            _ => return false,
+        };
+        let mut updates = vec![];
+        for item in items {
+            if item.item_type() != Some("session") {
+                return false;
+            }
+            match serde_json::from_slice::<SessionUpdate<'static>>(item.payload) {
+                Ok(update) => updates.push(update),
+                Err(_) => return false,
+            }
+        }
+
+        let mut current = self.current.lock().unwrap();
+        if !updates.iter().all(|update| {
+            update.init
+                || current.sessions.contains_key(&update.session_id)
+                || current.running.contains_key(&update.session_id)
+        }) {
+            return false;
+        }
+        for update in updates {
+            current.record(&envelope.dsn, update);
+        }
+        drop(current);
+
+        self.start_flusher();
+        true
+    }
+
+    fn start_flusher(self: &Arc<Self>) {
+        if self.flusher_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let aggregator = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(aggregator.window);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                aggregator.flush().await;
+            }
+        });
+    }
+
+    /**
+     * Forward every aggregate of the current window and start a new one
+     */
+    pub async fn flush(&self) {
+        let window = std::mem::take(&mut *self.current.lock().unwrap());
+        let mut envelopes: Vec<SentryEnvelope> = window
+            .buckets
+            .into_values()
+            .map(Bucket::into_envelope)
+            .collect();
+        let mut running: HashMap<String, (Dsn, Vec<SessionUpdate<'static>>)> = HashMap::new();
+        for (dsn, update) in window.running.into_values() {
+            running
+                .entry(dsn.to_string())
+                .or_insert_with(|| (dsn, vec![]))
+                .1
+                .push(update);
+        }
+        envelopes.extend(
+            running
+                .into_values()
+                .map(|(dsn, updates)| session_envelope(dsn, &updates)),
+        );
+        for mut envelope in envelopes {
+            let proxy = region::proxy(&self.proxies, envelope.dsn.host());
+            if let Err(e) = envelope.forward_via(&self.client, proxy).await {
+                let message = format!(
+                    "Failed to forward aggregated sessions to sentry : {} - Host = {}",
+                    e,
+                    envelope.dsn.host()
+                );
+                error!("{}", message);
+                monitoring::capture_error(&message);
+            }
+        }
+    }
+}
+
+impl Window {
+    fn record(&mut self, dsn: &Dsn, mut update: SessionUpdate<'static>) {
+        let outcome = match Outcome::of(&update) {
+            Some(outcome) => outcome,
+            None if self.sessions.contains_key(&update.session_id) => return,
+            None => {
+                let init = update.init
+                    || self
+                        .running
+                        .get(&update.session_id)
+                        .map_or(false, |(_, previous)| previous.init);
+                update.init = init;
+                self.running
+                    .insert(update.session_id, (dsn.clone(), update));
+                return;
+            }
+        };
+        self.running.remove(&update.session_id);
+        if let Some((bucket_key, item_key, previous)) = self.sessions.get_mut(&update.session_id) {
+            if outcome > *previous {
+                if let Some(item) = self
+                    .buckets
+                    .get_mut(bucket_key)
+                    .and_then(|bucket| bucket.items.get_mut(item_key))
+                {
+                    *previous.counter(item) -= 1;
+                    *outcome.counter(item) += 1;
+                }
+                *previous = outcome;
+            }
+            return;
+        }
+
+        let attributes = update.attributes;
+        let bucket_key = (
+            dsn.to_string(),
+            attributes.release.to_string(),
+            attributes.environment.as_ref().map(|env| env.to_string()),
+        );
+        let started = update.started.timestamp();
+        let minute = Utc
+            .timestamp_opt(started - started.rem_euclid(60), 0)
+            .single()
+            .unwrap_or(update.started);
+        let item_key = (minute, update.distinct_id);
+        let bucket = self
+            .buckets
+            .entry(bucket_key.clone())
+            .or_insert_with(|| Bucket {
+                dsn: dsn.clone(),
+                attributes: SessionAttributes {
+                    release: attributes.release,
+                    environment: attributes.environment,
+                    ip_address: None,
+                    user_agent: None,
+                },
+                items: HashMap::new(),
+            });
+        let item = bucket
+            .items
+            .entry(item_key.clone())
+            .or_insert_with(|| SessionAggregateItem {
+                started: item_key.0,
+                distinct_id: item_key.1.clone(),
+                exited: 0,
+                errored: 0,
+                abnormal: 0,
+                crashed: 0,
+            });
+        *outcome.counter(item) += 1;
+        self.sessions
+            .insert(update.session_id, (bucket_key, item_key, outcome));
+    }
+}
+
+impl Bucket {
+    fn into_envelope(self) -> SentryEnvelope {
+        let aggregates = SessionAggregates {
+            aggregates: self.items.into_values().collect(),
+            attributes: self.attributes,
+        };
+        let payload = serde_json::to_vec(&aggregates).unwrap_or_default();
+        let header = json!({
+            "dsn": self.dsn.to_string(),
+            "sent_at": Utc::now(),
+        });
+        let item_header = json!({
+            "type": "sessions",
+            "length": payload.len(),
+        });
+        let mut raw_body = format!("{}\n{}\n", header, item_header).into_bytes();
+        raw_body.extend_from_slice(&payload);
+        raw_body.push(b'\n');
+        SentryEnvelope {
+            raw_body,
+            dsn: self.dsn,
+        }
+    }
+}
+
+/**
+ * Envelope of `session` items forwarding the updates of sessions that were not aggregated
+ */
+fn session_envelope(dsn: Dsn, updates: &[SessionUpdate<'static>]) -> SentryEnvelope {
+    let header = json!({
+        "dsn": dsn.to_string(),
+        "sent_at": Utc::now(),
+    });
+    let mut raw_body = format!("{}\n", header).into_bytes();
+    for update in updates {
+        let payload = serde_json::to_vec(update).unwrap_or_default();
+        let item_header = json!({
+            "type": "session",
+            "length": payload.len(),
+        });
+        raw_body.extend_from_slice(format!("{}\n", item_header).as_bytes());
+        raw_body.extend_from_slice(&payload);
+        raw_body.push(b'\n');
+    }
+    SentryEnvelope { raw_body, dsn }
+}
@@ -0,0 +1,54 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/**
+ * The signature of a request does not prove it comes from a client of the claimed project
+ */
+#[derive(Debug)]
+pub enum SignatureError {
+    MissingSignature,
+    InvalidSignature,
+    UnsignedChannel,
+}
+
+impl Error for SignatureError {}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::MissingSignature => {
+                f.write_str("Missing signature, this project requires signed requests.")
+            }
+            SignatureError::InvalidSignature => f.write_str("Invalid request signature."),
+            SignatureError::UnsignedChannel => f.write_str(
+                "This project requires signed requests, only single envelopes posted on the tunnel path can be signed.",
+            ),
+        }
+    }
+}
+
+/**
+ * Check that `signature` is the hex encoded HMAC-SHA256 of `body`, keyed by `secret`
+ */
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> Result<(), SignatureError> {
+    let signature = hex::decode(signature.trim())
+        .map_err(|_| SignatureError::InvalidSignature)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|_| SignatureError::InvalidSignature)?;
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| SignatureError::InvalidSignature)
+}
+
+/**
+ * Hex encoded HMAC-SHA256 of `body`, keyed by `secret`
+ */
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
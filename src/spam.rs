@@ -0,0 +1,129 @@
+use crate::envelope;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/**
+ * Tag added to the first event of a window, counting the duplicates dropped during the previous one
+ */
+pub const COLLAPSED_TAG: &str = "tunnel.collapsed_duplicates";
+
+/**
+ * Events are considered identical when they come from the same client and project, with the same
+ * exception or message
+ */
+type Key = (Option<IpAddr>, u64, String);
+
+#[derive(Debug)]
+struct Entry {
+    started: Instant,
+    seen: u64,
+}
+
+/**
+ * What to do with an event
+ */
+#[derive(Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /** Forward the event, `collapsed` duplicates were dropped since the previous forwarded one */
+    Forward { collapsed: u64 },
+    Drop,
+}
+
+/**
+ * Detects floods of identical events : only the first `limit` events of a window are forwarded
+ */
+#[derive(Debug)]
+pub struct SpamFilter {
+    window: Duration,
+    limit: u64,
+    entries: Mutex<(HashMap<Key, Entry>, Instant)>,
+}
+
+impl SpamFilter {
+    pub fn new(window: Duration, limit: u64) -> SpamFilter {
+        SpamFilter {
+            window,
+            limit,
+            entries: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+
+    pub fn check(&self, client_ip: Option<IpAddr>, project_id: u64, fingerprint: String) -> Verdict {
+        let mut entries = self.entries.lock().unwrap();
+        let (entries, last_purge) = &mut *entries;
+        if last_purge.elapsed() > self.window {
+            // Dropped duplicates are reported if the event comes back during the next window
+            let (window, limit) = (self.window, self.limit);
+            entries.retain(|_, entry| {
+                let elapsed = entry.started.elapsed();
+                elapsed <= window || (entry.seen > limit && elapsed <= window * 2)
+            });
+            *last_purge = Instant::now();
+        }
+
+        let key = (client_ip, project_id, fingerprint);
+        match entries.get_mut(&key) {
+            Some(entry) if entry.started.elapsed() <= self.window => {
+                entry.seen += 1;
+                if entry.seen > self.limit {
+                    Verdict::Drop
+                } else {
+                    Verdict::Forward { collapsed: 0 }
+                }
+            }
+            previous => {
+                let collapsed = previous.map_or(0, |entry| entry.seen.saturating_sub(self.limit));
+                entries.insert(
+                    key,
+                    Entry {
+                        started: Instant::now(),
+                        seen: 1,
+                    },
+                );
+                Verdict::Forward { collapsed }
+            }
+        }
+    }
+}
+
+/**
+ * The exceptions or message of an event, used to recognize identical events
+ */
+pub fn fingerprint(event: &Value) -> Option<String> {
+    let exceptions = event
+        .pointer("/exception/values")
+        .or_else(|| event.get("exception"))
+        .and_then(Value::as_array);
+    if let Some(exceptions) = exceptions {
+        let fingerprint: Vec<String> = exceptions
+            .iter()
+            .map(|exception| {
+                format!(
+                    "{}: {}",
+                    exception.get("type").and_then(Value::as_str).unwrap_or_default(),
+                    exception.get("value").and_then(Value::as_str).unwrap_or_default()
+                )
+            })
+            .collect();
+        if !fingerprint.is_empty() {
+            return Some(fingerprint.join("\n"));
+        }
+    }
+    event
+        .pointer("/logentry/message")
+        .or_else(|| event.pointer("/logentry/formatted"))
+        .or_else(|| event.get("message"))
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/**
+ * Add the number of collapsed duplicates to the tags of the event
+ */
+pub fn tag_collapsed(event: &mut Value, collapsed: u64) {
+    envelope::set_tag(event, COLLAPSED_TAG, collapsed.to_string());
+}
@@ -0,0 +1,86 @@
+use anyhow::Error as AError;
+use gotham::hyper::body::{Body, Bytes, HttpBody};
+use log::*;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Size of the chunks read back from a spilled body
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Makes the name of each spill file unique within the process
+static SPILLED: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * A request body written to a temporary file
+ */
+#[derive(Debug)]
+pub struct SpilledBody {
+    file: File,
+    size: u64,
+}
+
+/**
+ * Write the rest of the body to a temporary file in `dir`. The file is unlinked as soon as it is
+ * created, so nothing is left behind once it is closed, even after a crash.
+ */
+pub async fn spill(mut body: Body, dir: &Path) -> Result<SpilledBody, AError> {
+    let path = dir.join(format!(
+        "sentry-tunnel-{}-{}.body",
+        process::id(),
+        SPILLED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await?;
+    fs::remove_file(&path).await?;
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.flush().await?;
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(SpilledBody { file, size })
+}
+
+impl SpilledBody {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /**
+     * Read the file back as a body, one chunk at a time
+     */
+    pub fn into_body(mut self) -> Body {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                match self.file.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        let data = Bytes::copy_from_slice(&chunk[..read]);
+                        if sender.send_data(data).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to read a spilled body : {}", e);
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+        });
+        body
+    }
+}
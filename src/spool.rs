@@ -0,0 +1,313 @@
+use crate::envelope::SentryEnvelope;
+use crate::forward::{RetryPolicy, UpstreamClient};
+use crate::monitoring;
+use crate::region;
+use crate::stats::Stats;
+use log::*;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Spooled envelopes are sent again this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+// A spool file is closed and a new one started once it reaches this size
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+const EXTENSION: &str = "spool";
+
+/**
+ * The spool file envelopes are appended to
+ */
+#[derive(Debug)]
+struct Segment {
+    file: File,
+    size: u64,
+}
+
+#[derive(Debug, Default)]
+struct Files {
+    current: Option<Segment>,
+    // Bytes of every spool file, the current one included
+    size: u64,
+    // Makes the names of the spool files created in the same millisecond unique
+    created: u64,
+}
+
+/**
+ * Envelopes that could not be forwarded because sentry was unreachable, appended to files of a
+ * directory and sent again in the background once it is back. Each envelope is stored as its
+ * length, as 4 big endian bytes, followed by its body.
+ */
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    proxies: HashMap<String, String>,
+    client: Arc<UpstreamClient>,
+    stats: Arc<Stats>,
+    files: Mutex<Files>,
+    flusher_started: AtomicBool,
+}
+
+/**
+ * Milliseconds since the epoch at which a spool file was created, from its name
+ */
+fn created_at(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.split('-').next()?.parse().ok()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/**
+ * Split the content of a spool file into the envelopes it holds. A record cut short by a crash
+ * ends the file.
+ */
+fn records(content: &[u8]) -> Vec<&[u8]> {
+    let mut records = vec![];
+    let mut rest = content;
+    while rest.len() >= 4 {
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() - 4 < length {
+            warn!("Ignoring a truncated spooled envelope");
+            break;
+        }
+        records.push(&rest[4..4 + length]);
+        rest = &rest[4 + length..];
+    }
+    records
+}
+
+fn append_record(content: &mut Vec<u8>, envelope: &[u8]) {
+    content.extend_from_slice(&(envelope.len() as u32).to_be_bytes());
+    content.extend_from_slice(envelope);
+}
+
+impl Spool {
+    /**
+     * Spool to `dir`, created if needed, keeping the spool files already there. Envelopes are
+     * dropped once the files hold `max_size` bytes, and files older than `max_age` are deleted
+     * without being sent.
+     */
+    pub fn open(
+        dir: PathBuf,
+        max_size: u64,
+        max_age: Duration,
+        proxies: HashMap<String, String>,
+        client: Arc<UpstreamClient>,
+        stats: Arc<Stats>,
+    ) -> io::Result<Spool> {
+        fs::create_dir_all(&dir)?;
+        let spool = Spool {
+            dir,
+            max_size,
+            max_age,
+            proxies,
+            client,
+            stats,
+            files: Mutex::new(Files::default()),
+            flusher_started: AtomicBool::new(false),
+        };
+        let size = spool
+            .segments()?
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        spool.files.lock().unwrap().size = size;
+        if size > 0 {
+            info!("{} bytes of envelopes are spooled in {:?}", size, spool.dir);
+        }
+        Ok(spool)
+    }
+
+    /**
+     * Whether envelopes spooled before are waiting to be sent
+     */
+    pub fn is_pending(&self) -> bool {
+        self.files.lock().unwrap().size > 0
+    }
+
+    /**
+     * The spool files, oldest first
+     */
+    fn segments(&self) -> io::Result<Vec<PathBuf>> {
+        let mut segments: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == EXTENSION))
+            .collect();
+        segments.sort_by_key(|path| (created_at(path), path.clone()));
+        Ok(segments)
+    }
+
+    /**
+     * Append an envelope to the spool. Returns false when it could not be spooled, because the
+     * spool is full or can not be written.
+     */
+    pub fn push(self: &Arc<Self>, envelope: &[u8]) -> bool {
+        let mut record = Vec::with_capacity(envelope.len() + 4);
+        append_record(&mut record, envelope);
+        let mut files = self.files.lock().unwrap();
+        if files.size + record.len() as u64 > self.max_size {
+            warn!("The spool is full, dropping an envelope of {} bytes", envelope.len());
+            self.stats.spooled_envelope_dropped();
+            return false;
+        }
+        if let Err(e) = self.append(&mut files, &record) {
+            error!("Could not spool an envelope to {:?} : {}", self.dir, e);
+            return false;
+        }
+        drop(files);
+        self.stats.envelope_spooled();
+        self.start_flusher();
+        true
+    }
+
+    fn append(&self, files: &mut Files, record: &[u8]) -> io::Result<()> {
+        if files.current.as_ref().is_some_and(|current| current.size >= SEGMENT_SIZE) {
+            files.current = None;
+        }
+        if files.current.is_none() {
+            files.created += 1;
+            let path = self
+                .dir
+                .join(format!("{}-{}.{}", now_millis(), files.created, EXTENSION));
+            let file = OpenOptions::new().append(true).create_new(true).open(&path)?;
+            files.current = Some(Segment { file, size: 0 });
+        }
+        let current = files.current.as_mut().unwrap();
+        current.file.write_all(record)?;
+        current.file.flush()?;
+        current.size += record.len() as u64;
+        files.size += record.len() as u64;
+        Ok(())
+    }
+
+    /**
+     * Send the spooled envelopes periodically, starting with those spooled by a previous run
+     */
+    pub fn start_flusher(self: &Arc<Self>) {
+        if self.flusher_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let spool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = spool.flush().await {
+                    error!("Could not flush the spool of {:?} : {}", spool.dir, e);
+                }
+            }
+        });
+    }
+
+    /**
+     * Send the spooled envelopes, oldest first, until sentry fails again. The envelopes that were
+     * not sent stay in the spool.
+     */
+    pub async fn flush(&self) -> io::Result<()> {
+        // Envelopes spooled during the flush go to a new file, that is not listed
+        let segments = {
+            let mut files = self.files.lock().unwrap();
+            files.current = None;
+            self.segments()?
+        };
+        for path in segments {
+            let content = fs::read(&path)?;
+            let expired = created_at(&path).is_some_and(|created| {
+                now_millis().saturating_sub(created) > self.max_age.as_millis() as u64
+            });
+            let records = records(&content);
+            if expired {
+                warn!("Dropping {} spooled envelopes, too old to be sent", records.len());
+                for _ in &records {
+                    self.stats.spooled_envelope_dropped();
+                }
+                self.remove(&path, content.len() as u64)?;
+                continue;
+            }
+            let mut sent = 0;
+            for record in &records {
+                if !self.send(record).await {
+                    break;
+                }
+                sent += 1;
+            }
+            if sent == records.len() {
+                self.remove(&path, content.len() as u64)?;
+                continue;
+            }
+            if sent > 0 {
+                self.keep(&path, &records[sent..], content.len() as u64)?;
+            }
+            debug!("Sentry is still unreachable, {} spooled envelopes were sent", sent);
+            break;
+        }
+        Ok(())
+    }
+
+    /**
+     * Send a spooled envelope. Returns false if it should be sent again later.
+     */
+    async fn send(&self, record: &[u8]) -> bool {
+        let mut envelope = match SentryEnvelope::try_new_from_body(record.to_vec()) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!("Dropping an invalid spooled envelope : {}", e);
+                return true;
+            }
+        };
+        let proxy = region::proxy(&self.proxies, envelope.dsn.host());
+        let forwarded = envelope.forward_via(&self.client, proxy).await;
+        if RetryPolicy::is_retryable(&forwarded) {
+            return false;
+        }
+        if let Err(e) = forwarded {
+            let message = format!(
+                "Failed to forward a spooled envelope to sentry : {} - Host = {}",
+                e,
+                envelope.dsn.host()
+            );
+            error!("{}", message);
+            monitoring::capture_error(&message);
+        }
+        true
+    }
+
+    fn remove(&self, path: &Path, size: u64) -> io::Result<()> {
+        fs::remove_file(path)?;
+        let mut files = self.files.lock().unwrap();
+        files.size = files.size.saturating_sub(size);
+        Ok(())
+    }
+
+    /**
+     * Replace a spool file with the envelopes of it that are still to send. The file is replaced
+     * at once, so that a crash never loses or duplicates them.
+     */
+    fn keep(&self, path: &Path, records: &[&[u8]], size: u64) -> io::Result<()> {
+        let mut content = vec![];
+        for record in records {
+            append_record(&mut content, record);
+        }
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, &content)?;
+        fs::rename(&temporary, path)?;
+        let mut files = self.files.lock().unwrap();
+        files.size = files.size.saturating_sub(size) + content.len() as u64;
+        Ok(())
+    }
+}
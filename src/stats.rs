@@ -0,0 +1,429 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/**
+ * Upper bounds in bytes of the request body size histogram buckets
+ */
+pub const BODY_SIZE_BUCKETS: [u64; 6] =
+    [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000];
+
+/**
+ * Number of observations per bucket, not cumulated, and their sum
+ */
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BODY_SIZE_BUCKETS.len()],
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: u64) {
+        if let Some(bucket) = BODY_SIZE_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Bytes of a body held in memory, released when dropped
+ */
+#[derive(Debug)]
+pub struct BufferedBytes<'a> {
+    stats: &'a Stats,
+    bytes: u64,
+}
+
+impl BufferedBytes<'_> {
+    /**
+     * Hold `bytes` more in memory, unless more than `max` bytes would be held at the same time
+     */
+    pub fn grow(&mut self, bytes: u64, max: Option<u64>) -> bool {
+        if !self.stats.reserve_buffer(bytes, max) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for BufferedBytes<'_> {
+    fn drop(&mut self) {
+        self.stats.buffered_bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Counters exposed on the metrics endpoint, in the Prometheus text format
+ */
+#[derive(Debug, Default)]
+pub struct Stats {
+    bot_requests_dropped: AtomicU64,
+    country_requests_rejected: AtomicU64,
+    replay_recordings_stripped: AtomicU64,
+    duplicate_events_dropped: AtomicU64,
+    honeypot_hits: AtomicU64,
+    banned_requests_rejected: AtomicU64,
+    content_type_requests_rejected: AtomicU64,
+    audited_rule_hits: AtomicU64,
+    canary_envelopes: AtomicU64,
+    sdk_envelopes_dropped: AtomicU64,
+    spilled_bodies: AtomicU64,
+    overloaded_requests_rejected: AtomicU64,
+    unknown_envelopes_dropped: AtomicU64,
+    replayed_responses: AtomicU64,
+    paced_envelopes: AtomicU64,
+    region_envelopes_rejected: AtomicU64,
+    body_length_mismatches: AtomicU64,
+    upload_timeouts: AtomicU64,
+    skipped_processing_stages: AtomicU64,
+    compressed_envelopes: AtomicU64,
+    upstream_rate_limits: AtomicU64,
+    rate_limited_envelopes: AtomicU64,
+    forward_retries: AtomicU64,
+    spooled_envelopes: AtomicU64,
+    spooled_envelopes_dropped: AtomicU64,
+    body_sizes: Histogram,
+    buffered_bytes: AtomicU64,
+    peak_buffered_bytes: AtomicU64,
+}
+
+impl Stats {
+    pub fn bot_request_dropped(&self) {
+        self.bot_requests_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn country_request_rejected(&self) {
+        self.country_requests_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn replay_recordings_stripped(&self, count: u64) {
+        self.replay_recordings_stripped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn duplicate_event_dropped(&self) {
+        self.duplicate_events_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn honeypot_hit(&self) {
+        self.honeypot_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn banned_request_rejected(&self) {
+        self.banned_requests_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn content_type_rejected(&self) {
+        self.content_type_requests_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn audited_rule_hit(&self) {
+        self.audited_rule_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn canary_envelope(&self) {
+        self.canary_envelopes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sdk_envelope_dropped(&self) {
+        self.sdk_envelopes_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn body_spilled(&self) {
+        self.spilled_bodies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn overloaded_request_rejected(&self) {
+        self.overloaded_requests_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unknown_envelope_dropped(&self) {
+        self.unknown_envelopes_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response_replayed(&self) {
+        self.replayed_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn envelope_paced(&self) {
+        self.paced_envelopes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn region_envelope_rejected(&self) {
+        self.region_envelopes_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn body_length_mismatched(&self) {
+        self.body_length_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upload_timed_out(&self) {
+        self.upload_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn processing_stage_skipped(&self) {
+        self.skipped_processing_stages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn envelope_compressed(&self) {
+        self.compressed_envelopes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upstream_rate_limited(&self) {
+        self.upstream_rate_limits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rate_limited_envelope(&self) {
+        self.rate_limited_envelopes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn forward_retried(&self) {
+        self.forward_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn envelope_spooled(&self) {
+        self.spooled_envelopes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn spooled_envelope_dropped(&self) {
+        self.spooled_envelopes_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn body_received(&self, bytes: u64) {
+        self.body_sizes.observe(bytes);
+    }
+
+    /**
+     * Count the bytes of a body held in memory until the returned guard is dropped, unless more
+     * than `max` bytes would be held at the same time
+     */
+    pub fn try_buffer(&self, bytes: u64, max: Option<u64>) -> Option<BufferedBytes<'_>> {
+        if !self.reserve_buffer(bytes, max) {
+            return None;
+        }
+        Some(BufferedBytes { stats: self, bytes })
+    }
+
+    fn reserve_buffer(&self, bytes: u64, max: Option<u64>) -> bool {
+        let mut buffered = self.buffered_bytes.load(Ordering::Relaxed);
+        loop {
+            let wanted = buffered + bytes;
+            if max.is_some_and(|max| wanted > max) {
+                return false;
+            }
+            match self.buffered_bytes.compare_exchange_weak(
+                buffered,
+                wanted,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.peak_buffered_bytes.fetch_max(wanted, Ordering::Relaxed);
+                    return true;
+                }
+                Err(actual) => buffered = actual,
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut rendered = String::new();
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_bot_requests_dropped_total",
+            "Requests dropped because of their User-Agent",
+            self.bot_requests_dropped.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_country_requests_rejected_total",
+            "Requests rejected because of the country they come from",
+            self.country_requests_rejected.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_replay_recordings_stripped_total",
+            "Replay recordings removed from envelopes because of their size",
+            self.replay_recordings_stripped.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_duplicate_events_dropped_total",
+            "Identical events dropped because a client sent too many of them",
+            self.duplicate_events_dropped.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_honeypot_hits_total",
+            "Requests on honeypot paths",
+            self.honeypot_hits.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_banned_requests_rejected_total",
+            "Requests rejected because their client is banned",
+            self.banned_requests_rejected.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_content_type_requests_rejected_total",
+            "Requests rejected because of their content type",
+            self.content_type_requests_rejected.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_audited_rule_hits_total",
+            "Requests flagged instead of dropped because the rule they broke is audited",
+            self.audited_rule_hits.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_canary_envelopes_total",
+            "Envelopes sent to the canary dsn of their project",
+            self.canary_envelopes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_sdk_envelopes_dropped_total",
+            "Envelopes dropped because the SDK that sent them is not allowed",
+            self.sdk_envelopes_dropped.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_spilled_bodies_total",
+            "Streamed bodies written to a temporary file before being forwarded",
+            self.spilled_bodies.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_overloaded_requests_rejected_total",
+            "Requests rejected because too many were being handled",
+            self.overloaded_requests_rejected.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_unknown_envelopes_dropped_total",
+            "Envelopes of unknown projects or hosts silently dropped",
+            self.unknown_envelopes_dropped.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_replayed_responses_total",
+            "Retried envelopes answered with the response of their first submission",
+            self.replayed_responses.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_paced_envelopes_total",
+            "Envelopes delayed to keep the upstream request rate under its limit",
+            self.paced_envelopes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_region_envelopes_rejected_total",
+            "Envelopes rejected because of the sentry.io region they are sent to",
+            self.region_envelopes_rejected.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_body_length_mismatches_total",
+            "Requests rejected because their body does not match their Content-Length",
+            self.body_length_mismatches.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_upload_timeouts_total",
+            "Requests whose body was not received before the upload timeout",
+            self.upload_timeouts.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_skipped_processing_stages_total",
+            "Optional processing stages skipped because the processing budget was spent",
+            self.skipped_processing_stages.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_compressed_envelopes_total",
+            "Envelopes forwarded compressed with one of the upstream encodings",
+            self.compressed_envelopes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_upstream_rate_limits_total",
+            "Envelopes sentry answered with a 429 status, relayed to the client",
+            self.upstream_rate_limits.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_rate_limited_envelopes_total",
+            "Envelopes rejected without being forwarded because of a rate limit of sentry",
+            self.rate_limited_envelopes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_forward_retries_total",
+            "Envelopes sent to sentry again after a connection error or a 5xx status",
+            self.forward_retries.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_spooled_envelopes_total",
+            "Envelopes written to the spool because sentry could not be reached",
+            self.spooled_envelopes.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut rendered,
+            "sentry_tunnel_spooled_envelopes_dropped_total",
+            "Envelopes dropped because the spool was full, or held them for too long",
+            self.spooled_envelopes_dropped.load(Ordering::Relaxed),
+        );
+        write_histogram(
+            &mut rendered,
+            "sentry_tunnel_request_body_bytes",
+            "Size of the request bodies announced by their Content-Length",
+            &self.body_sizes,
+        );
+        write_gauge(
+            &mut rendered,
+            "sentry_tunnel_buffered_bytes",
+            "Bytes of request bodies currently held in memory",
+            self.buffered_bytes.load(Ordering::Relaxed),
+        );
+        write_gauge(
+            &mut rendered,
+            "sentry_tunnel_buffered_bytes_peak",
+            "Highest number of bytes of request bodies held in memory at the same time",
+            self.peak_buffered_bytes.load(Ordering::Relaxed),
+        );
+        rendered
+    }
+}
+
+fn write_counter(rendered: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(rendered, "# HELP {} {}", name, help);
+    let _ = writeln!(rendered, "# TYPE {} counter", name);
+    let _ = writeln!(rendered, "{} {}", name, value);
+}
+
+fn write_gauge(rendered: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(rendered, "# HELP {} {}", name, help);
+    let _ = writeln!(rendered, "# TYPE {} gauge", name);
+    let _ = writeln!(rendered, "{} {}", name, value);
+}
+
+fn write_histogram(rendered: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(rendered, "# HELP {} {}", name, help);
+    let _ = writeln!(rendered, "# TYPE {} histogram", name);
+    let mut cumulated = 0;
+    for (bound, bucket) in BODY_SIZE_BUCKETS.iter().zip(&histogram.buckets) {
+        cumulated += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(rendered, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulated);
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(rendered, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(rendered, "{}_sum {}", name, histogram.sum.load(Ordering::Relaxed));
+    let _ = writeln!(rendered, "{}_count {}", name, count);
+}
@@ -0,0 +1,172 @@
+use crate::envelope::BodyError;
+use futures_util::stream::Stream;
+use gotham::hyper::body::Bytes;
+use memchr::memchr;
+use serde_json::Value;
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/**
+ * Maximum size of a single item, depending on its type
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct ItemSizeLimits {
+    pub attachment: u64,
+    pub default: u64,
+}
+
+impl ItemSizeLimits {
+    pub fn limit_for(&self, item_type: Option<&str>) -> u64 {
+        match item_type {
+            Some("attachment") => self.attachment,
+            _ => self.default,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    EnvelopeHeader,
+    ItemHeader(Vec<u8>),
+    SizedPayload { remaining: u64 },
+    SizedPayloadEnd,
+    LinePayload { seen: u64, limit: u64 },
+}
+
+/**
+ * A stream of envelope bytes that follows the item boundaries while data flows through it, and
+ * fails as soon as an item is bigger than what is allowed for its type.
+ */
+pub struct LimitedItems<S> {
+    inner: S,
+    limits: ItemSizeLimits,
+    allowed_types: Option<Vec<String>>,
+    state: State,
+    violation: Arc<Mutex<Option<BodyError>>>,
+}
+
+impl<S> LimitedItems<S> {
+    pub fn new(inner: S, limits: ItemSizeLimits) -> LimitedItems<S> {
+        LimitedItems {
+            inner,
+            limits,
+            allowed_types: None,
+            state: State::EnvelopeHeader,
+            violation: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /**
+     * Also fail when an item type is not in the given list
+     */
+    pub fn with_allowed_types(mut self, allowed_types: Option<Vec<String>>) -> LimitedItems<S> {
+        self.allowed_types = allowed_types;
+        self
+    }
+
+    /**
+     * A handle on the error that stopped the stream, if any
+     */
+    pub fn violation(&self) -> Arc<Mutex<Option<BodyError>>> {
+        self.violation.clone()
+    }
+
+    fn inspect(&mut self, mut chunk: &[u8]) -> Result<(), BodyError> {
+        while !chunk.is_empty() {
+            match &mut self.state {
+                State::EnvelopeHeader => match memchr(b'\n', chunk) {
+                    Some(end) => {
+                        chunk = &chunk[end + 1..];
+                        self.state = State::ItemHeader(vec![]);
+                    }
+                    None => chunk = &[],
+                },
+                State::ItemHeader(buffer) => {
+                    let end = memchr(b'\n', chunk);
+                    let taken = end.unwrap_or(chunk.len());
+                    buffer.extend_from_slice(&chunk[..taken]);
+                    if buffer.len() as u64 > self.limits.default {
+                        return Err(BodyError::InvalidItem);
+                    }
+                    chunk = &chunk[(taken + 1).min(chunk.len())..];
+                    if end.is_some() {
+                        let header = std::mem::take(buffer);
+                        self.state = self.start_item(&header)?;
+                    }
+                }
+                State::SizedPayload { remaining } => {
+                    let taken = (*remaining).min(chunk.len() as u64);
+                    *remaining -= taken;
+                    chunk = &chunk[taken as usize..];
+                    if *remaining == 0 {
+                        self.state = State::SizedPayloadEnd;
+                    }
+                }
+                State::SizedPayloadEnd => {
+                    if chunk[0] == b'\n' {
+                        chunk = &chunk[1..];
+                    }
+                    self.state = State::ItemHeader(vec![]);
+                }
+                State::LinePayload { seen, limit } => {
+                    let end = memchr(b'\n', chunk);
+                    let taken = end.unwrap_or(chunk.len());
+                    *seen += taken as u64;
+                    if *seen > *limit {
+                        return Err(BodyError::ItemTooLarge);
+                    }
+                    chunk = &chunk[(taken + 1).min(chunk.len())..];
+                    if end.is_some() {
+                        self.state = State::ItemHeader(vec![]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn start_item(&self, header: &[u8]) -> Result<State, BodyError> {
+        if header.iter().all(u8::is_ascii_whitespace) {
+            return Ok(State::ItemHeader(vec![]));
+        }
+        let header: Value = serde_json::from_slice(header).map_err(|_| BodyError::InvalidItem)?;
+        let item_type = header.get("type").and_then(Value::as_str);
+        if let Some(allowed_types) = &self.allowed_types {
+            let item_type = item_type.unwrap_or_default();
+            if !allowed_types.iter().any(|allowed| allowed == item_type) {
+                return Err(BodyError::UnknownItemType(item_type.to_string()));
+            }
+        }
+        let limit = self.limits.limit_for(item_type);
+        match header.get("length").and_then(Value::as_u64) {
+            Some(length) if length > limit => Err(BodyError::ItemTooLarge),
+            Some(0) => Ok(State::SizedPayloadEnd),
+            Some(length) => Ok(State::SizedPayload { remaining: length }),
+            None => Ok(State::LinePayload { seen: 0, limit }),
+        }
+    }
+}
+
+impl<S> Stream for LimitedItems<S>
+where
+    S: Stream<Item = Result<Bytes, io::Error>> + Unpin,
+{
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => match self.inspect(&chunk) {
+                Ok(()) => Poll::Ready(Some(Ok(chunk))),
+                Err(e) => {
+                    let error = io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+                    *self.violation.lock().unwrap() = Some(e);
+                    Poll::Ready(Some(Err(error)))
+                }
+            },
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::Config;
+use gotham::hyper::HeaderMap;
+use log::*;
+use tracing::Span;
+
+// Name of the tunnel in the traces it exports
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "sentry_tunnel";
+
+/**
+ * Export the spans of the requests to the OTLP collector of TUNNEL_TRACING_ENDPOINT, if set.
+ * Must be called from the runtime, which sends them in the background.
+ */
+#[cfg(feature = "otel")]
+pub fn init(config: &Config) {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let endpoint = match &config.tracing_endpoint {
+        Some(endpoint) => endpoint,
+        None => return,
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", SERVICE_NAME),
+        ])))
+        .install_batch(runtime::Tokio);
+    let tracer = match tracer {
+        Ok(tracer) => tracer,
+        Err(e) => {
+            error!("Could not export traces to {} : {}", endpoint, e);
+            return;
+        }
+    };
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer));
+    match subscriber.try_init() {
+        Ok(()) => info!("Exporting traces to {}", endpoint),
+        Err(e) => error!("Could not export traces to {} : {}", endpoint, e),
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(config: &Config) {
+    if config.tracing_endpoint.is_some() {
+        error!("TUNNEL_TRACING_ENDPOINT is set but this build does not include the 'otel' feature");
+    }
+}
+
+/**
+ * Send the spans still pending to the collector
+ */
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/**
+ * Reads the W3C trace context of a request
+ */
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/**
+ * The span of a request, a child of the trace of the `traceparent` header when it has one, so
+ * that the tunnel shows up in the traces started by a gateway in front of it
+ */
+pub fn request_span(headers: &HeaderMap, name: &'static str) -> Span {
+    let span = tracing::info_span!("request", otel.name = name, otel.kind = "server");
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        span.set_parent(parent);
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = headers;
+    span
+}
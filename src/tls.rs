@@ -0,0 +1,228 @@
+use crate::config::Config;
+use crate::server::{dispatch, ClientIdentity};
+use anyhow::{anyhow, Error as AError};
+use gotham::hyper::server::conn::Http;
+use gotham::hyper::service::service_fn;
+use gotham::router::Router;
+use log::*;
+use notify::{Event, RecursiveMode, Watcher};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+use std::convert::Infallible;
+use std::fs;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Protocols of the HTTPS listener, HTTP/2 is not negotiated
+const HTTPS_ALPN: &[&[u8]] = &[b"http/1.1"];
+
+pub fn load_certs(pem: &[u8]) -> Result<Vec<rustls::Certificate>, AError> {
+    Ok(rustls_pemfile::certs(&mut BufReader::new(pem))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect())
+}
+
+/**
+ * The TLS configuration of a listener negotiating the `alpn` protocols. When a client CA is
+ * given, clients must present a certificate signed by one of its CAs.
+ */
+pub fn load_tls_config(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+    client_ca_path: Option<&str>,
+    alpn: &[&[u8]],
+) -> Result<rustls::ServerConfig, AError> {
+    let certs = load_certs(cert_pem)?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(key_pem))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in the TLS key"))?;
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for ca in load_certs(&fs::read(client_ca_path)?)? {
+                roots.add(&ca)?;
+            }
+            builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed(),
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls_config = builder.with_single_cert(certs, key)?;
+    tls_config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(tls_config)
+}
+
+/**
+ * The TLS configuration of a listener, with the current certificate of the configuration
+ */
+pub fn server_config(config: &Config, alpn: &[&[u8]]) -> Result<rustls::ServerConfig, AError> {
+    let (cert_pem, key_pem) = config.tls_certificate()?;
+    load_tls_config(&cert_pem, &key_pem, config.tls_client_ca_path.as_deref(), alpn)
+}
+
+/**
+ * CN and DNS SANs of the certificate presented by the client, if any
+ */
+pub fn client_identity(certs: Option<&[rustls::Certificate]>) -> Option<ClientIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs?.first()?.0).ok()?;
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Some(ClientIdentity { names })
+}
+
+/**
+ * Call `reload` on SIGHUP, or when the files of the certificate or key change, so that the
+ * `listener` picks up the new certificate. A certificate that can not be loaded is logged and the
+ * current one is kept.
+ */
+pub async fn watch_certificate<F>(
+    config: &Config,
+    listener: &str,
+    mut reload: F,
+) -> Result<(), AError>
+where
+    F: FnMut(&Config) -> Result<(), AError>,
+{
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if event.is_ok() {
+            let _ = sender.send(());
+        }
+    })?;
+    // The directories are watched, certificates being usually rotated by swapping symlinks
+    for path in [&config.tls_cert_path, &config.tls_key_path].iter().copied().flatten() {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            // A file of the working directory
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    let mut hangup = hangups()?;
+    loop {
+        tokio::select! {
+            Some(()) = changes.recv() => {
+                // Wait for the key to be written along with the certificate
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                while changes.try_recv().is_ok() {}
+            }
+            Some(()) = hangup.recv() => {}
+            else => return Ok(()),
+        }
+        match reload(config) {
+            Ok(()) => info!("Reloaded the TLS certificate of the {} listener", listener),
+            Err(e) => error!(
+                "Could not reload the {} certificate, keeping the current one : {}",
+                listener, e
+            ),
+        }
+    }
+}
+
+/**
+ * The SIGHUP signals received by the tunnel. Platforms without signals never receive any.
+ */
+fn hangups() -> Result<mpsc::UnboundedReceiver<()>, AError> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() && sender.send(()).is_ok() {}
+        });
+    }
+    #[cfg(not(unix))]
+    drop(sender);
+    Ok(receiver)
+}
+
+/**
+ * Serve the router over HTTPS on the given TCP address, with the TLS certificate of the
+ * configuration, which is reloaded when it changes. Connections already established keep the
+ * previous certificate.
+ */
+pub async fn serve(addr: SocketAddr, router: Router, config: Config) -> Result<(), AError> {
+    let acceptor = Arc::new(RwLock::new(TlsAcceptor::from(Arc::new(server_config(
+        &config,
+        HTTPS_ALPN,
+    )?))));
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening for HTTPS on {}", addr);
+    let reloaded = acceptor.clone();
+    tokio::spawn(async move {
+        let reload = |config: &Config| -> Result<(), AError> {
+            let tls_config = server_config(config, HTTPS_ALPN)?;
+            *reloaded.write().unwrap() = TlsAcceptor::from(Arc::new(tls_config));
+            Ok(())
+        };
+        if let Err(e) = watch_certificate(&config, "HTTPS", reload).await {
+            error!("Could not watch the TLS certificate : {}", e);
+        }
+    });
+    loop {
+        let (stream, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Could not accept a TLS connection : {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.read().unwrap().clone();
+        let router = router.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(acceptor, stream, client_addr, router).await {
+                debug!("TLS connection from {} failed : {}", client_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    acceptor: TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    client_addr: SocketAddr,
+    router: Router,
+) -> Result<(), AError> {
+    let stream = acceptor.accept(stream).await?;
+    let identity = client_identity(stream.get_ref().1.peer_certificates());
+    let service = service_fn(move |request| {
+        let router = router.clone();
+        let identity = identity.clone();
+        async move {
+            Ok::<_, Infallible>(dispatch(&router, request, client_addr, identity).await)
+        }
+    });
+    Http::new()
+        .serve_connection(stream, service)
+        .with_upgrades()
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use crate::audit;
+use log::*;
+use serde_json::{Map, Value};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::sync::RwLock;
+
+pub const REPLAY_RECORDING_SIZE: &str = "replay_recording_size";
+
+/**
+ * Filters that can be switched off at runtime
+ */
+pub const TOGGLES: &[&str] = &[
+    audit::USER_AGENT,
+    audit::COUNTRY,
+    audit::BANNED,
+    audit::CONTENT_TYPE,
+    audit::DUPLICATE,
+    audit::SDK,
+    REPLAY_RECORDING_SIZE,
+];
+
+/**
+ * The toggles could not be changed
+ */
+#[derive(Debug)]
+pub enum ToggleError {
+    UnknownToggle(String),
+    InvalidValue(String),
+    Persist(io::Error),
+}
+
+impl Error for ToggleError {}
+
+impl Display for ToggleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToggleError::UnknownToggle(toggle) => {
+                f.write_fmt(format_args!("Unknown toggle '{}'.", toggle))
+            }
+            ToggleError::InvalidValue(toggle) => f.write_fmt(format_args!(
+                "Toggle '{}' must be set to true, false or null.",
+                toggle
+            )),
+            ToggleError::Persist(e) => {
+                f.write_fmt(format_args!("Could not persist the toggles : {}", e))
+            }
+        }
+    }
+}
+
+/**
+ * Overrides of the configured filters, persisted to a file when a path is configured
+ */
+#[derive(Debug, Default)]
+pub struct Toggles {
+    overrides: RwLock<BTreeMap<String, bool>>,
+    path: Option<String>,
+}
+
+impl Toggles {
+    /**
+     * Start with the overrides persisted at this path, if any
+     */
+    pub fn load(path: Option<String>) -> Toggles {
+        let overrides = path
+            .as_ref()
+            .and_then(|path| match fs::read(path) {
+                Ok(content) => serde_json::from_slice(&content)
+                    .map_err(|e| error!("Ignoring the invalid toggles of {} : {}", path, e))
+                    .ok(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    error!("Could not read the toggles of {} : {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Toggles {
+            overrides: RwLock::new(overrides),
+            path,
+        }
+    }
+
+    pub fn is_enabled(&self, toggle: &str) -> bool {
+        self.overrides
+            .read()
+            .unwrap()
+            .get(toggle)
+            .copied()
+            .unwrap_or(true)
+    }
+
+    /**
+     * Every toggle and whether it is enabled, followed by the overrides
+     */
+    pub fn to_json(&self) -> Value {
+        let overrides = self.overrides.read().unwrap();
+        let toggles: Map<String, Value> = TOGGLES
+            .iter()
+            .map(|toggle| {
+                let enabled = overrides.get(*toggle).copied().unwrap_or(true);
+                (toggle.to_string(), Value::Bool(enabled))
+            })
+            .collect();
+        serde_json::json!({ "toggles": toggles, "overrides": &*overrides })
+    }
+
+    /**
+     * Override toggles with `true` or `false`, or remove their override with `null`
+     */
+    pub fn apply(&self, changes: &Map<String, Value>) -> Result<(), ToggleError> {
+        for (toggle, value) in changes {
+            if !TOGGLES.contains(&toggle.as_str()) {
+                return Err(ToggleError::UnknownToggle(toggle.clone()));
+            }
+            if !value.is_boolean() && !value.is_null() {
+                return Err(ToggleError::InvalidValue(toggle.clone()));
+            }
+        }
+        let mut overrides = self.overrides.write().unwrap();
+        for (toggle, value) in changes {
+            match value.as_bool() {
+                Some(enabled) => overrides.insert(toggle.clone(), enabled),
+                None => overrides.remove(toggle),
+            };
+        }
+        info!("Toggles overridden : {:?}", *overrides);
+        if let Some(path) = &self.path {
+            let content = serde_json::to_vec(&*overrides).map_err(io::Error::from);
+            content
+                .and_then(|content| fs::write(path, content))
+                .map_err(ToggleError::Persist)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::Config;
+use crate::server::{dispatch, router};
+use ::tower::{Layer, Service};
+use gotham::hyper::{Body, Request, Response, Uri};
+use gotham::router::Router;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/**
+ * A layer handling the requests under `path` with the tunnel, and passing the other ones to the
+ * inner service. `TunnelLayer::new("/sentry", config)` serves the tunnel path of the
+ * configuration on `/sentry/tunnel` for instance.
+ */
+#[derive(Clone)]
+pub struct TunnelLayer {
+    router: Router,
+    path: String,
+}
+
+impl TunnelLayer {
+    pub fn new(path: &str, config: Config) -> TunnelLayer {
+        TunnelLayer {
+            router: router(&config.tunnel_path.clone(), config),
+            path: path.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl<S> Layer<S> for TunnelLayer {
+    type Service = TunnelService<S>;
+
+    fn layer(&self, inner: S) -> TunnelService<S> {
+        TunnelService {
+            inner,
+            router: self.router.clone(),
+            path: self.path.clone(),
+        }
+    }
+}
+
+/**
+ * The service built by `TunnelLayer`. The client address is read from the `SocketAddr`
+ * extension of the requests, when the server sets one.
+ */
+#[derive(Clone)]
+pub struct TunnelService<S> {
+    inner: S,
+    router: Router,
+    path: String,
+}
+
+impl<S> TunnelService<S> {
+    /**
+     * The uri of the request for the tunnel router, without the path of the layer, if the request
+     * is under that path
+     */
+    fn tunnel_uri(&self, uri: &Uri) -> Option<Uri> {
+        let rest = uri.path().strip_prefix(&self.path)?;
+        if !(rest.is_empty() || rest.starts_with('/')) {
+            return None;
+        }
+        let path = if rest.is_empty() { "/" } else { rest };
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        path_and_query.parse().ok()
+    }
+}
+
+impl<S> Service<Request<Body>> for TunnelService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        match self.tunnel_uri(request.uri()) {
+            Some(uri) => {
+                let router = self.router.clone();
+                let client_addr = request
+                    .extensions()
+                    .get::<SocketAddr>()
+                    .copied()
+                    .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+                let (mut parts, request_body) = request.into_parts();
+                parts.uri = uri;
+                let request = Request::from_parts(parts, request_body);
+                Box::pin(async move { Ok(dispatch(&router, request, client_addr, None).await) })
+            }
+            None => Box::pin(self.inner.call(request)),
+        }
+    }
+}
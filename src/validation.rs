@@ -0,0 +1,53 @@
+use crate::envelope::{BodyError, Host, ProjectId, SentryEnvelope};
+use crate::sdk::{self, SdkRule};
+use anyhow::Error as AError;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/**
+ * The checks envelopes must pass to be forwarded, for the builds without a server, running in
+ * Cloudflare Workers or Fastly Compute for instance
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    pub remote_hosts: Vec<Host>,
+    pub project_ids: Vec<ProjectId>,
+    pub allowed_items: Option<Vec<String>>,
+    pub allowed_sdks: Vec<SdkRule>,
+}
+
+/**
+ * The dsn of the envelope points to a host that is not a remote host
+ */
+#[derive(Debug)]
+pub struct UnknownHost(pub String);
+
+impl Display for UnknownHost {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Unauthorized sentry host : {}", self.0))
+    }
+}
+
+impl Error for UnknownHost {}
+
+/**
+ * Parse the body and validate the envelope against the rules. Returns `None` when the envelope
+ * must be dropped silently, like the tunnel does for denied SDKs.
+ */
+pub fn validate(body: Vec<u8>, rules: &Rules) -> Result<Option<SentryEnvelope>, AError> {
+    let envelope = SentryEnvelope::try_new_from_body(body)?;
+    if !rules.project_ids.contains(&envelope.project_id()) {
+        return Err(AError::new(BodyError::InvalidProjectId));
+    }
+    if !envelope.dsn_host_is_valid(&rules.remote_hosts) {
+        return Err(AError::new(UnknownHost(envelope.dsn.host().to_string())));
+    }
+    if let Some(allowed_items) = &rules.allowed_items {
+        envelope.check_item_types(allowed_items)?;
+    }
+    let denied = envelope
+        .sdk()
+        .is_some_and(|(name, version)| !sdk::is_allowed(&rules.allowed_sdks, &name, &version));
+    Ok(if denied { None } else { Some(envelope) })
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Error as AError};
+use isahc::{AsyncReadResponseExt, Request, RequestExt};
+use log::*;
+use crate::redact;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/**
+ * Where the service account token of the pod is mounted
+ */
+pub const KUBERNETES_JWT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/**
+ * How to reach Vault and where the tunnel secrets are stored
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VaultConfig {
+    pub address: String,
+    // API path of the secret, `secret/data/sentry-tunnel` for a KV version 2 engine
+    pub secret_path: String,
+    // Role of the Kubernetes auth method, a token is used otherwise
+    pub role: Option<String>,
+    #[serde(serialize_with = "redact::optional_secret")]
+    pub token: Option<String>,
+}
+
+/**
+ * A Vault token and how long it remains valid
+ */
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub token: String,
+    pub duration: Duration,
+    pub renewable: bool,
+}
+
+async fn call(
+    config: &VaultConfig,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> Result<Value, AError> {
+    let url = format!("{}/v1/{}", config.address.trim_end_matches('/'), path);
+    let mut request = Request::builder().method(method).uri(url);
+    if let Some(token) = token {
+        request = request.header("X-Vault-Token", token);
+    }
+    let mut response = request
+        .body(body.map(|body| body.to_string()).unwrap_or_default())?
+        .send_async()
+        .await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Vault answered {} to {} {} : {}",
+            status,
+            method,
+            path,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn parse_lease(token: String, ttl: Option<&Value>, renewable: Option<&Value>) -> Lease {
+    Lease {
+        token,
+        duration: Duration::from_secs(ttl.and_then(Value::as_u64).unwrap_or_default()),
+        renewable: renewable.and_then(Value::as_bool).unwrap_or_default(),
+    }
+}
+
+/**
+ * Log in with the Kubernetes service account of the pod, or look up the configured token
+ */
+pub async fn login(config: &VaultConfig) -> Result<Lease, AError> {
+    match (&config.role, &config.token) {
+        (Some(role), _) => {
+            let jwt = tokio::fs::read_to_string(KUBERNETES_JWT_PATH).await?;
+            let response = call(
+                config,
+                "POST",
+                "auth/kubernetes/login",
+                None,
+                Some(json!({ "role": role, "jwt": jwt.trim() })),
+            )
+            .await?;
+            let auth = &response["auth"];
+            let token = auth["client_token"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Vault did not return a client token"))?;
+            Ok(parse_lease(
+                token.to_string(),
+                auth.get("lease_duration"),
+                auth.get("renewable"),
+            ))
+        }
+        (None, Some(token)) => {
+            let response = call(config, "GET", "auth/token/lookup-self", Some(token), None).await?;
+            let data = &response["data"];
+            Ok(parse_lease(token.clone(), data.get("ttl"), data.get("renewable")))
+        }
+        (None, None) => Err(anyhow!("No Vault role nor token configured")),
+    }
+}
+
+/**
+ * Read the fields of the tunnel secret, from a KV version 1 or 2 engine
+ */
+pub async fn read_secret(
+    config: &VaultConfig,
+    lease: &Lease,
+) -> Result<HashMap<String, String>, AError> {
+    let response = call(config, "GET", &config.secret_path, Some(&lease.token), None).await?;
+    let data = match response.pointer("/data/data") {
+        Some(data @ Value::Object(_)) => data,
+        _ => &response["data"],
+    };
+    let fields = data
+        .as_object()
+        .ok_or_else(|| anyhow!("The Vault secret {} holds no data", config.secret_path))?;
+    Ok(fields
+        .iter()
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+        .collect())
+}
+
+/**
+ * Renew the token before it expires, logging in again when it can not be renewed anymore
+ */
+pub async fn keep_renewed(config: VaultConfig, mut lease: Lease) {
+    loop {
+        if lease.duration.is_zero() {
+            // Tokens without a TTL never expire
+            return;
+        }
+        tokio::time::sleep(lease.duration * 2 / 3).await;
+        let renewed = if lease.renewable {
+            call(&config, "POST", "auth/token/renew-self", Some(&lease.token), None)
+                .await
+                .map(|response| {
+                    let auth = &response["auth"];
+                    parse_lease(
+                        lease.token.clone(),
+                        auth.get("lease_duration"),
+                        auth.get("renewable"),
+                    )
+                })
+        } else {
+            login(&config).await
+        };
+        match renewed {
+            Ok(renewed) => {
+                debug!("Renewed the Vault token for {:?}", renewed.duration);
+                lease = renewed;
+            }
+            Err(e) => {
+                error!("Failed to renew the Vault token : {}", e);
+                lease.renewable = false;
+                lease.duration = Duration::from_secs(60);
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::redact;
+use anyhow::{anyhow, Error as AError};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use log::*;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use std::ffi::OsString;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+// Name of the service and of its event log source
+pub const SERVICE_NAME: &str = "sentry_tunnel";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/**
+ * Runs the tunnel until the future it is given completes
+ */
+pub type Serve = fn(LocalBoxFuture<'static, ()>) -> LocalBoxFuture<'static, Result<(), String>>;
+
+// The service entry point is called by the service control manager, without arguments of ours
+static SERVE: OnceLock<Serve> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/**
+ * Handle `sentry_tunnel service install <config file>`, `service uninstall` and `service run`,
+ * the command the service control manager starts the tunnel with. Services do not inherit the
+ * environment of the user, so the tunnel reads its configuration from the file given at install.
+ */
+pub fn main(serve: Serve) {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let done = match args.first().map(String::as_str) {
+        Some("install") => install(args.get(1)),
+        Some("uninstall") => uninstall(),
+        Some("run") => {
+            if let Some(config_file) = args.get(1) {
+                std::env::set_var("TUNNEL_CONFIG_FILE", config_file);
+            }
+            let _ = SERVE.set(serve);
+            service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(AError::new)
+        }
+        _ => Err(anyhow!(
+            "Usage : sentry_tunnel service <install CONFIG_FILE | uninstall | run [CONFIG_FILE]>"
+        )),
+    };
+    if let Err(e) = done {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }
+}
+
+/**
+ * Register the service, started automatically with the machine, and its event log source.
+ * Requires an administrator.
+ */
+fn install(config_file: Option<&String>) -> Result<(), AError> {
+    let config_file = config_file.ok_or_else(|| anyhow!("The configuration file is missing"))?;
+    let config_file = std::fs::canonicalize(config_file)?;
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("Sentry Tunnel"),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![
+            OsString::from("service"),
+            OsString::from("run"),
+            config_file.into_os_string(),
+        ],
+        dependencies: vec![],
+        // LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+    manager.create_service(&info, ServiceAccess::QUERY_STATUS)?;
+    eventlog::register(SERVICE_NAME)?;
+    println!("Installed the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+fn uninstall() -> Result<(), AError> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    manager
+        .open_service(SERVICE_NAME, ServiceAccess::DELETE)?
+        .delete()?;
+    eventlog::deregister(SERVICE_NAME)?;
+    println!("Uninstalled the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    match eventlog::EventLog::new(SERVICE_NAME, Level::Info) {
+        Ok(event_log) => {
+            let _ = redact::init(event_log);
+        }
+        Err(e) => eprintln!("Could not log to the event log : {}", e),
+    }
+    if let Err(e) = run_service() {
+        error!("{}", e);
+    }
+}
+
+fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+/**
+ * Serve until the service is stopped, or the machine shuts down. Requests being handled when the
+ * stop is received are completed before the service reports that it stopped.
+ */
+fn run_service() -> Result<(), AError> {
+    let serve = SERVE
+        .get()
+        .ok_or_else(|| anyhow!("The service was not started by `sentry_tunnel service run`"))?;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let stop = Mutex::new(Some(stop));
+    let status_handle = service_control_handler::register(SERVICE_NAME, move |control| {
+        match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(stop) = stop.lock().unwrap().take() {
+                    let _ = stop.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    })?;
+    status_handle.set_service_status(status(ServiceState::Running, 0))?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let shutdown = async {
+        let _ = stopped.await;
+        info!("Stopping the {} service", SERVICE_NAME);
+    };
+    let served = runtime.block_on(serve(shutdown.boxed_local()));
+    // Let the requests still being handled complete
+    runtime.shutdown_timeout(Duration::from_secs(10));
+    let exit_code = if served.is_ok() { 0 } else { 1 };
+    status_handle.set_service_status(status(ServiceState::Stopped, exit_code))?;
+    served.map_err(AError::msg)
+}